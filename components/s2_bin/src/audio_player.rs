@@ -1,7 +1,38 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
-use cpal::{SampleFormat, Sample, FromSample, ChannelCount};
-use std::sync::mpsc;
+use cpal::{SampleFormat, Sample, SizedSample, FromSample, ChannelCount};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use s2_lib::try3::spsc;
+use s2_lib::try3::units::Ms;
+
+use crate::SYNTH_BLOCK_FRAMES;
+use crate::stats::Stats;
+
+/// Audio output options, each defaulting to the device's own preference.
+#[derive(clap::Args)]
+pub struct AudioArgs {
+    /// The audio output device to play through, by part of its name.
+    #[arg(long)]
+    audio_device: Option<String>,
+    /// In hz.
+    #[arg(long)]
+    sample_rate: Option<u32>,
+    /// Frames requested from the device per callback.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    buffer_frames: Option<u32>,
+    /// How far ahead of the device the synth renders, in milliseconds.
+    ///
+    /// Lower latency is more likely to drop out.
+    #[arg(long, default_value_t = DEFAULT_LATENCY.0)]
+    latency: f32,
+    /// Output channels. The first two are left and right;
+    /// a single channel gets both mixed.
+    #[arg(long)]
+    channels: Option<u16>,
+}
 
 pub struct Player {
     pub channels: PlayerChannels,
@@ -10,26 +41,49 @@ pub struct Player {
 
 pub struct PlayerChannels {
     pub sample_rate: u32,
-    pub buf_filled_tx: mpsc::SyncSender<Buffer>,
-    pub buf_empty_rx: mpsc::Receiver<Buffer>,
+    /// Rendered frames waiting for the device.
+    pub audio_tx: spsc::Producer<Frame>,
+    /// Frames to keep queued for the device.
+    pub target_frames: usize,
 }
 
 pub struct PlayerStream {
     stream: Box<dyn StreamTrait>,
 }
 
-pub const BUFFER_FRAMES: usize = 2048;
+/// A stereo frame, left then right.
+///
+/// Mapped onto however many channels the device has.
+pub type Frame = [f32; 2];
+
+const DEFAULT_LATENCY: Ms = Ms(20.0);
 
-/// This buffer only takes a single channel.
-pub struct Buffer(Box<[f32]>);
+/// Frames copied out of the ring at a time in the device callback.
+const CALLBACK_CHUNK_FRAMES: usize = 256;
 
-impl Buffer {
-    pub fn as_slice_mut(&mut self) -> &mut [f32] {
-        &mut self.0
+/// The most frames a callback is assumed to ask for,
+/// when the device doesn't say or allows more.
+///
+/// Devices report a buffer range far past the size of a callback,
+/// which would otherwise push the latency to seconds.
+const MAX_CALLBACK_FRAMES: u32 = 4096;
+
+/// Print the audio output devices of the default host.
+pub fn list_devices() -> Result<()> {
+    let host = cpal::default_host();
+    let default_name = host.default_output_device()
+        .and_then(|device| device.name().ok());
+
+    println!("audio output devices:");
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        let default = if Some(&name) == default_name.as_ref() { " (default)" } else { "" };
+        println!("  {}{}", name, default);
     }
+    Ok(())
 }
 
-pub fn start_player() -> Result<Option<Player>> {
+pub fn start_player(args: &AudioArgs, stats: Arc<Stats>) -> Result<Option<Player>> {
     let host = cpal::default_host();
 
     log::info!("audio devices:");
@@ -37,215 +91,244 @@ pub fn start_player() -> Result<Option<Player>> {
         log::info!("{}", device.name()?);
     }
 
-    let output_device = host.default_output_device();
+    let Some(output_device) = output_device(&host, args.audio_device.as_deref())? else {
+        return Ok(None);
+    };
+    let device_name = output_device.name()?;
+    log::info!("output device: {}", device_name);
 
-    if let Some(output_device) = output_device {
-        log::info!("default output device: {}", output_device.name()?);
+    log::info!("supported output configs:");
+    for configs in output_device.supported_output_configs()? {
+        log::info!("{:#?}", configs);
+    }
 
-        log::info!("supported output configs:");
-        for configs in output_device.supported_output_configs()? {
-            log::info!("{:#?}", configs);
-        }
+    let supported_config = stream_config(&output_device, &device_name, args)?;
+    log::info!("output config: {:#?}", supported_config);
 
-        let supported_config = output_device.default_output_config()?;
-        log::info!("default output config: {:#?}", supported_config);
-
-        let sample_format = supported_config.sample_format();
-        let config = cpal::StreamConfig::from(supported_config);
-
-        let (buf_filled_tx, buf_filled_rx) = mpsc::sync_channel(2);
-        let (buf_empty_tx, buf_empty_rx) = mpsc::sync_channel(2);
-
-        buf_empty_tx.send(Buffer(Box::from([0_f32; BUFFER_FRAMES])));
-        buf_empty_tx.send(Buffer(Box::from([0_f32; BUFFER_FRAMES])));
-
-        let handle_error = |error| {
-            log::error!("audio output error: {}", error);
-        };
-
-        let mut state = State {
-            output_channels: config.channels,
-            pending_buffer: None,
-            buf_filled_rx,
-            buf_empty_tx,
-        };
-
-        let stream = match sample_format {
-            SampleFormat::I16 => {
-                output_device.build_output_stream(
-                    &config,
-                    move |buffer: &mut [i16], info| {
-                        fill_buffer(buffer, &mut state)
-                    },
-                    handle_error,
-                    None,
-                )?
-            }
-            SampleFormat::U16 => {
-                output_device.build_output_stream(
-                    &config,
-                    move |buffer: &mut [u16], info| {
-                        fill_buffer(buffer, &mut state)
-                    },
-                    handle_error,
-                    None,
-                )?
-            }
-            SampleFormat::F32 => {
-                output_device.build_output_stream(
-                    &config,
-                    move |buffer: &mut [f32], info| {
-                        fill_buffer(buffer, &mut state)
-                    },
-                    handle_error,
-                    None,
-                )?
+    let sample_format = supported_config.sample_format();
+    let buffer_size = *supported_config.buffer_size();
+    let mut config = cpal::StreamConfig::from(supported_config);
+
+    if let Some(frames) = args.buffer_frames {
+        if let cpal::SupportedBufferSize::Range { min, max } = buffer_size {
+            if frames < min || frames > max {
+                bail!("{} supports buffers of {} to {} frames, not {}", device_name, min, max, frames);
             }
-            _ => todo!(),
-        };
-
-        stream.play()?;
-
-        Ok(Some(Player {
-            channels: PlayerChannels {
-                sample_rate: config.sample_rate.0,
-                buf_filled_tx,
-                buf_empty_rx,
-            },
-            stream: PlayerStream {
-                stream: Box::from(stream),
-            },
-        }))
-    } else {
-        Ok(None)
+        }
+        config.buffer_size = cpal::BufferSize::Fixed(frames);
     }
-}
 
-struct State {
-    output_channels: ChannelCount,
-    pending_buffer: Option<PendingBuffer>,
-    buf_filled_rx: mpsc::Receiver<Buffer>,
-    buf_empty_tx: mpsc::SyncSender<Buffer>,
-}
+    let sample_rate = config.sample_rate.0;
+    let callback_frames = max_callback_frames(config.buffer_size, buffer_size);
+    let latency_frames = (args.latency * sample_rate as f32 / 1000.0) as usize;
+    // A whole callback has to be queued before it arrives,
+    // and the synth only tops the queue up a block at a time.
+    let target_frames = latency_frames.max(callback_frames + SYNTH_BLOCK_FRAMES);
+    if target_frames > latency_frames {
+        log::info!(
+            "rendering {} frames ahead to cover callbacks of up to {} frames; try a lower --buffer-frames",
+            target_frames,
+            callback_frames,
+        );
+    }
+    // Room for the target, with plenty to spare for the synth
+    // rendering a block past it.
+    let (audio_tx, audio_rx) = spsc::channel(target_frames * 2);
+
+    let state = State {
+        output_channels: config.channels,
+        sample_rate,
+        target_frames,
+        audio_rx,
+        warned_callback_size: false,
+        stats,
+    };
+
+    let stream = match sample_format {
+        SampleFormat::I8 => build_stream::<i8>(&output_device, &config, state)?,
+        SampleFormat::I16 => build_stream::<i16>(&output_device, &config, state)?,
+        SampleFormat::I32 => build_stream::<i32>(&output_device, &config, state)?,
+        SampleFormat::I64 => build_stream::<i64>(&output_device, &config, state)?,
+        SampleFormat::U8 => build_stream::<u8>(&output_device, &config, state)?,
+        SampleFormat::U16 => build_stream::<u16>(&output_device, &config, state)?,
+        SampleFormat::U32 => build_stream::<u32>(&output_device, &config, state)?,
+        SampleFormat::U64 => build_stream::<u64>(&output_device, &config, state)?,
+        SampleFormat::F32 => build_stream::<f32>(&output_device, &config, state)?,
+        SampleFormat::F64 => build_stream::<f64>(&output_device, &config, state)?,
+        other => bail!("unsupported sample format {}", other),
+    };
 
-struct PendingBuffer {
-    buf: Buffer,
-    consumed: usize,
+    stream.play()?;
+
+    Ok(Some(Player {
+        channels: PlayerChannels {
+            sample_rate,
+            audio_tx,
+            target_frames,
+        },
+        stream: PlayerStream {
+            stream: Box::from(stream),
+        },
+    }))
 }
 
-fn fill_buffer<S>(
-    buffer: &mut [S],
-    state: &mut State,
-)
-where S: Sample,
-      S: FromSample<f32>,
-{
-    let output_channels = state.output_channels as usize;
-    assert!(buffer.len() % output_channels == 0);
+/// The most frames the device may ask for in one callback.
+fn max_callback_frames(buffer_size: cpal::BufferSize, supported: cpal::SupportedBufferSize) -> usize {
+    let frames = match (buffer_size, supported) {
+        (cpal::BufferSize::Fixed(frames), _) => frames,
+        (cpal::BufferSize::Default, cpal::SupportedBufferSize::Range { max, .. }) => {
+            max.min(MAX_CALLBACK_FRAMES)
+        }
+        (cpal::BufferSize::Default, cpal::SupportedBufferSize::Unknown) => MAX_CALLBACK_FRAMES,
+    };
+    frames as usize
+}
 
-    let total_frames_to_write = buffer.len() / output_channels;
+/// The output device whose name contains `name`, ignoring case,
+/// or the default device.
+fn output_device(host: &cpal::Host, name: Option<&str>) -> Result<Option<cpal::Device>> {
+    let Some(name) = name else {
+        return Ok(host.default_output_device());
+    };
 
-    // todo we can probably cut BUFFER_FRAMES in half
-    // and make this total_frames_to_write > BUFFER_FRAMES * 2,
-    // make two attempts to recv.
-    if total_frames_to_write > BUFFER_FRAMES {
-        log::error!("audio device requesting {} frames, but buffer is only {} frames", total_frames_to_write, BUFFER_FRAMES);
+    let lowercase_name = name.to_lowercase();
+    for device in host.output_devices()? {
+        if device.name()?.to_lowercase().contains(&lowercase_name) {
+            return Ok(Some(device));
+        }
     }
 
-    // First fill the output buffer from a buffer we already have.
-    let initial_frames_written = fill_buffer_from_pending(buffer, state);
-    assert!(initial_frames_written <= total_frames_to_write);
+    bail!("no audio output device matching {}", name)
+}
 
-    if initial_frames_written == total_frames_to_write {
-        return;
+/// The device's default config, with any requested sample rate and channel count.
+fn stream_config(
+    device: &cpal::Device,
+    device_name: &str,
+    args: &AudioArgs,
+) -> Result<cpal::SupportedStreamConfig> {
+    let default_config = device.default_output_config()?;
+    if args.sample_rate.is_none() && args.channels.is_none() {
+        return Ok(default_config);
     }
 
-    assert!(state.pending_buffer.is_none());
+    let sample_rate = cpal::SampleRate(args.sample_rate.unwrap_or(default_config.sample_rate().0));
+    let channels = args.channels.unwrap_or(default_config.channels());
+
+    let mut candidates: Vec<_> = device.supported_output_configs()?
+        .filter(|range| {
+            range.channels() == channels
+                && range.min_sample_rate() <= sample_rate
+                && sample_rate <= range.max_sample_rate()
+        })
+        .collect();
+    // Keep the default sample format if possible.
+    candidates.sort_by_key(|range| range.sample_format() != default_config.sample_format());
+
+    candidates.into_iter().next()
+        .map(|range| range.with_sample_rate(sample_rate))
+        .ok_or_else(|| {
+            anyhow!("{} does not support {} channels at {} hz", device_name, channels, sample_rate.0)
+        })
+}
 
-    let buffer = &mut buffer[output_channels * initial_frames_written ..];
-    let final_frames_to_write = total_frames_to_write - initial_frames_written;
+fn build_stream<S>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut state: State,
+) -> Result<cpal::Stream>
+where S: SizedSample,
+      S: FromSample<f32>,
+{
+    let handle_error = |error| {
+        log::error!("audio output error: {}", error);
+    };
 
-    let fill_remaining_zeros = |buffer: &mut [S]| {
-        let out_frames = buffer.chunks_mut(output_channels);
-        for out_frame in out_frames {
-            for sample in out_frame.iter_mut() {
-                *sample = S::from_sample(0.0);
+    let stream = device.build_output_stream(
+        config,
+        move |buffer: &mut [S], info| {
+            let start = Instant::now();
+            fill_buffer(buffer, &mut state);
+
+            let timestamp = info.timestamp();
+            if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                state.stats.set_output_latency(latency);
             }
-        }
-    };
+            let frames = buffer.len() / state.output_channels as usize;
+            let budget = Duration::from_secs_f64(frames as f64 / state.sample_rate as f64);
+            state.stats.audio_callback.record(start.elapsed(), budget);
+        },
+        handle_error,
+        None,
+    )?;
+
+    Ok(stream)
+}
 
-    // Receive a new buffer.
-    match state.buf_filled_rx.try_recv() {
-        Ok(new_buffer) => {
-            state.pending_buffer = Some(PendingBuffer {
-                buf: new_buffer,
-                consumed: 0,
-            });
-            let final_frames_written = fill_buffer_from_pending(buffer, state);
-            assert!(final_frames_written <= final_frames_to_write);
-
-            let buffer = &mut buffer[output_channels * final_frames_written ..];
-            fill_remaining_zeros(buffer);
-        }
-        Err(mpsc::TryRecvError::Disconnected) => {
-            /* shutting down */
-            fill_remaining_zeros(buffer);
-        }
-        Err(mpsc::TryRecvError::Empty) => {
-            log::warn!("didn't receive buffer in time for audio out");
-            fill_remaining_zeros(buffer);
-        }
-    }
+struct State {
+    output_channels: ChannelCount,
+    sample_rate: u32,
+    target_frames: usize,
+    audio_rx: spsc::Consumer<Frame>,
+    /// Whether we've reported that the device wants more than the target latency per callback,
+    /// which only happens past `MAX_CALLBACK_FRAMES`.
+    warned_callback_size: bool,
+    stats: Arc<Stats>,
 }
 
-fn fill_buffer_from_pending<S>(
+fn fill_buffer<S>(
     buffer: &mut [S],
     state: &mut State,
-) -> usize
+)
 where S: Sample,
       S: FromSample<f32>,
 {
     let output_channels = state.output_channels as usize;
     assert!(buffer.len() % output_channels == 0);
 
-    let frames_to_write = buffer.len() / output_channels;
-    let mut frames_written = 0;
+    let total_frames_to_write = buffer.len() / output_channels;
 
-    if let Some(mut pending_buffer) = state.pending_buffer.take() {
+    if total_frames_to_write > state.target_frames && !state.warned_callback_size {
+        // The synth only keeps the target queued, so this drops out.
+        state.stats.oversized_callback_frames.store(total_frames_to_write as u64, Ordering::Relaxed);
+        state.warned_callback_size = true;
+    }
 
-        assert!(pending_buffer.consumed < pending_buffer.buf.0.len());
+    let mut frames = [[0.0; 2]; CALLBACK_CHUNK_FRAMES];
+    let mut underrun = false;
 
-        let remaining_buffer = &pending_buffer.buf.0[pending_buffer.consumed..];
-        let frames_to_write = frames_to_write.min(remaining_buffer.len());
-        let in_frames = remaining_buffer.iter().take(frames_to_write);
-        let out_frames = buffer.chunks_mut(output_channels).take(frames_to_write);
-        let frames = in_frames.zip(out_frames);
+    for out_chunk in buffer.chunks_mut(output_channels * CALLBACK_CHUNK_FRAMES) {
+        let frames_to_write = out_chunk.len() / output_channels;
+        let frames_read = state.audio_rx.pop_slice(&mut frames[..frames_to_write]);
 
-        for (in_frame, out_frame) in frames {
-            for sample in out_frame.iter_mut() {
-                *sample = S::from_sample(*in_frame);
-            }
+        if frames_read < frames_to_write {
+            underrun = true;
+            frames[frames_read..frames_to_write].fill([0.0; 2]);
         }
 
-        pending_buffer.consumed += frames_to_write;
-        frames_written += frames_to_write;
-
-        assert!(pending_buffer.consumed <= pending_buffer.buf.0.len());
-
-        if pending_buffer.consumed < pending_buffer.buf.0.len() {
-            state.pending_buffer = Some(pending_buffer);
-        } else {
-            match state.buf_empty_tx.try_send(pending_buffer.buf) {
-                Ok(_) => { },
-                Err(mpsc::TrySendError::Disconnected(_)) => {
-                    /* shutting down */
-                }
-                Err(mpsc::TrySendError::Full(_)) => {
-                    panic!("full channel");
-                }
+        let out_frames = out_chunk.chunks_mut(output_channels);
+        for (in_frame, out_frame) in frames.iter().zip(out_frames) {
+            for (channel, sample) in out_frame.iter_mut().enumerate() {
+                *sample = S::from_sample(frame_sample(*in_frame, channel, output_channels));
             }
         }
     }
 
-    frames_written
+    if underrun {
+        // Reported by the stats thread, to keep logging out of the callback.
+        state.stats.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// One channel of a frame, for a device with `channels` channels.
+///
+/// A mono device gets both channels mixed. Channels past
+/// the first two, as in surround layouts, are silent.
+fn frame_sample(frame: Frame, channel: usize, channels: usize) -> f32 {
+    match (channels, channel) {
+        (1, _) => (frame[0] + frame[1]) * 0.5,
+        (_, 0) => frame[0],
+        (_, 1) => frame[1],
+        _ => 0.0,
+    }
 }
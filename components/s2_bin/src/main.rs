@@ -4,20 +4,125 @@
 //mod plotting;
 //mod threads;
 mod audio_player;
+mod midi;
+mod midi_ports;
+mod patch_watcher;
+mod render;
+mod stats;
 mod tables;
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use clap::Parser;
-use std::sync::mpsc;
+use std::path::PathBuf;
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
 
 use s2_lib::try3::synth;
+use s2_lib::try3::patch;
+use s2_lib::try3::mpe;
+use s2_lib::try3::wavetable::Wavetable;
+use s2_lib::try3::units::{Ms, SampleRateKhz};
 
 #[derive(Parser)]
 enum Command {
-    Midi,
+    Midi {
+        /// A `.synth2` or `.json` patch to play instead of the default patch.
+        ///
+        /// The file is reloaded whenever it changes.
+        #[arg(long)]
+        patch: Option<PathBuf>,
+        /// MIDI input ports to connect to, by index or part of the name.
+        ///
+        /// May be given more than once. Connects to every port by default.
+        /// Ports plugged in later are connected when they appear.
+        #[arg(long)]
+        midi_port: Vec<midi_ports::PortSelector>,
+        #[command(flatten)]
+        audio: audio_player::AudioArgs,
+        #[command(flatten)]
+        voice: VoiceArgs,
+        /// Log underruns, processing load and latency every second.
+        #[arg(long)]
+        stats: bool,
+    },
+    /// List MIDI input ports and audio output devices.
+    ListPorts,
+    /// Render a MIDI file to WAV without an audio device.
+    Render(render::RenderArgs),
     BuildTables,
 }
 
+/// Voice management options for commands that play the synth.
+#[derive(clap::Args)]
+struct VoiceArgs {
+    /// How many voices may sound at once.
+    #[arg(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+            .range(1..=synth::MAX_POLYPHONY as u64),
+    )]
+    polyphony: usize,
+    /// Which voice a new note takes when all are in use:
+    /// oldest, quietest, low-note or high-note.
+    #[arg(long, default_value = "oldest")]
+    steal: synth::StealPolicy,
+    /// Restart a note that is already sounding instead of layering it.
+    #[arg(long)]
+    retrigger: bool,
+    /// Play one note at a time, choosing among held notes by
+    /// last, low or high note priority.
+    #[arg(long)]
+    mono: Option<synth::NotePriority>,
+    /// In mono mode, don't restart envelopes when changing notes.
+    #[arg(long)]
+    legato: bool,
+    /// In mono mode, glide between notes over this many milliseconds.
+    #[arg(long, default_value_t = 0.0)]
+    glide: f32,
+    /// Make the glide time per octave instead of per note change.
+    #[arg(long)]
+    glide_rate: bool,
+    /// Enable the MPE lower zone with this many member channels,
+    /// for controllers that don't configure zones themselves.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=15))]
+    mpe_lower: Option<u8>,
+    /// Enable the MPE upper zone with this many member channels.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=15))]
+    mpe_upper: Option<u8>,
+}
+
+impl VoiceArgs {
+    fn apply(&self, synth: &mut synth::Synth) {
+        synth.set_voice_allocation(synth::VoiceAllocation {
+            polyphony: self.polyphony,
+            steal: self.steal,
+            retrigger_same_note: self.retrigger,
+        });
+        synth.set_voice_mode(match self.mono {
+            Some(priority) => synth::VoiceMode::Mono {
+                priority,
+                legato: self.legato,
+            },
+            None => synth::VoiceMode::Poly,
+        });
+        synth.set_glide(synth::Glide {
+            time: Ms(self.glide),
+            kind: if self.glide_rate {
+                synth::GlideKind::ConstantRate
+            } else {
+                synth::GlideKind::ConstantTime
+            },
+        });
+        if let Some(member_channels) = self.mpe_lower {
+            synth.configure_mpe(mpe::LOWER_MANAGER, member_channels);
+        }
+        if let Some(member_channels) = self.mpe_upper {
+            synth.configure_mpe(mpe::UPPER_MANAGER, member_channels);
+        }
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
@@ -27,8 +132,15 @@ fn main() -> Result<()> {
     let opts = Command::parse();
 
     match opts {
-        Command::Midi => {
-            do_midi()?;
+        Command::Midi { patch, midi_port, audio, voice, stats } => {
+            do_midi(patch, midi_port, audio, voice, stats)?;
+        }
+        Command::ListPorts => {
+            midi_ports::list()?;
+            audio_player::list_devices()?;
+        }
+        Command::Render(args) => {
+            render::render(args)?;
         }
         Command::BuildTables => {
             tables::build()?;
@@ -38,71 +150,72 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn do_midi() -> Result<()> {
-    let audio_player = audio_player::start_player()?;
+fn do_midi(
+    patch_path: Option<PathBuf>,
+    port_selectors: Vec<midi_ports::PortSelector>,
+    audio_args: audio_player::AudioArgs,
+    voice_args: VoiceArgs,
+    log_stats: bool,
+) -> Result<()> {
+    let patch = match &patch_path {
+        Some(path) => {
+            let patch = patch::load(path)?;
+            let wavetable = patch::load_wavetable(path, &patch)?;
+            log::info!("loaded patch {} from {}", patch.name, path.display());
+            Some((patch, wavetable))
+        }
+        None => None,
+    };
+
+    const MAX_PATCH_UPDATES: usize = 4;
+
+    let (patch_tx, patch_rx) = mpsc::sync_channel(MAX_PATCH_UPDATES);
+    let (retired_tx, retired_rx) = mpsc::sync_channel(MAX_PATCH_UPDATES * 2);
+
+    let patch_watcher = match patch_path {
+        Some(path) => Some(patch_watcher::start(path, patch_tx, retired_rx)?),
+        None => None,
+    };
+
+    let stats = Arc::new(stats::Stats::default());
+    let stats_reporter = stats::start_reporter(stats.clone(), log_stats)?;
+
+    let audio_player = audio_player::start_player(&audio_args, stats.clone())?;
     let (audio_player_channels, audio_player_stream) =
         audio_player.map(|player| {
             (Some(player.channels), Some(player.stream))
         }).unwrap_or((None, None));
 
-    let (midi, midi_rx) = {
-        use midir::{Ignore, MidiInput};
+    const MAX_MIDI_MESSAGES: usize = 1024;
 
-        let mut midi_in = MidiInput::new("midir test input")?;
-        midi_in.ignore(Ignore::None);
-
-        log::info!("available midi input ports:");
-        for (i, p) in midi_in.ports().iter().enumerate() {
-            log::info!("{}: {}", i, midi_in.port_name(p)?);
-        }
-
-        const MAX_MIDI_MESSAGES: usize = 1024;
-
-        let (midi_tx, midi_rx) = mpsc::sync_channel(MAX_MIDI_MESSAGES);
-
-        let port = midi_in.ports().get(0).cloned();
-        match port {
-            Some(port) => {
-                let midi = midi_in.connect(
-                    &port,
-                    "midi",
-                    move |stamp, msg, _| {
-                        match midi_tx.try_send(msg.to_vec()) {
-                            Ok(_) => { },
-                            Err(mpsc::TrySendError::Disconnected(_)) => {
-                                /* shutting down? */
-                            },
-                            Err(mpsc::TrySendError::Full(_)) => {
-                                log::error!("midi channel full");
-                            }
-                        }
-                    },
-                    ()
-                ).map_err(|e| anyhow!("{}", e))?;
-                (Some(midi), midi_rx)
-            }
-            None => {
-                (None, midi_rx)
-            }
-        }
-    };
+    let (midi_txs, midi_queues) = midi_ports::queues(MAX_MIDI_MESSAGES);
+    let midi_ports = midi_ports::start(port_selectors, midi_txs)?;
 
     let synth_thread = std::thread::Builder::new()
         .name("synth".to_string())
         .spawn(move || {
-            run_synth(audio_player_channels, midi_rx);
+            let patch_channels = PatchChannels { patch_rx, retired_tx, pending: None };
+            run_synth(audio_player_channels, midi_queues, patch_channels, patch, voice_args, &stats);
         })?;
 
     std::io::stdin().read_line(&mut String::new());
 
-    drop(midi);
+    midi_ports.stop();
+    stats_reporter.stop();
     drop(audio_player_stream);
 
-    let threads = [
+    let mut threads = vec![
         synth_thread,
+        midi_ports.thread,
+        stats_reporter.thread,
     ];
 
-    let thread_results = threads.map(|t| {
+    if let Some(patch_watcher) = patch_watcher {
+        patch_watcher.stop();
+        threads.push(patch_watcher.thread);
+    }
+
+    let thread_results = threads.into_iter().map(|t| {
         (
             t.thread().name().unwrap_or("unknown").to_owned(),
             t.join()
@@ -118,48 +231,90 @@ fn do_midi() -> Result<()> {
     Ok(())
 }
 
+/// Frames the synth renders at a time, independent of the device callback size.
+const SYNTH_BLOCK_FRAMES: usize = 64;
+
+/// How quickly the stream clock follows the device.
+const CLOCK_SMOOTHING: f64 = 0.01;
+
 fn run_synth(
     audio_player_channels: Option<audio_player::PlayerChannels>,
-    midi_rx: mpsc::Receiver<Vec<u8>>,
+    mut midi_queues: midi_ports::MidiQueues,
+    mut patch_channels: PatchChannels,
+    patch: Option<(patch::Patch, Arc<Wavetable>)>,
+    voice_args: VoiceArgs,
+    stats: &stats::Stats,
 ) {
-    use s2_lib::try3::units::SampleRateKhz;
-
-    let Some(audio_player_channels) = audio_player_channels else {
+    let Some(mut audio_player_channels) = audio_player_channels else {
         log::info!("no audio player");
         return;
     };
 
     let sample_rate = SampleRateKhz(audio_player_channels.sample_rate);
-    let mut synth = synth::Synth::new();
-
-    loop {
-        match audio_player_channels.buf_empty_rx.recv() {
-            Ok(mut buffer) => {
-                let mut chunks = buffer.as_slice_mut().array_chunks_mut::<16>();
-
-                while let Some(chunk) = chunks.next() {
-                    apply_all_midi_messages(&midi_rx, &mut synth);
-                    synth.sample(chunk, sample_rate);
-                }
-
-                let mut remainder = chunks.into_remainder();
-                apply_all_midi_messages(&midi_rx, &mut synth);
-                synth.sample(remainder, sample_rate);
-
-                match audio_player_channels.buf_filled_tx.try_send(buffer) {
-                    Ok(_) => { },
-                    Err(mpsc::TrySendError::Disconnected(_)) => {
-                        /* shutting down */
-                    }
-                    Err(mpsc::TrySendError::Full(_)) => {
-                        panic!("full channel");
-                    }
-                }
-            }
-            Err(mpsc::RecvError) => {
-                break;
-            }
+    let target_frames = audio_player_channels.target_frames;
+    let audio_tx = &mut audio_player_channels.audio_tx;
+    let mut synth = match patch {
+        Some((patch, wavetable)) => synth::Synth::from_patch(&patch, wavetable),
+        None => synth::Synth::new(),
+    };
+    voice_args.apply(&mut synth);
+    let mut midi_input = midi::MidiInput::new();
+
+    let mut clock = StreamClock::new(sample_rate);
+    let mut rendered_frames: u64 = 0;
+    let mut left = [0.0; SYNTH_BLOCK_FRAMES];
+    let mut right = [0.0; SYNTH_BLOCK_FRAMES];
+    let mut frames = [[0.0; 2]; SYNTH_BLOCK_FRAMES];
+    let idle_time = frames_duration(SYNTH_BLOCK_FRAMES / 2, sample_rate);
+
+    // The device dropping its end of the ring means we're shutting down.
+    while !audio_tx.is_abandoned() {
+        let queued_frames = audio_tx.len();
+        if queued_frames >= target_frames {
+            std::thread::sleep(idle_time);
+            continue;
         }
+
+        apply_patch_updates(&mut patch_channels, &mut synth);
+
+        let now = Instant::now();
+        clock.update(now, rendered_frames - queued_frames as u64);
+        let output_latency = stats.output_latency();
+        let block_start = rendered_frames;
+        let block_end = block_start + SYNTH_BLOCK_FRAMES as u64;
+
+        // Events are played a fixed latency after they arrive, so the
+        // ones for later blocks wait in the queue.
+        let event_frames = |midi_event: &midi_ports::MidiEvent| {
+            let received_frame = clock.frame_at(midi_event.received);
+            (received_frame, (received_frame + target_frames as f64).max(0.0) as u64)
+        };
+        let midi_events = std::iter::from_fn(|| {
+            let midi_event = midi_queues.pop_if(|midi_event| event_frames(midi_event).1 < block_end)?;
+            let (received_frame, frame) = event_frames(&midi_event);
+
+            let frame = frame.max(block_start);
+            let delay = (frame as f64 - received_frame).max(0.0) as usize;
+            stats.midi_latency.record(frames_duration(delay, sample_rate) + output_latency);
+
+            Some(synth::TimedEvent {
+                frame: (frame - block_start) as usize,
+                event: midi_event,
+            })
+        });
+        synth.sample_events(&mut left, &mut right, sample_rate, midi_events, |synth, midi_event| {
+            midi_input.apply(midi_event.channel, midi_event.message, synth);
+        });
+
+        for (frame, (left, right)) in frames.iter_mut().zip(left.iter().zip(right.iter())) {
+            *frame = [*left, *right];
+        }
+        // The ring has room for the target and more, so this all fits.
+        let pushed = audio_tx.push_slice(&frames);
+        debug_assert_eq!(pushed, SYNTH_BLOCK_FRAMES);
+        rendered_frames += SYNTH_BLOCK_FRAMES as u64;
+
+        stats.synth_render.record(now.elapsed(), frames_duration(SYNTH_BLOCK_FRAMES, sample_rate));
     }
 
     drop(audio_player_channels);
@@ -167,94 +322,76 @@ fn run_synth(
     log::info!("synth thread exiting");
 }
 
-fn apply_all_midi_messages(
-    midi_rx: &mpsc::Receiver<Vec<u8>>,
+/// Patches from the watcher, and the wavetables going back to it.
+struct PatchChannels {
+    patch_rx: mpsc::Receiver<patch_watcher::PatchUpdate>,
+    /// Wavetables the synth is done with, freed by the watcher.
+    retired_tx: mpsc::SyncSender<Arc<Wavetable>>,
+    /// An update the synth had no room for, retried before any newer one.
+    pending: Option<patch_watcher::PatchUpdate>,
+}
+
+fn apply_patch_updates(
+    patch_channels: &mut PatchChannels,
     synth: &mut synth::Synth,
 ) {
-    loop {
-        match midi_rx.try_recv() {
-            Ok(midi_msg) => {
-                apply_midi(&midi_msg, synth);
-                // fixme: send midi buffer back to avoid deallocating it
-                // on the synth thread.
-                // or just do the midi parsing in the midi thread.
-            }
-            Err(_) => {
-                break;
-            }
+    // Wavetables the channel has no room for stay with the synth until the
+    // next block, so the last reference is never dropped on this thread.
+    synth.send_retired_wavetables(|wavetable| {
+        patch_channels.retired_tx.try_send(wavetable).map_err(|error| match error {
+            mpsc::TrySendError::Full(wavetable) | mpsc::TrySendError::Disconnected(wavetable) => wavetable,
+        })
+    });
+
+    // If several arrive at once, the synth retires all but the newest.
+    let updates = patch_channels.pending.take().into_iter().chain(patch_channels.patch_rx.try_iter());
+    for update in updates {
+        if let Some((config, wavetable)) = synth.set_patch(update.config, update.wavetable) {
+            patch_channels.pending = Some(patch_watcher::PatchUpdate { config, wavetable });
+            break;
         }
     }
 }
 
-fn apply_midi(midi_msg: &[u8], synth: &mut synth::Synth) {
-    use muddy2::message::{Message, ChannelMessage, ChannelMessageType, ChannelVoiceMessage};
-    use s2_lib::try3::units::Unipolar;
-
-    let midi_msg = parse_midi_message(&midi_msg);
-    match midi_msg {
-        Some(Message::Channel(ch_msg)) => {
-            match ch_msg.message {
-                ChannelMessageType::ChannelVoice(cvm) => {
-                    if let Some((note_number, velocity)) = cvm.should_note_on() {
-                        let note = synth::Note(u8::from(note_number.0));
-                        let velocity = synth::Velocity(Unipolar(f32::from(u8::from(velocity.0)) / 127.0));
-                        synth.note_on(note, velocity);
-                    } else if let Some((note_number, velocity)) = cvm.should_note_off() {
-                        let note = synth::Note(u8::from(note_number.0));
-                        let _velocity = synth::Velocity(Unipolar(f32::from(u8::from(velocity.0)) / 127.0));
-                        synth.note_off(note);
-                    }
-                }
-                _ => { }
-            }
-        }
-        _ => { }
-    }
+/// Maps instants to positions in the stream of frames the device has played.
+///
+/// The device takes frames from the ring a callback at a time, so the
+/// played position is only known in steps. Following it slowly keeps
+/// those steps from showing up as jitter in event timing.
+struct StreamClock {
+    start: Instant,
+    sample_rate: SampleRateKhz,
+    /// Frames played minus frames of time since `start`.
+    offset: Option<f64>,
 }
 
-fn parse_midi_message(midi_msg: &[u8]) -> Option<muddy2::message::Message> {
-    log::trace!("midi msg bytes: {:?}", midi_msg);
-
-    use muddy2::parser::{Parser, MessageParseOutcome, MessageParseOutcomeStatus};
-    use muddy2::message;
-
-    let mut parser = Parser::new();
-    let parse = parser.parse(&midi_msg);
-
-    match parse {
-        Ok(parse) => {
-            if parse.bytes_consumed as usize != midi_msg.len() {
-                log::error!("did not consume entire midi message. len = {}, consumed = {}", midi_msg.len(), parse.bytes_consumed);
-            }
-            match parse.status {
-                MessageParseOutcomeStatus::Message(
-                    message::Message::System(
-                        message::SystemMessage::SystemRealTime(
-                            message::SystemRealTimeMessage::TimingClock
-                        )
-                    )
-                ) => {
-                    // these happen a lot
-                    log::trace!("midi msg: {:#?}", parse.status);
-                }
-                _ => {
-                    log::debug!("midi msg: {:#?}", parse.status);
-                }
-            }
-            match parse.status {
-                MessageParseOutcomeStatus::Message(msg) => Some(msg),
-                _ => None,
-            }
-        }
-        Err(e) => {
-            log::error!("midi parse error: {}", e);
-            let mut maybe_source = e.source();
-            while let Some(source) = maybe_source {
-                log::error!("source: {}", source);
-                maybe_source = source.source();
-            }
-            None
+impl StreamClock {
+    fn new(sample_rate: SampleRateKhz) -> StreamClock {
+        StreamClock {
+            start: Instant::now(),
+            sample_rate,
+            offset: None,
         }
     }
+
+    fn update(&mut self, now: Instant, played_frames: u64) {
+        let offset = played_frames as f64 - self.elapsed_frames(now);
+        self.offset = Some(match self.offset {
+            Some(previous) => previous + (offset - previous) * CLOCK_SMOOTHING,
+            None => offset,
+        });
+    }
+
+    /// The frame the device was playing at `instant`.
+    fn frame_at(&self, instant: Instant) -> f64 {
+        self.elapsed_frames(instant) + self.offset.unwrap_or(0.0)
+    }
+
+    fn elapsed_frames(&self, instant: Instant) -> f64 {
+        instant.saturating_duration_since(self.start).as_secs_f64() * self.sample_rate.0 as f64
+    }
 }
 
+fn frames_duration(frames: usize, sample_rate: SampleRateKhz) -> Duration {
+    Duration::from_secs_f64(frames as f64 / sample_rate.0 as f64)
+}
@@ -0,0 +1,289 @@
+//! Routing of MIDI channel messages to the synth.
+//!
+//! Live MIDI and MIDI files are decoded by different crates.
+//! Both are converted to `ChannelMessage` and applied here,
+//! along with the state that spans several messages,
+//! like registered parameter numbers.
+
+use s2_lib::try3::mpe::Channel;
+use s2_lib::try3::synth::{self, Synth};
+use s2_lib::try3::units::{Bipolar, Unipolar};
+
+const MOD_WHEEL_CC: u8 = 1;
+const DATA_ENTRY_MSB_CC: u8 = 6;
+const SUSTAIN_CC: u8 = 64;
+const SOSTENUTO_CC: u8 = 66;
+const TIMBRE_CC: u8 = 74;
+const NRPN_LSB_CC: u8 = 98;
+const NRPN_MSB_CC: u8 = 99;
+const RPN_LSB_CC: u8 = 100;
+const RPN_MSB_CC: u8 = 101;
+
+/// Registered parameter numbers, as (msb, lsb).
+const PITCH_BEND_SENSITIVITY_RPN: (u8, u8) = (0, 0);
+const MPE_CONFIGURATION_RPN: (u8, u8) = (0, 6);
+/// Selecting this deselects the current parameter,
+/// so stray data entry messages change nothing.
+const NULL_RPN: (u8, u8) = (127, 127);
+
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub enum ChannelMessage {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8 },
+    PolyPressure { note: u8, pressure: u8 },
+    ControlChange { control: u8, value: u8 },
+    ChannelPressure { pressure: u8 },
+    /// From 0 to 16383, with no bend at 8192.
+    PitchBend { value: u16 },
+}
+
+pub struct MidiInput {
+    /// The registered parameter selected on each channel.
+    ///
+    /// Selecting a non-registered parameter deselects it.
+    rpn: [(Option<u8>, Option<u8>); 16],
+}
+
+impl MidiInput {
+    pub fn new() -> MidiInput {
+        MidiInput {
+            rpn: [(None, None); 16],
+        }
+    }
+
+    /// Messages on MPE member channels go to the notes on that channel,
+    /// and all others to the whole synth.
+    pub fn apply(&mut self, channel: u8, message: ChannelMessage, synth: &mut Synth) {
+        let channel = Channel(channel & 0x0F);
+        let member = synth.mpe_zones().is_member(channel);
+
+        match message {
+            ChannelMessage::NoteOn { note, velocity } if velocity > 0 => {
+                let note = synth::Note(note);
+                let velocity = synth::Velocity(Unipolar(f32::from(velocity) / 127.0));
+                if member {
+                    synth.note_on_member(channel, note, velocity);
+                } else {
+                    synth.note_on(note, velocity);
+                }
+            }
+            ChannelMessage::NoteOn { note, .. } | ChannelMessage::NoteOff { note } => {
+                let note = synth::Note(note);
+                if member {
+                    synth.note_off_member(channel, note);
+                } else {
+                    synth.note_off(note);
+                }
+            }
+            ChannelMessage::PolyPressure { note, pressure } => {
+                // MPE controllers send pressure as channel pressure on member channels.
+                if !member {
+                    let pressure = Unipolar(f32::from(pressure) / 127.0);
+                    synth.set_poly_pressure(synth::Note(note), pressure);
+                }
+            }
+            ChannelMessage::PitchBend { value } => {
+                let pitch_bend = Bipolar((f32::from(value) - 8192.0) / 8192.0);
+                if member {
+                    synth.set_member_pitch_bend(channel, pitch_bend);
+                } else {
+                    synth.set_pitch_bend(pitch_bend);
+                }
+            }
+            ChannelMessage::ChannelPressure { pressure } => {
+                let pressure = Unipolar(f32::from(pressure) / 127.0);
+                if member {
+                    synth.set_member_pressure(channel, pressure);
+                } else {
+                    synth.set_pressure(pressure);
+                }
+            }
+            ChannelMessage::ControlChange { control, value } => {
+                self.control_change(channel, member, control, value, synth);
+            }
+        }
+    }
+
+    fn control_change(
+        &mut self,
+        channel: Channel,
+        member: bool,
+        control: u8,
+        value: u8,
+        synth: &mut Synth,
+    ) {
+        // Pedals are down from the middle of the range up.
+        let pedal_down = value >= 64;
+        let rpn = &mut self.rpn[channel.0 as usize];
+
+        match control {
+            MOD_WHEEL_CC => synth.set_mod_wheel(Unipolar(f32::from(value) / 127.0)),
+            SUSTAIN_CC => synth.set_sustain(pedal_down),
+            SOSTENUTO_CC => synth.set_sostenuto(pedal_down),
+            TIMBRE_CC => {
+                let timbre = Unipolar(f32::from(value) / 127.0);
+                if member {
+                    synth.set_member_timbre(channel, timbre);
+                } else {
+                    synth.set_timbre(timbre);
+                }
+            }
+            RPN_MSB_CC | RPN_LSB_CC => {
+                if control == RPN_MSB_CC {
+                    rpn.0 = Some(value);
+                } else {
+                    rpn.1 = Some(value);
+                }
+                if *rpn == (Some(NULL_RPN.0), Some(NULL_RPN.1)) {
+                    *rpn = (None, None);
+                }
+            }
+            NRPN_MSB_CC | NRPN_LSB_CC => *rpn = (None, None),
+            DATA_ENTRY_MSB_CC => {
+                if let (Some(msb), Some(lsb)) = *rpn {
+                    registered_parameter(channel, member, (msb, lsb), value, synth);
+                }
+            }
+            _ => { }
+        }
+    }
+}
+
+fn registered_parameter(
+    channel: Channel,
+    member: bool,
+    rpn: (u8, u8),
+    value: u8,
+    synth: &mut Synth,
+) {
+    match rpn {
+        MPE_CONFIGURATION_RPN => {
+            synth.configure_mpe(channel, value);
+        }
+        PITCH_BEND_SENSITIVITY_RPN if member => {
+            synth.set_mpe_pitch_bend_range(channel, f32::from(value));
+        }
+        PITCH_BEND_SENSITIVITY_RPN => {
+            log::debug!("ignoring pitch bend sensitivity on channel {}, the patch sets it", channel.0);
+        }
+        _ => {
+            log::debug!("ignoring rpn {:?} on channel {}", rpn, channel.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s2_lib::try3::mpe::{LOWER_MANAGER, MpeZone, MpeZones};
+
+    /// Send `(control, value)` pairs on `channel`.
+    fn control_changes(input: &mut MidiInput, synth: &mut Synth, channel: u8, changes: &[(u8, u8)]) {
+        for &(control, value) in changes {
+            input.apply(channel, ChannelMessage::ControlChange { control, value }, synth);
+        }
+    }
+
+    /// The lower zone with every other channel as a member.
+    fn lower_zone(input: &mut MidiInput, synth: &mut Synth) {
+        control_changes(input, synth, LOWER_MANAGER.0, &[(RPN_MSB_CC, 0), (RPN_LSB_CC, 6), (DATA_ENTRY_MSB_CC, 15)]);
+    }
+
+    #[test]
+    fn test_mpe_configuration() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        lower_zone(&mut input, &mut synth);
+        assert_eq!(synth.mpe_zones(), MpeZones { lower: Some(MpeZone::new(15)), upper: None });
+        assert_eq!(input.rpn[0], (Some(0), Some(6)));
+
+        // Data entry repeats on the selected parameter.
+        control_changes(&mut input, &mut synth, 0, &[(DATA_ENTRY_MSB_CC, 0)]);
+        assert_eq!(synth.mpe_zones(), MpeZones::default());
+    }
+
+    #[test]
+    fn test_pitch_bend_sensitivity() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        lower_zone(&mut input, &mut synth);
+
+        control_changes(&mut input, &mut synth, 1, &[(RPN_MSB_CC, 0), (RPN_LSB_CC, 0), (DATA_ENTRY_MSB_CC, 24)]);
+        assert_eq!(synth.mpe_zones().lower.expect("lower zone").pitch_bend_range, 24.0);
+
+        // The manager channel's range is set by the patch.
+        control_changes(&mut input, &mut synth, 0, &[(RPN_MSB_CC, 0), (RPN_LSB_CC, 0), (DATA_ENTRY_MSB_CC, 2)]);
+        assert_eq!(synth.mpe_zones().lower.expect("lower zone").pitch_bend_range, 24.0);
+    }
+
+    #[test]
+    fn test_null_rpn_deselects() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        control_changes(&mut input, &mut synth, 0, &[
+            (RPN_MSB_CC, 0), (RPN_LSB_CC, 6),
+            (RPN_MSB_CC, 127), (RPN_LSB_CC, 127),
+        ]);
+        assert_eq!(input.rpn[0], (None, None));
+
+        control_changes(&mut input, &mut synth, 0, &[(DATA_ENTRY_MSB_CC, 15)]);
+        assert_eq!(synth.mpe_zones(), MpeZones::default());
+    }
+
+    #[test]
+    fn test_nrpn_deselects() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        control_changes(&mut input, &mut synth, 0, &[
+            (RPN_MSB_CC, 0), (RPN_LSB_CC, 6),
+            (NRPN_MSB_CC, 0), (NRPN_LSB_CC, 6),
+            (DATA_ENTRY_MSB_CC, 15),
+        ]);
+        assert_eq!(input.rpn[0], (None, None));
+        assert_eq!(synth.mpe_zones(), MpeZones::default());
+    }
+
+    #[test]
+    fn test_rpn_is_per_channel() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        control_changes(&mut input, &mut synth, 0, &[(RPN_MSB_CC, 0), (RPN_LSB_CC, 6)]);
+        control_changes(&mut input, &mut synth, 15, &[(DATA_ENTRY_MSB_CC, 15)]);
+        assert_eq!(input.rpn[15], (None, None));
+        assert_eq!(synth.mpe_zones(), MpeZones::default());
+    }
+
+    #[test]
+    fn test_pedals() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        control_changes(&mut input, &mut synth, 0, &[(SUSTAIN_CC, 64), (SOSTENUTO_CC, 127)]);
+        assert!(synth.sustain());
+        assert!(synth.sostenuto());
+
+        control_changes(&mut input, &mut synth, 0, &[(SUSTAIN_CC, 63), (SOSTENUTO_CC, 0)]);
+        assert!(!synth.sustain());
+        assert!(!synth.sostenuto());
+    }
+
+    #[test]
+    fn test_timbre_on_member_channels() {
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        control_changes(&mut input, &mut synth, 3, &[(TIMBRE_CC, 127), (MOD_WHEEL_CC, 127)]);
+        assert_eq!(synth.controllers().timbre.target(), 1.0);
+        assert_eq!(synth.controllers().mod_wheel.target(), 1.0);
+
+        let mut input = MidiInput::new();
+        let mut synth = Synth::new();
+        lower_zone(&mut input, &mut synth);
+        control_changes(&mut input, &mut synth, 3, &[(TIMBRE_CC, 127)]);
+        assert_eq!(synth.member_controllers(Channel(3)).timbre.target(), 1.0);
+        assert_eq!(synth.controllers().timbre.target(), 0.0);
+
+        control_changes(&mut input, &mut synth, 0, &[(TIMBRE_CC, 127)]);
+        assert_eq!(synth.controllers().timbre.target(), 1.0);
+        assert_eq!(synth.member_controllers(Channel(4)).timbre.target(), 0.0);
+    }
+}
@@ -0,0 +1,479 @@
+//! Live MIDI input from hardware ports.
+//!
+//! Ports are chosen by name or index, and re-scanned periodically
+//! on their own thread, so a controller plugged in after startup
+//! is connected and one that is unplugged is dropped.
+//! Indexes refer to the port list at startup, since they shift as ports come and go.
+//! Messages are decoded on the port's thread and queued for the synth,
+//! each port on its own queue, so every queue has a single producer.
+
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use s2_lib::try3::spsc;
+
+use crate::midi::ChannelMessage;
+
+const CLIENT_NAME: &str = "s2";
+
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most ports connected at once, each with its own queue.
+const MAX_PORTS: usize = 8;
+
+/// Picks MIDI input ports, as an index in the port list
+/// or part of a port name, ignoring case.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum PortSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl FromStr for PortSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<PortSelector, Self::Err> {
+        Ok(match s.parse() {
+            Ok(index) => PortSelector::Index(index),
+            Err(_) => PortSelector::Name(s.to_lowercase()),
+        })
+    }
+}
+
+/// A `PortSelector` with any index resolved to the id of the port
+/// it picked at startup.
+enum ResolvedSelector {
+    Id(String),
+    Name(String),
+}
+
+impl ResolvedSelector {
+    fn matches(&self, id: &str, name: &str) -> bool {
+        match self {
+            ResolvedSelector::Id(selected) => selected == id,
+            ResolvedSelector::Name(selected) => name.to_lowercase().contains(selected.as_str()),
+        }
+    }
+}
+
+fn resolve_selectors(
+    selectors: Vec<PortSelector>,
+    ports: &[midir::MidiInputPort],
+) -> Vec<ResolvedSelector> {
+    selectors.into_iter().filter_map(|selector| match selector {
+        PortSelector::Index(index) => match ports.get(index) {
+            Some(port) => Some(ResolvedSelector::Id(port.id())),
+            None => {
+                log::warn!("no midi input port {}", index);
+                None
+            }
+        },
+        PortSelector::Name(name) => Some(ResolvedSelector::Name(name)),
+    }).collect()
+}
+
+/// A MIDI channel message and when it arrived.
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct MidiEvent {
+    pub received: Instant,
+    pub channel: u8,
+    pub message: ChannelMessage,
+}
+
+/// Converts midir timestamps, which count microseconds from a point
+/// that differs between backends, to `Instant`s.
+///
+/// The clock is anchored at the first message received.
+struct MidiClock {
+    anchor: Option<(u64, Instant)>,
+}
+
+impl MidiClock {
+    fn new() -> MidiClock {
+        MidiClock { anchor: None }
+    }
+
+    fn instant(&mut self, stamp: u64) -> Instant {
+        let (anchor_stamp, anchor_instant) = *self.anchor.get_or_insert_with(|| {
+            (stamp, Instant::now())
+        });
+        anchor_instant + Duration::from_micros(stamp.saturating_sub(anchor_stamp))
+    }
+}
+
+/// Create a queue for each of up to `MAX_PORTS` ports, holding
+/// `capacity` events each, to pass to `start` and the synth.
+pub fn queues(capacity: usize) -> (Vec<spsc::Producer<MidiEvent>>, MidiQueues) {
+    let (producers, consumers) = (0..MAX_PORTS).map(|_| spsc::channel(capacity)).unzip();
+    (producers, MidiQueues { queues: consumers })
+}
+
+/// The synth's end of every port's queue.
+pub struct MidiQueues {
+    queues: Vec<spsc::Consumer<MidiEvent>>,
+}
+
+impl MidiQueues {
+    /// Pop the earliest event across all ports if `f` accepts it.
+    ///
+    /// Each port's events are in the order they arrived,
+    /// so the earliest is at the front of one of the queues.
+    pub fn pop_if(&mut self, f: impl FnOnce(&MidiEvent) -> bool) -> Option<MidiEvent> {
+        let (midi_event, queue) = self.queues.iter_mut()
+            .filter_map(|queue| Some((queue.peek()?, queue)))
+            .min_by_key(|(midi_event, _)| midi_event.received)?;
+        if !f(&midi_event) {
+            return None;
+        }
+        queue.pop()
+    }
+}
+
+/// A port's queue, which goes back to the scanner for another port
+/// when its connection is closed or fails to open.
+struct PortQueue {
+    midi_tx: Option<spsc::Producer<MidiEvent>>,
+    free_tx: mpsc::Sender<spsc::Producer<MidiEvent>>,
+}
+
+impl Drop for PortQueue {
+    fn drop(&mut self) {
+        if let Some(midi_tx) = self.midi_tx.take() {
+            // The scanner only stops listening once it has closed every port.
+            self.free_tx.send(midi_tx).ok();
+        }
+    }
+}
+
+pub struct MidiPorts {
+    pub thread: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MidiPorts {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Print the available MIDI input ports.
+pub fn list() -> Result<()> {
+    let midi_in = MidiInput::new(CLIENT_NAME)?;
+    println!("midi input ports:");
+    for (index, port) in midi_in.ports().iter().enumerate() {
+        println!("  {}: {}", index, midi_in.port_name(port)?);
+    }
+    Ok(())
+}
+
+/// Connect to every port matching any of `selectors`, or every port
+/// if there are none, as they appear, sending each port's messages
+/// to one of the queues from `queues`.
+pub fn start(
+    selectors: Vec<PortSelector>,
+    midi_txs: Vec<spsc::Producer<MidiEvent>>,
+) -> Result<MidiPorts> {
+    let scanner = MidiInput::new(CLIENT_NAME)?;
+    let ports = scanner.ports();
+
+    log::info!("available midi input ports:");
+    for (index, port) in ports.iter().enumerate() {
+        log::info!("{}: {}", index, scanner.port_name(port)?);
+    }
+
+    // No selectors connects every port.
+    let selectors = (!selectors.is_empty()).then(|| resolve_selectors(selectors, &ports));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    // Each port calls back on its own thread, so each gets its own queue.
+    let (free_tx, free_rx) = mpsc::channel();
+    for midi_tx in midi_txs {
+        free_tx.send(midi_tx)?;
+    }
+    let free_queues = FreeQueues { free_tx, free_rx };
+
+    let thread = thread::Builder::new()
+        .name("midi-ports".to_string())
+        .spawn(move || {
+            run_scanner(scanner, selectors, free_queues, &thread_stop);
+        })?;
+
+    Ok(MidiPorts {
+        thread,
+        stop,
+    })
+}
+
+/// Queues not in use by a port.
+struct FreeQueues {
+    free_tx: mpsc::Sender<spsc::Producer<MidiEvent>>,
+    free_rx: mpsc::Receiver<spsc::Producer<MidiEvent>>,
+}
+
+impl FreeQueues {
+    fn take(&self) -> Option<PortQueue> {
+        Some(PortQueue {
+            midi_tx: Some(self.free_rx.try_recv().ok()?),
+            free_tx: self.free_tx.clone(),
+        })
+    }
+}
+
+fn run_scanner(
+    scanner: MidiInput,
+    selectors: Option<Vec<ResolvedSelector>>,
+    free_queues: FreeQueues,
+    stop: &AtomicBool,
+) {
+    // By port id, which stays the same while the port exists.
+    let mut connections: HashMap<String, MidiInputConnection<PortQueue>> = HashMap::new();
+
+    let selectors = selectors.as_deref();
+
+    scan(&scanner, selectors, &free_queues, &mut connections);
+    if connections.is_empty() {
+        log::warn!("no matching midi input ports, waiting for one to be plugged in");
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(RESCAN_INTERVAL);
+        scan(&scanner, selectors, &free_queues, &mut connections);
+    }
+
+    log::info!("midi port scanner exiting");
+}
+
+fn scan(
+    scanner: &MidiInput,
+    selectors: Option<&[ResolvedSelector]>,
+    free_queues: &FreeQueues,
+    connections: &mut HashMap<String, MidiInputConnection<PortQueue>>,
+) {
+    let ports = scanner.ports();
+
+    connections.retain(|id, _| {
+        let present = ports.iter().any(|port| port.id() == *id);
+        if !present {
+            log::info!("midi input port {} disconnected", id);
+        }
+        present
+    });
+
+    for (index, port) in ports.iter().enumerate() {
+        let id = port.id();
+        if connections.contains_key(&id) {
+            continue;
+        }
+        let Ok(name) = scanner.port_name(port) else {
+            continue;
+        };
+        let selected = match selectors {
+            None => true,
+            Some(selectors) => selectors.iter().any(|selector| selector.matches(&id, &name)),
+        };
+        if !selected {
+            continue;
+        }
+
+        let Some(queue) = free_queues.take() else {
+            log::warn!("already connected to {} midi input ports, skipping {}", MAX_PORTS, name);
+            continue;
+        };
+        match connect(port, &name, queue) {
+            Ok(connection) => {
+                log::info!("connected to midi input port {}: {}", index, name);
+                connections.insert(id, connection);
+            }
+            Err(e) => {
+                log::error!("connecting to midi input port {}: {:#}", name, e);
+            }
+        }
+    }
+}
+
+fn connect(
+    port: &midir::MidiInputPort,
+    name: &str,
+    queue: PortQueue,
+) -> Result<MidiInputConnection<PortQueue>> {
+    let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+    midi_in.ignore(Ignore::None);
+
+    let mut midi_clock = MidiClock::new();
+    midi_in.connect(
+        port,
+        name,
+        move |stamp, msg, queue| {
+            let Some((channel, message)) = parse_channel_message(msg) else {
+                return;
+            };
+            let midi_event = MidiEvent {
+                received: midi_clock.instant(stamp),
+                channel,
+                message,
+            };
+            let Some(midi_tx) = &mut queue.midi_tx else {
+                return;
+            };
+            if midi_tx.push(midi_event).is_err() {
+                log::error!("midi queue full");
+            }
+        },
+        queue,
+    ).map_err(|e| anyhow!("{}", e))
+}
+
+/// Decode the channel voice messages the synth responds to.
+///
+/// This runs on the MIDI thread, so the synth thread only sees
+/// the decoded message and its channel.
+fn parse_channel_message(midi_msg: &[u8]) -> Option<(u8, ChannelMessage)> {
+    use muddy2::message::{Message, ChannelMessageType, ChannelVoiceMessage};
+
+    let midi_msg = parse_midi_message(&midi_msg);
+    let Some(Message::Channel(ch_msg)) = midi_msg else {
+        return None;
+    };
+    let ChannelMessageType::ChannelVoice(cvm) = ch_msg.message else {
+        return None;
+    };
+
+    let message = if let Some((note_number, velocity)) = cvm.should_note_on() {
+        ChannelMessage::NoteOn {
+            note: u8::from(note_number.0),
+            velocity: u8::from(velocity.0),
+        }
+    } else if let Some((note_number, _velocity)) = cvm.should_note_off() {
+        ChannelMessage::NoteOff {
+            note: u8::from(note_number.0),
+        }
+    } else {
+        match cvm {
+            ChannelVoiceMessage::PitchBendChange { value } => {
+                ChannelMessage::PitchBend { value: u16::from(value.0) }
+            }
+            ChannelVoiceMessage::ControlChange { control, value } => {
+                ChannelMessage::ControlChange {
+                    control: u8::from(control.0),
+                    value: u8::from(value.0),
+                }
+            }
+            ChannelVoiceMessage::PolyphonicKeyPressure { note_number, pressure } => {
+                ChannelMessage::PolyPressure {
+                    note: u8::from(note_number.0),
+                    pressure: u8::from(pressure.0),
+                }
+            }
+            ChannelVoiceMessage::ChannelPressure { pressure } => {
+                ChannelMessage::ChannelPressure { pressure: u8::from(pressure.0) }
+            }
+            _ => return None,
+        }
+    };
+
+    Some((u8::from(ch_msg.channel.0), message))
+}
+
+fn parse_midi_message(midi_msg: &[u8]) -> Option<muddy2::message::Message> {
+    log::trace!("midi msg bytes: {:?}", midi_msg);
+
+    use muddy2::parser::{Parser, MessageParseOutcome, MessageParseOutcomeStatus};
+    use muddy2::message;
+
+    let mut parser = Parser::new();
+    let parse = parser.parse(&midi_msg);
+
+    match parse {
+        Ok(parse) => {
+            if parse.bytes_consumed as usize != midi_msg.len() {
+                log::error!("did not consume entire midi message. len = {}, consumed = {}", midi_msg.len(), parse.bytes_consumed);
+            }
+            match parse.status {
+                MessageParseOutcomeStatus::Message(
+                    message::Message::System(
+                        message::SystemMessage::SystemRealTime(
+                            message::SystemRealTimeMessage::TimingClock
+                        )
+                    )
+                ) => {
+                    // these happen a lot
+                    log::trace!("midi msg: {:#?}", parse.status);
+                }
+                _ => {
+                    log::debug!("midi msg: {:#?}", parse.status);
+                }
+            }
+            match parse.status {
+                MessageParseOutcomeStatus::Message(msg) => Some(msg),
+                _ => None,
+            }
+        }
+        Err(e) => {
+            log::error!("midi parse error: {}", e);
+            let mut maybe_source = e.source();
+            while let Some(source) = maybe_source {
+                log::error!("source: {}", source);
+                maybe_source = source.source();
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(received: Instant, note: u8) -> MidiEvent {
+        MidiEvent {
+            received,
+            channel: 0,
+            message: ChannelMessage::NoteOn { note, velocity: 100 },
+        }
+    }
+
+    fn note(midi_event: MidiEvent) -> u8 {
+        match midi_event.message {
+            ChannelMessage::NoteOn { note, .. } => note,
+            message => panic!("{:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_queues_merge_ports_by_arrival() {
+        let (mut midi_txs, mut midi_queues) = queues(16);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        for (port, times) in [(0, [1, 4, 5]), (1, [2, 3, 6]), (2, [0, 7, 8])] {
+            for ms in times {
+                midi_txs[port].push(note_on(at(ms), ms as u8)).expect("room");
+            }
+        }
+
+        let notes: Vec<_> = std::iter::from_fn(|| midi_queues.pop_if(|_| true)).map(note).collect();
+        assert_eq!(notes, (0..9).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_pop_if_leaves_rejected_event() {
+        let (mut midi_txs, mut midi_queues) = queues(16);
+        let start = Instant::now();
+        midi_txs[0].push(note_on(start + Duration::from_millis(1), 1)).expect("room");
+        midi_txs[1].push(note_on(start, 0)).expect("room");
+
+        let cutoff = start + Duration::from_millis(1);
+        assert_eq!(midi_queues.pop_if(|midi_event| midi_event.received < cutoff).map(note), Some(0));
+        assert!(midi_queues.pop_if(|midi_event| midi_event.received < cutoff).is_none());
+        assert_eq!(midi_queues.pop_if(|_| true).map(note), Some(1));
+        assert!(midi_queues.pop_if(|_| true).is_none());
+    }
+}
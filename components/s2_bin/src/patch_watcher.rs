@@ -0,0 +1,136 @@
+//! Reloads a patch file when it or its wavetable changes on disk.
+//!
+//! The files are polled on their own thread and parsed there,
+//! so the synth thread only ever receives finished configs.
+//! Wavetables the synth is done with come back here to be freed.
+//! A patch that fails to load is logged and the previous patch stays live.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use s2_lib::try3::patch;
+use s2_lib::try3::static_config as sc;
+use s2_lib::try3::wavetable::Wavetable;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct PatchUpdate {
+    pub config: sc::Layer,
+    pub wavetable: Arc<Wavetable>,
+}
+
+pub struct PatchWatcher {
+    pub thread: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PatchWatcher {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Watch `path`, sending each successfully parsed version to `patch_tx`
+/// and freeing the wavetables that arrive on `retired_rx`.
+pub fn start(
+    path: PathBuf,
+    patch_tx: mpsc::SyncSender<PatchUpdate>,
+    retired_rx: mpsc::Receiver<Arc<Wavetable>>,
+) -> Result<PatchWatcher> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+
+    let thread = thread::Builder::new()
+        .name("patch-watcher".to_string())
+        .spawn(move || {
+            run_watcher(path, patch_tx, retired_rx, &thread_stop);
+        })?;
+
+    Ok(PatchWatcher {
+        thread,
+        stop,
+    })
+}
+
+fn run_watcher(
+    path: PathBuf,
+    patch_tx: mpsc::SyncSender<PatchUpdate>,
+    retired_rx: mpsc::Receiver<Arc<Wavetable>>,
+    stop: &AtomicBool,
+) {
+    log::info!("watching {} for changes", path.display());
+
+    // Changes to the patch's wavetable reload it too.
+    let mut wavetable_path = patch::load(&path).ok()
+        .and_then(|patch| patch::wavetable_path(&path, &patch));
+    let mut last_modified = modified_times(&path, wavetable_path.as_deref());
+
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(POLL_INTERVAL);
+
+        retired_rx.try_iter().for_each(drop);
+
+        let modified = modified_times(&path, wavetable_path.as_deref());
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        let loaded = patch::load(&path).and_then(|patch| {
+            let new_wavetable_path = patch::wavetable_path(&path, &patch);
+            if new_wavetable_path != wavetable_path {
+                wavetable_path = new_wavetable_path;
+                last_modified = modified_times(&path, wavetable_path.as_deref());
+            }
+            let wavetable = patch::load_wavetable(&path, &patch)?;
+            Ok((patch, wavetable))
+        });
+        let (patch, wavetable) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                log::error!("{:#}", e);
+                log::error!("keeping previous patch");
+                continue;
+            }
+        };
+
+        log::info!("reloaded patch {} from {}", patch.name, path.display());
+
+        let update = PatchUpdate {
+            config: patch.layer,
+            wavetable,
+        };
+        match patch_tx.try_send(update) {
+            Ok(_) => { },
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                /* shutting down */
+                break;
+            }
+            Err(mpsc::TrySendError::Full(_)) => {
+                log::warn!("patch channel full, retrying");
+                last_modified = (None, None);
+            }
+        }
+    }
+
+    log::info!("patch watcher exiting");
+}
+
+/// When the patch and its wavetable, if it has one, were last modified.
+fn modified_times(
+    path: &Path,
+    wavetable_path: Option<&Path>,
+) -> (Option<SystemTime>, Option<SystemTime>) {
+    (modified_time(path), wavetable_path.and_then(modified_time))
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
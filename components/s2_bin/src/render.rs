@@ -0,0 +1,249 @@
+//! Offline rendering of Standard MIDI Files to WAV.
+//!
+//! This drives the synth directly, without an audio device,
+//! splitting rendering at the exact frame of every MIDI event.
+
+use anyhow::{Result, anyhow, bail, Context};
+use std::path::PathBuf;
+
+use s2_lib::try3::patch;
+use s2_lib::try3::synth;
+use s2_lib::try3::units::{Ms, SampleRateKhz};
+
+use crate::midi::ChannelMessage;
+
+#[derive(clap::Args)]
+pub struct RenderArgs {
+    /// The Standard MIDI File to play.
+    input: PathBuf,
+    /// The WAV file to write.
+    output: PathBuf,
+    /// A `.synth2` or `.json` patch to play instead of the default patch.
+    #[arg(long)]
+    patch: Option<PathBuf>,
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+    /// 1 for mono, 2 for stereo.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=2))]
+    channels: u16,
+    /// How long to keep rendering after the last event, in milliseconds.
+    #[arg(long, default_value_t = 2000.0)]
+    tail: f32,
+    #[command(flatten)]
+    voice: crate::VoiceArgs,
+}
+
+const BLOCK_FRAMES: usize = 1024;
+
+/// Microseconds per beat until the file says otherwise (120 bpm).
+const DEFAULT_TEMPO: u32 = 500_000;
+
+pub fn render(args: RenderArgs) -> Result<()> {
+    let mut synth = match &args.patch {
+        Some(path) => {
+            let patch = patch::load(path)?;
+            synth::Synth::from_patch(&patch, patch::load_wavetable(path, &patch)?)
+        }
+        None => synth::Synth::new(),
+    };
+    args.voice.apply(&mut synth);
+    let mut midi_input = crate::midi::MidiInput::new();
+
+    let smf_bytes = std::fs::read(&args.input)
+        .with_context(|| format!("reading {}", args.input.display()))?;
+    let smf = midly::Smf::parse(&smf_bytes)
+        .map_err(|e| anyhow!("parsing {}: {}", args.input.display(), e))?;
+
+    let sample_rate = SampleRateKhz(args.sample_rate);
+    let events = timed_events(&smf, args.sample_rate)?;
+
+    let spec = hound::WavSpec {
+        channels: args.channels,
+        sample_rate: args.sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&args.output, spec)
+        .with_context(|| format!("creating {}", args.output.display()))?;
+
+    let mut buffer_left = [0_f32; BLOCK_FRAMES];
+    let mut buffer_right = [0_f32; BLOCK_FRAMES];
+    let mut current_frame: u64 = 0;
+
+    let mut render_until = |synth: &mut synth::Synth, writer: &mut hound::WavWriter<_>, end_frame: u64| -> Result<()> {
+        while current_frame < end_frame {
+            let frames = (end_frame - current_frame).min(BLOCK_FRAMES as u64) as usize;
+            let buffer_left = &mut buffer_left[..frames];
+            let buffer_right = &mut buffer_right[..frames];
+            if args.channels == 1 {
+                synth.sample(buffer_left, sample_rate);
+                for sample in buffer_left.iter() {
+                    writer.write_sample(*sample)?;
+                }
+            } else {
+                synth.sample_stereo(buffer_left, buffer_right, sample_rate);
+                for (left, right) in buffer_left.iter().zip(buffer_right.iter()) {
+                    writer.write_sample(*left)?;
+                    writer.write_sample(*right)?;
+                }
+            }
+            current_frame += frames as u64;
+        }
+        Ok(())
+    };
+
+    for (frame, channel, message) in &events {
+        render_until(&mut synth, &mut writer, *frame)?;
+        if let Some(message) = channel_message(*message) {
+            midi_input.apply(*channel, message, &mut synth);
+        }
+    }
+
+    let last_frame = events.last().map(|(frame, _, _)| *frame).unwrap_or(0);
+    let tail_frames = Ms(args.tail).as_samples(sample_rate).0 as u64;
+    render_until(&mut synth, &mut writer, last_frame + tail_frames)?;
+
+    writer.finalize()?;
+
+    log::info!(
+        "rendered {} events to {} ({:.2}s)",
+        events.len(),
+        args.output.display(),
+        (last_frame + tail_frames) as f64 / args.sample_rate as f64,
+    );
+
+    Ok(())
+}
+
+/// All channel messages in the file, merged across tracks,
+/// and paired with the frame and channel at which they occur.
+fn timed_events(
+    smf: &midly::Smf,
+    sample_rate: u32,
+) -> Result<Vec<(u64, u8, midly::MidiMessage)>> {
+    use midly::{Timing, TrackEventKind, MetaMessage, Format};
+
+    if smf.header.format == Format::Sequential {
+        bail!("sequential (format 2) midi files are not supported");
+    }
+
+    // (tick, track, event) sorts events from different tracks with
+    // the same tick in a stable order.
+    let mut events = vec![];
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut tick: u64 = 0;
+        for (event_index, event) in track.iter().enumerate() {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, track_index, event_index, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, track_index, event_index, _)| (*tick, *track_index, *event_index));
+
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick: u64 = 0;
+    let mut seconds: f64 = 0.0;
+    let mut timed = vec![];
+
+    for (tick, _, _, kind) in events {
+        let ticks = (tick - last_tick) as f64;
+        seconds += match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                ticks * tempo as f64 / 1_000_000.0 / ticks_per_beat.as_int() as f64
+            }
+            Timing::Timecode(fps, ticks_per_frame) => {
+                ticks / (fps.as_f32() as f64 * ticks_per_frame as f64)
+            }
+        };
+        last_tick = tick;
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(new_tempo)) => {
+                tempo = new_tempo.as_int();
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let frame = (seconds * sample_rate as f64).round() as u64;
+                timed.push((frame, channel.as_int(), message));
+            }
+            _ => { }
+        }
+    }
+
+    Ok(timed)
+}
+
+fn channel_message(message: midly::MidiMessage) -> Option<ChannelMessage> {
+    use midly::MidiMessage;
+
+    match message {
+        MidiMessage::NoteOn { key, vel } => {
+            Some(ChannelMessage::NoteOn { note: key.as_int(), velocity: vel.as_int() })
+        }
+        MidiMessage::NoteOff { key, .. } => {
+            Some(ChannelMessage::NoteOff { note: key.as_int() })
+        }
+        MidiMessage::Aftertouch { key, vel } => {
+            Some(ChannelMessage::PolyPressure { note: key.as_int(), pressure: vel.as_int() })
+        }
+        MidiMessage::PitchBend { bend } => {
+            Some(ChannelMessage::PitchBend { value: bend.0.as_int() })
+        }
+        MidiMessage::Controller { controller, value } => {
+            Some(ChannelMessage::ControlChange { control: controller.as_int(), value: value.as_int() })
+        }
+        MidiMessage::ChannelAftertouch { vel } => {
+            Some(ChannelMessage::ChannelPressure { pressure: vel.as_int() })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent { delta: delta.into(), kind }
+    }
+
+    fn note_on(channel: u8, key: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi { channel: channel.into(), message: MidiMessage::NoteOn { key: key.into(), vel: 100.into() } }
+    }
+
+    fn tempo(micros_per_beat: u32) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat.into()))
+    }
+
+    #[test]
+    fn test_timed_events() {
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+        // 120 bpm for the first beat, then 240 bpm.
+        smf.tracks.push(vec![
+            event(480, tempo(250_000)),
+            event(480, note_on(0, 62)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        smf.tracks.push(vec![
+            event(240, note_on(1, 60)),
+            event(240, note_on(1, 61)),
+            event(240, note_on(1, 63)),
+        ]);
+
+        let events = timed_events(&smf, 48000).expect("timed events");
+        assert_eq!(events, vec![
+            // Half a beat at 120 bpm.
+            (12000, 1, MidiMessage::NoteOn { key: 60.into(), vel: 100.into() }),
+            // The tempo change only applies to the ticks after it.
+            (24000, 1, MidiMessage::NoteOn { key: 61.into(), vel: 100.into() }),
+            // Then half a beat at 240 bpm.
+            (30000, 1, MidiMessage::NoteOn { key: 63.into(), vel: 100.into() }),
+            (36000, 0, MidiMessage::NoteOn { key: 62.into(), vel: 100.into() }),
+        ]);
+    }
+
+    #[test]
+    fn test_timed_events_rejects_sequential_files() {
+        let smf = Smf::new(Header::new(Format::Sequential, Timing::Metrical(480.into())));
+        timed_events(&smf, 48000).expect_err("format 2");
+    }
+}
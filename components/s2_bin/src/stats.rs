@@ -0,0 +1,169 @@
+//! Counters for the realtime path, for tuning buffer sizes.
+//!
+//! The audio callback and the synth thread record into atomics,
+//! and a reporter thread logs and resets them once per interval.
+
+use anyhow::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct Stats {
+    /// Callbacks that found no rendered buffer and played silence.
+    pub underruns: AtomicU64,
+    /// Frames asked for by a callback longer than the audio rendered ahead,
+    /// or 0. Set at most once, and logged as a warning.
+    pub oversized_callback_frames: AtomicU64,
+    /// Time in the audio callback against the audio it produced.
+    pub audio_callback: Load,
+    /// Time rendering each buffer on the synth thread against its length.
+    pub synth_render: Load,
+    /// Estimated time from a MIDI message arriving to it being heard.
+    pub midi_latency: Latency,
+    /// The device's own output latency, as last reported.
+    output_latency_nanos: AtomicU64,
+}
+
+impl Stats {
+    pub fn set_output_latency(&self, latency: Duration) {
+        self.output_latency_nanos.store(nanos(latency), Ordering::Relaxed);
+    }
+
+    pub fn output_latency(&self) -> Duration {
+        Duration::from_nanos(self.output_latency_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Time spent doing some work against the time available for it.
+#[derive(Default)]
+pub struct Load {
+    busy_nanos: AtomicU64,
+    budget_nanos: AtomicU64,
+    /// The highest single load, in thousandths.
+    max_permille: AtomicU64,
+}
+
+impl Load {
+    pub fn record(&self, busy: Duration, budget: Duration) {
+        let busy = nanos(busy);
+        let budget = nanos(budget).max(1);
+        self.busy_nanos.fetch_add(busy, Ordering::Relaxed);
+        self.budget_nanos.fetch_add(budget, Ordering::Relaxed);
+        self.max_permille.fetch_max(busy * 1000 / budget, Ordering::Relaxed);
+    }
+
+    /// Mean and max load since the last call, as fractions of the budget.
+    fn take(&self) -> (f64, f64) {
+        let busy = self.busy_nanos.swap(0, Ordering::Relaxed);
+        let budget = self.budget_nanos.swap(0, Ordering::Relaxed);
+        let max = self.max_permille.swap(0, Ordering::Relaxed);
+        let mean = if budget > 0 { busy as f64 / budget as f64 } else { 0.0 };
+        (mean, max as f64 / 1000.0)
+    }
+}
+
+#[derive(Default)]
+pub struct Latency {
+    count: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Latency {
+    pub fn record(&self, latency: Duration) {
+        let latency = nanos(latency);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(latency, Ordering::Relaxed);
+        self.max_nanos.fetch_max(latency, Ordering::Relaxed);
+    }
+
+    /// Mean and max since the last call, if anything was recorded.
+    fn take(&self) -> Option<(Duration, Duration)> {
+        let count = self.count.swap(0, Ordering::Relaxed);
+        let total = self.total_nanos.swap(0, Ordering::Relaxed);
+        let max = self.max_nanos.swap(0, Ordering::Relaxed);
+        (count > 0).then(|| (Duration::from_nanos(total / count), Duration::from_nanos(max)))
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+pub struct Reporter {
+    pub thread: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Reporter {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Log a status line every interval if `verbose`,
+/// and otherwise only warn about underruns and oversized callbacks.
+pub fn start_reporter(stats: Arc<Stats>, verbose: bool) -> Result<Reporter> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+
+    let thread = thread::Builder::new()
+        .name("stats".to_string())
+        .spawn(move || {
+            run_reporter(&stats, verbose, &thread_stop);
+        })?;
+
+    Ok(Reporter {
+        thread,
+        stop,
+    })
+}
+
+fn run_reporter(stats: &Stats, verbose: bool, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(REPORT_INTERVAL);
+
+        let underruns = stats.underruns.swap(0, Ordering::Relaxed);
+        let (callback_mean, callback_max) = stats.audio_callback.take();
+        let (render_mean, render_max) = stats.synth_render.take();
+        let midi_latency = stats.midi_latency.take();
+
+        let oversized_callback_frames = stats.oversized_callback_frames.swap(0, Ordering::Relaxed);
+        if oversized_callback_frames > 0 {
+            log::warn!(
+                "audio device requesting {} frames, more than are rendered ahead; try a higher --latency",
+                oversized_callback_frames,
+            );
+        }
+
+        if !verbose {
+            if underruns > 0 {
+                log::warn!("{} audio underruns", underruns);
+            }
+            continue;
+        }
+
+        let midi_latency = match midi_latency {
+            Some((mean, max)) => format!("{:.1}ms mean {:.1}ms max", ms(mean), ms(max)),
+            None => "-".to_string(),
+        };
+        log::info!(
+            "underruns {} | callback {:.0}% mean {:.0}% max | render {:.0}% mean {:.0}% max | output latency {:.1}ms | midi latency {}",
+            underruns,
+            callback_mean * 100.0,
+            callback_max * 100.0,
+            render_mean * 100.0,
+            render_max * 100.0,
+            ms(stats.output_latency()),
+            midi_latency,
+        );
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,165 @@
+//! Performance controllers as modulation sources.
+//!
+//! Controller messages arrive far apart compared to the sample rate,
+//! so each controller glides towards its latest value a little every
+//! frame instead of jumping, which would be audible as zipper noise.
+
+use super::units::*;
+
+/// How long a controller takes to move most of the way to a new value.
+const SMOOTHING_TIME: Ms = Ms(5.0);
+
+/// Differences smaller than this snap straight to the target.
+const SNAP_THRESHOLD: f32 = 1.0e-5;
+
+/// A controller value that moves smoothly towards its latest target.
+#[derive(Copy, Clone)]
+#[derive(Default)]
+pub struct Smoothed {
+    target: f32,
+    value: f32,
+}
+
+impl Smoothed {
+    pub fn new(value: f32) -> Smoothed {
+        Smoothed {
+            target: value,
+            value,
+        }
+    }
+
+    pub fn set(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// The latest value set, which `next_frames` moves towards.
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Jump straight to the target.
+    pub fn snap(&mut self) {
+        self.value = self.target;
+    }
+
+    /// The value of each of the next `frames` frames, up to 16.
+    ///
+    /// The remaining frames repeat the last value.
+    pub fn next_frames(&mut self, frames: usize, sample_rate: SampleRateKhz) -> [f32; 16] {
+        debug_assert!(frames <= 16);
+
+        if self.value == self.target {
+            return [self.value; 16];
+        }
+
+        let smoothing_frames = SMOOTHING_TIME.as_samples(sample_rate).0.max(1.0);
+        let coefficient = 1.0 - (-1.0 / smoothing_frames).exp();
+
+        let mut values = [0.0; 16];
+        for value in values.iter_mut().take(frames) {
+            self.value += (self.target - self.value) * coefficient;
+            if (self.target - self.value).abs() < SNAP_THRESHOLD {
+                self.value = self.target;
+            }
+            *value = self.value;
+        }
+        for value in values.iter_mut().skip(frames) {
+            *value = self.value;
+        }
+
+        values
+    }
+}
+
+/// The controller values for a single frame.
+#[derive(Copy, Clone)]
+#[derive(Default)]
+pub struct ControllerFrame {
+    /// Full bend down to full bend up, before the bend range.
+    pub pitch_bend: Bipolar<1>,
+    /// Semitones of per-note bend from an MPE member channel.
+    pub note_pitch_bend: f32,
+    pub mod_wheel: Unipolar<1>,
+    pub pressure: Unipolar<1>,
+    /// Usually CC74, the third dimension of MPE controllers.
+    pub timbre: Unipolar<1>,
+    /// Polyphonic key pressure, for this voice only.
+    pub poly_pressure: Unipolar<1>,
+}
+
+impl ControllerFrame {
+    /// Add the expression of an MPE member channel to the zone-wide controllers.
+    pub fn with_member(self, member: ControllerFrame, pitch_bend_range: f32) -> ControllerFrame {
+        ControllerFrame {
+            note_pitch_bend: member.pitch_bend.0 * pitch_bend_range,
+            pressure: Unipolar((self.pressure.0 + member.pressure.0).min(1.0)),
+            timbre: Unipolar((self.timbre.0 + member.timbre.0).min(1.0)),
+            ..self
+        }
+    }
+}
+
+/// Controllers that apply to every voice on a channel.
+#[derive(Copy, Clone)]
+#[derive(Default)]
+pub struct ChannelControllers {
+    pub pitch_bend: Smoothed,
+    pub mod_wheel: Smoothed,
+    pub pressure: Smoothed,
+    pub timbre: Smoothed,
+}
+
+impl ChannelControllers {
+    /// The next `frames` frames of every controller, up to 16.
+    pub fn next_frames(&mut self, frames: usize, sample_rate: SampleRateKhz) -> [ControllerFrame; 16] {
+        let pitch_bend = self.pitch_bend.next_frames(frames, sample_rate);
+        let mod_wheel = self.mod_wheel.next_frames(frames, sample_rate);
+        let pressure = self.pressure.next_frames(frames, sample_rate);
+        let timbre = self.timbre.next_frames(frames, sample_rate);
+
+        std::array::from_fn(|i| ControllerFrame {
+            pitch_bend: Bipolar(pitch_bend[i]),
+            note_pitch_bend: 0.0,
+            mod_wheel: Unipolar(mod_wheel[i]),
+            pressure: Unipolar(pressure[i]),
+            timbre: Unipolar(timbre[i]),
+            poly_pressure: Unipolar(0.0),
+        })
+    }
+
+    /// Jump every controller to its target, as when a new MPE note starts
+    /// with the expression sent just before it.
+    pub fn snap(&mut self) {
+        self.pitch_bend.snap();
+        self.mod_wheel.snap();
+        self.pressure.snap();
+        self.timbre.snap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothed_approaches_target() {
+        let sample_rate = SampleRateKhz(48000);
+        let mut smoothed = Smoothed::new(0.0);
+        smoothed.set(1.0);
+
+        let first = smoothed.next_frames(16, sample_rate);
+        assert!(first[0] > 0.0 && first[0] < 0.1);
+        assert!(first.windows(2).all(|w| w[0] < w[1]));
+
+        // Well past the smoothing time.
+        for _ in 0..1000 {
+            smoothed.next_frames(16, sample_rate);
+        }
+        assert_eq!(smoothed.next_frames(16, sample_rate), [1.0; 16]);
+
+        smoothed.set(0.5);
+        let partial = smoothed.next_frames(4, sample_rate);
+        assert!(partial[3] < 1.0);
+        assert!(partial[4..].iter().all(|v| *v == partial[3]));
+    }
+}
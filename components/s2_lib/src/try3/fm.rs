@@ -0,0 +1,360 @@
+//! Four-operator FM, in the style of the Yamaha DX9 and TX81Z.
+//!
+//! Each operator is a sine oscillator with its own frequency ratio, level
+//! and envelope. An algorithm routes some operators' outputs into the phase
+//! of others, which is phase modulation, as on the DX synths. The rest are
+//! carriers, which are mixed to the output. Any operator can also modulate
+//! itself with its own output from the previous samples.
+//!
+//! Operators are numbered from 1 in patches, and only modulate lower-numbered
+//! operators, so they can be sampled from the highest down.
+//!
+//! - <https://github.com/google/music-synthesizer-for-android>
+
+use std::simd::prelude::*;
+use std::simd::StdFloat;
+use super::oscillators::phase_accumulating::{accum_phase, accum_phase_x16, OscillatorState};
+use super::oscillators::phased::{TableOscillator, TableOscillatorX16};
+use super::static_config::{FM_ALGORITHMS, FM_OPERATORS};
+use super::tables::SIN_TABLE;
+use super::units::*;
+
+/// Cycles of phase modulation from a modulator at full level.
+///
+/// About 12.6 radians, close to the deepest modulation of the DX7.
+const MODULATION_DEPTH: f32 = 2.0;
+
+/// Cycles of phase modulation from an operator's own output at full feedback.
+const FEEDBACK_DEPTH: f32 = 0.5;
+
+/// Which operators modulate each other, and which are heard.
+pub struct Algorithm {
+    /// For each operator, whether each other operator modulates it.
+    modulators: [[bool; FM_OPERATORS]; FM_OPERATORS],
+    carriers: [bool; FM_OPERATORS],
+}
+
+/// The eight algorithms of the DX9 and TX81Z, by their numbers there.
+///
+/// Each lists modulator and modulated operator pairs, then the carriers.
+static ALGORITHMS: [Algorithm; FM_ALGORITHMS as usize] = [
+    // 4 -> 3 -> 2 -> 1
+    algorithm(&[(4, 3), (3, 2), (2, 1)], &[1]),
+    // 3 and 4 -> 2 -> 1
+    algorithm(&[(4, 2), (3, 2), (2, 1)], &[1]),
+    // 3 -> 2 -> 1, and 4 -> 1
+    algorithm(&[(3, 2), (2, 1), (4, 1)], &[1]),
+    // 4 -> 3 -> 1, and 2 -> 1
+    algorithm(&[(4, 3), (3, 1), (2, 1)], &[1]),
+    // 4 -> 3 and 2 -> 1
+    algorithm(&[(4, 3), (2, 1)], &[1, 3]),
+    // 4 -> each of 3, 2 and 1
+    algorithm(&[(4, 3), (4, 2), (4, 1)], &[1, 2, 3]),
+    // 4 -> 3, with 2 and 1 alone
+    algorithm(&[(4, 3)], &[1, 2, 3]),
+    // Every operator alone
+    algorithm(&[], &[1, 2, 3, 4]),
+];
+
+const fn algorithm(modulations: &[(usize, usize)], carriers: &[usize]) -> Algorithm {
+    let mut algorithm = Algorithm {
+        modulators: [[false; FM_OPERATORS]; FM_OPERATORS],
+        carriers: [false; FM_OPERATORS],
+    };
+
+    let mut index = 0;
+    while index < modulations.len() {
+        let (modulator, modulated) = modulations[index];
+        assert!(modulator > modulated);
+        algorithm.modulators[modulated - 1][modulator - 1] = true;
+        index += 1;
+    }
+
+    let mut index = 0;
+    while index < carriers.len() {
+        algorithm.carriers[carriers[index] - 1] = true;
+        index += 1;
+    }
+
+    algorithm
+}
+
+/// The algorithm with `number`, from 1 to `FM_ALGORITHMS`.
+pub fn algorithm_number(number: u8) -> &'static Algorithm {
+    &ALGORITHMS[usize::from(number.clamp(1, FM_ALGORITHMS) - 1)]
+}
+
+impl Algorithm {
+    /// Scales the carriers so that their sum stays in range.
+    fn carrier_gain(&self) -> f32 {
+        let carriers = self.carriers.iter().filter(|&&carrier| carrier).count();
+        1.0 / carriers as f32
+    }
+}
+
+#[derive(Default)]
+#[derive(Copy, Clone)]
+pub struct FmState {
+    pub operators: [OscillatorState; FM_OPERATORS],
+    /// Each operator's last two outputs, newest first, for feedback.
+    pub outputs: [[f32; 2]; FM_OPERATORS],
+}
+
+#[derive(Copy, Clone)]
+pub struct Operator {
+    pub period: SampleOffset,
+    /// After the operator's envelope.
+    pub level: Unipolar<1>,
+    pub feedback: Unipolar<1>,
+}
+
+#[derive(Copy, Clone)]
+pub struct OperatorX<const N: usize> {
+    pub periods: [SampleOffset; N],
+    pub levels: [Unipolar<1>; N],
+    pub feedback: Unipolar<1>,
+}
+
+pub struct FmOscillator<'this> {
+    pub state: &'this mut FmState,
+    pub algorithm: &'this Algorithm,
+    pub operators: [Operator; FM_OPERATORS],
+}
+
+pub struct FmOscillatorX16<'this> {
+    pub state: &'this mut FmState,
+    pub algorithm: &'this Algorithm,
+    pub operators: [OperatorX<16>; FM_OPERATORS],
+}
+
+/// The fractional part, for phases pushed out of range by modulation.
+fn wrap_phase(phase: f32) -> f32 {
+    phase - phase.floor()
+}
+
+/// One frame of an operator, at its accumulated `phase` plus `modulation` cycles.
+fn sample_operator(
+    outputs: &mut [f32; 2],
+    period: SampleOffset,
+    phase: Unipolar<1>,
+    level: Unipolar<1>,
+    feedback: Unipolar<1>,
+    modulation: f32,
+) -> f32 {
+    let [previous, before] = *outputs;
+    // Averaging two samples keeps strong feedback from
+    // oscillating at nyquist, as on the DX7.
+    let feedback = (previous + before) / 2.0 * feedback.0 * FEEDBACK_DEPTH;
+    let phase = wrap_phase(phase.0 + modulation + feedback);
+
+    let sine = TableOscillator {
+        table: &SIN_TABLE,
+        period,
+        phase: Unipolar(phase),
+    }.sample(SampleOffset(0.0));
+    let output = sine.0 * level.0;
+
+    *outputs = [output, previous];
+    output
+}
+
+impl FmOscillator<'_> {
+    pub fn sample(&mut self) -> Bipolar<1> {
+        let mut outputs = [0.0; FM_OPERATORS];
+        let mut sample = 0.0;
+
+        for index in (0..FM_OPERATORS).rev() {
+            let modulators = &self.algorithm.modulators[index];
+            let modulation: f32 = (index + 1..FM_OPERATORS)
+                .filter(|&modulator| modulators[modulator])
+                .map(|modulator| outputs[modulator])
+                .sum();
+
+            let operator = &self.operators[index];
+            let state = &mut self.state.operators[index];
+            let phase = state.phase_accum.unwrap_or(Unipolar(0.0));
+            state.phase_accum = Some(accum_phase(phase, operator.period));
+
+            outputs[index] = sample_operator(
+                &mut self.state.outputs[index],
+                operator.period,
+                phase,
+                operator.level,
+                operator.feedback,
+                modulation * MODULATION_DEPTH,
+            );
+            if self.algorithm.carriers[index] {
+                sample += outputs[index];
+            }
+        }
+
+        Bipolar(sample * self.algorithm.carrier_gain())
+    }
+}
+
+impl FmOscillatorX16<'_> {
+    pub fn sample(&mut self) -> [Bipolar<1>; 16] {
+        let mut outputs = [f32x16::splat(0.0); FM_OPERATORS];
+        let mut samples = f32x16::splat(0.0);
+
+        for index in (0..FM_OPERATORS).rev() {
+            let modulators = &self.algorithm.modulators[index];
+            let modulation: f32x16 = (index + 1..FM_OPERATORS)
+                .filter(|&modulator| modulators[modulator])
+                .map(|modulator| outputs[modulator])
+                .sum();
+            let modulation = modulation * f32x16::splat(MODULATION_DEPTH);
+
+            let operator = &self.operators[index];
+            let state = &mut self.state.operators[index];
+            let init_phase = state.phase_accum.unwrap_or(Unipolar(0.0));
+            let (phase, phase_accum) = accum_phase_x16(init_phase, operator.periods);
+            state.phase_accum = Some(phase_accum);
+
+            let output = if operator.feedback.0 == 0.0 {
+                let phase = f32x16::from_array(phase.map(|p| p.0)) + modulation;
+                let phase = phase - phase.floor();
+                let sine = TableOscillatorX16 {
+                    table: &SIN_TABLE,
+                    period: operator.periods,
+                    phase: phase.to_array().map(Unipolar),
+                }.sample([SampleOffset(0.0); 16]);
+                let sine = f32x16::from_array(sine.map(|s| s.0));
+                let output = sine * f32x16::from_array(operator.levels.map(|l| l.0));
+
+                // Kept in case feedback is turned up in a later frame.
+                self.state.outputs[index] = [output[15], output[14]];
+                output
+            } else {
+                // Each frame feeds back into the next, so they go one at a time.
+                let outputs = &mut self.state.outputs[index];
+                let modulation = modulation.to_array();
+                f32x16::from_array(std::array::from_fn(|i| {
+                    sample_operator(
+                        outputs,
+                        operator.periods[i],
+                        phase[i],
+                        operator.levels[i],
+                        operator.feedback,
+                        modulation[i],
+                    )
+                }))
+            };
+
+            outputs[index] = output;
+            if self.algorithm.carriers[index] {
+                samples += output;
+            }
+        }
+
+        let samples = samples * f32x16::splat(self.algorithm.carrier_gain());
+        samples.to_array().map(Bipolar)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::oscillators::phase_accumulating::SineOscillator;
+    use super::super::test_util;
+
+    fn operators(levels: [f32; FM_OPERATORS], feedback: f32) -> [Operator; FM_OPERATORS] {
+        std::array::from_fn(|index| Operator {
+            period: SampleOffset(109.1 / (index + 1) as f32),
+            level: Unipolar(levels[index]),
+            feedback: Unipolar(if index == FM_OPERATORS - 1 { feedback } else { 0.0 }),
+        })
+    }
+
+    fn render(algorithm: u8, operators: [Operator; FM_OPERATORS], frames: usize) -> Vec<f32> {
+        let mut state = FmState::default();
+        (0..frames).map(|_| {
+            FmOscillator {
+                state: &mut state,
+                algorithm: algorithm_number(algorithm),
+                operators,
+            }.sample().0
+        }).collect()
+    }
+
+    #[test]
+    fn test_algorithms() {
+        for algorithm in &ALGORITHMS {
+            assert!(algorithm.carriers.contains(&true));
+            for index in 0..FM_OPERATORS {
+                // Only higher operators modulate, and every
+                // operator is either heard or modulates something.
+                assert!(algorithm.modulators[index][..=index].iter().all(|&m| !m));
+                let modulates = (0..index).any(|other| algorithm.modulators[other][index]);
+                assert!(algorithm.carriers[index] || modulates, "operator {}", index + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unmodulated_carrier_is_sine() {
+        let period = SampleOffset(109.1);
+        let mut state = OscillatorState::default();
+        let sine: Vec<f32> = (0..500).map(|_| {
+            SineOscillator { state: &mut state, period, phase: Unipolar(0.0) }.sample().0
+        }).collect();
+
+        // Modulators at zero level leave the carrier alone.
+        let fm = render(1, operators([1.0, 0.0, 0.0, 0.0], 0.0), 500);
+        assert_eq!(fm, sine);
+    }
+
+    #[test]
+    fn test_modulation() {
+        let carrier = render(1, operators([1.0, 0.0, 0.0, 0.0], 0.0), 500);
+        let modulated = render(1, operators([1.0, 0.5, 0.0, 0.0], 0.0), 500);
+        assert_ne!(carrier, modulated);
+        assert!(modulated.iter().all(|s| s.abs() <= 1.0));
+
+        // Algorithm 8 mixes every operator, scaled to stay in range.
+        let mixed = render(8, operators([1.0; FM_OPERATORS], 1.0), 500);
+        assert!(mixed.iter().all(|s| s.abs() <= 1.0));
+        assert!(mixed.iter().any(|s| s.abs() > 0.5));
+    }
+
+    #[test]
+    fn test_x16_matches_scalar() {
+        // Every operator's period and level change every frame.
+        let operator = |index: usize, frame: usize, feedback: f32| Operator {
+            period: test_util::varying_period(109.1 / (index + 1) as f32, frame),
+            level: test_util::varying_level(frame + index * 10),
+            feedback: Unipolar(if index == FM_OPERATORS - 1 { feedback } else { 0.0 }),
+        };
+
+        for feedback in [0.0, 0.7] {
+            let mut scalar_state = FmState::default();
+            let mut x16_state = FmState::default();
+            test_util::assert_x16_matches_scalar(
+                8,
+                1e-3,
+                |frame| {
+                    FmOscillator {
+                        state: &mut scalar_state,
+                        algorithm: algorithm_number(1),
+                        operators: std::array::from_fn(|index| operator(index, frame, feedback)),
+                    }.sample().0
+                },
+                |start| {
+                    let operators = std::array::from_fn(|index| {
+                        let lanes: [_; 16] = std::array::from_fn(|lane| operator(index, start + lane, feedback));
+                        OperatorX {
+                            periods: lanes.map(|operator| operator.period),
+                            levels: lanes.map(|operator| operator.level),
+                            feedback: lanes[0].feedback,
+                        }
+                    });
+                    FmOscillatorX16 {
+                        state: &mut x16_state,
+                        algorithm: algorithm_number(1),
+                        operators,
+                    }.sample().map(|s| s.0)
+                },
+            );
+        }
+    }
+}
@@ -39,6 +39,43 @@ pub fn line_y_value_with_y_offset_x16(
     }
 }
 
+/// In-place radix-2 FFT of a complex signal, without scaling.
+///
+/// The length must be a power of two.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let len = re.len();
+    assert_eq!(len, im.len());
+    assert!(len.is_power_of_two());
+
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits().checked_shr(usize::BITS - bits).unwrap_or(0);
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut size = 2;
+    while size <= len {
+        let angle = -std::f64::consts::TAU / size as f64;
+        for start in (0..len).step_by(size) {
+            for k in 0..size / 2 {
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let (w_re, w_im) = (w_re as f32, w_im as f32);
+                let (a, b) = (start + k, start + k + size / 2);
+                let b_re = re[b] * w_re - im[b] * w_im;
+                let b_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - b_re;
+                im[b] = im[a] - b_im;
+                re[a] += b_re;
+                im[a] += b_im;
+            }
+        }
+        size *= 2;
+    }
+}
+
 pub const fn indexes_u32<const N: usize>() -> [u32; N] {
     let mut indexes = [0; N];
     let mut index = 0;
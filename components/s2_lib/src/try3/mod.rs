@@ -2,7 +2,11 @@ pub mod synth;
 
 pub mod process;
 
+pub mod controllers;
+pub mod mpe;
+
 pub mod static_config;
+pub mod patch;
 mod render_plan;
 
 mod envelopes;
@@ -10,6 +14,8 @@ mod filters;
 mod dsp_filters;
 mod math;
 mod oscillators;
+mod fm;
+pub mod wavetable;
 mod hashnoise;
 
 pub mod state;
@@ -17,3 +23,8 @@ pub mod state;
 mod tables;
 pub mod lookup;
 pub mod units;
+
+pub mod spsc;
+
+#[cfg(test)]
+mod test_util;
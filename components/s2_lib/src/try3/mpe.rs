@@ -0,0 +1,168 @@
+//! MIDI Polyphonic Expression zones.
+//!
+//! An MPE controller plays each note on its own member channel,
+//! so pitch bend, pressure and timbre on that channel apply to that
+//! note alone. Messages on a zone's manager channel apply to every note.
+//!
+//! The lower zone is managed from the first channel and its members
+//! count up from the second. The upper zone is managed from the last
+//! channel and its members count down from the fifteenth.
+//! A zone on its own may use the other zone's manager channel as a member.
+
+/// A MIDI channel, from 0 to 15.
+#[derive(Eq, PartialEq)]
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct Channel(pub u8);
+
+pub const LOWER_MANAGER: Channel = Channel(0);
+pub const UPPER_MANAGER: Channel = Channel(15);
+
+/// Member channels available to a zone on its own: every channel but its manager.
+const MAX_MEMBER_CHANNELS: u8 = 15;
+
+/// Member channels available to both zones together.
+const MAX_SHARED_MEMBER_CHANNELS: u8 = 14;
+
+/// Semitones of per-note pitch bend until configured otherwise.
+pub const DEFAULT_MEMBER_PITCH_BEND_RANGE: f32 = 48.0;
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Default)]
+pub struct MpeZones {
+    pub lower: Option<MpeZone>,
+    pub upper: Option<MpeZone>,
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub struct MpeZone {
+    pub member_channels: u8,
+    /// Semitones at full bend on a member channel.
+    pub pitch_bend_range: f32,
+}
+
+impl MpeZone {
+    pub fn new(member_channels: u8) -> MpeZone {
+        MpeZone {
+            member_channels,
+            pitch_bend_range: DEFAULT_MEMBER_PITCH_BEND_RANGE,
+        }
+    }
+
+    fn is_lower_member(&self, channel: Channel) -> bool {
+        channel.0 > LOWER_MANAGER.0 && channel.0 <= LOWER_MANAGER.0 + self.member_channels
+    }
+
+    fn is_upper_member(&self, channel: Channel) -> bool {
+        channel.0 < UPPER_MANAGER.0 && channel.0 >= UPPER_MANAGER.0 - self.member_channels
+    }
+}
+
+impl MpeZones {
+    pub fn is_enabled(&self) -> bool {
+        self.lower.is_some() || self.upper.is_some()
+    }
+
+    /// Apply an MPE configuration message received on `manager`.
+    ///
+    /// Zero member channels disables the zone. If the zones would overlap,
+    /// the other zone shrinks, or is disabled if the new zone takes its
+    /// manager channel, as the MPE spec requires.
+    /// Messages on channels other than the two manager channels are ignored.
+    pub fn configure(&mut self, manager: Channel, member_channels: u8) {
+        let member_channels = member_channels.min(MAX_MEMBER_CHANNELS);
+        let zone = (member_channels > 0).then(|| MpeZone::new(member_channels));
+        let remaining = MAX_SHARED_MEMBER_CHANNELS.saturating_sub(member_channels);
+
+        let (zone_slot, other_slot) = if manager == LOWER_MANAGER {
+            (&mut self.lower, &mut self.upper)
+        } else if manager == UPPER_MANAGER {
+            (&mut self.upper, &mut self.lower)
+        } else {
+            return;
+        };
+
+        *zone_slot = zone;
+        if let Some(other) = other_slot {
+            if other.member_channels > remaining {
+                other.member_channels = remaining;
+            }
+            if other.member_channels == 0 {
+                *other_slot = None;
+            }
+        }
+    }
+
+    /// The zone in which `channel` is a member channel.
+    pub fn member_zone(&self, channel: Channel) -> Option<&MpeZone> {
+        let lower = self.lower.as_ref().filter(|zone| zone.is_lower_member(channel));
+        let upper = self.upper.as_ref().filter(|zone| zone.is_upper_member(channel));
+        lower.or(upper)
+    }
+
+    pub fn member_zone_mut(&mut self, channel: Channel) -> Option<&mut MpeZone> {
+        let lower = self.lower.as_mut().filter(|zone| zone.is_lower_member(channel));
+        let upper = self.upper.as_mut().filter(|zone| zone.is_upper_member(channel));
+        lower.or(upper)
+    }
+
+    pub fn is_member(&self, channel: Channel) -> bool {
+        self.member_zone(channel).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configure_zones() {
+        let mut zones = MpeZones::default();
+        assert!(!zones.is_member(Channel(1)));
+
+        zones.configure(LOWER_MANAGER, 7);
+        assert!(zones.is_member(Channel(1)));
+        assert!(zones.is_member(Channel(7)));
+        assert!(!zones.is_member(Channel(8)));
+        assert!(!zones.is_member(LOWER_MANAGER));
+
+        zones.configure(UPPER_MANAGER, 7);
+        assert!(zones.is_member(Channel(8)));
+        assert!(zones.is_member(Channel(14)));
+        assert!(!zones.is_member(UPPER_MANAGER));
+
+        zones.member_zone_mut(Channel(14)).expect("zone").pitch_bend_range = 2.0;
+        assert_eq!(zones.upper.expect("upper").pitch_bend_range, 2.0);
+        assert_eq!(zones.lower.expect("lower").pitch_bend_range, DEFAULT_MEMBER_PITCH_BEND_RANGE);
+
+        // The upper zone shrinks to make room.
+        zones.configure(LOWER_MANAGER, 10);
+        assert_eq!(zones.upper.expect("upper").member_channels, 4);
+        assert!(zones.is_member(Channel(10)));
+        assert!(zones.is_member(Channel(11)));
+
+        // A zone on its own can use the other manager channel.
+        zones.configure(LOWER_MANAGER, 15);
+        assert_eq!(zones.lower.expect("lower").member_channels, 15);
+        assert_eq!(zones.upper, None);
+        assert!(zones.is_member(UPPER_MANAGER));
+        zones.member_zone_mut(UPPER_MANAGER).expect("zone").pitch_bend_range = 12.0;
+        assert_eq!(zones.lower.expect("lower").pitch_bend_range, 12.0);
+
+        // Starting the other zone shrinks it back.
+        zones.configure(UPPER_MANAGER, 3);
+        assert_eq!(zones.lower.expect("lower").member_channels, 11);
+        assert!(zones.is_member(Channel(12)));
+        assert!(!zones.is_member(UPPER_MANAGER));
+
+        zones.configure(UPPER_MANAGER, 15);
+        assert_eq!(zones.upper.expect("upper").member_channels, 15);
+        assert_eq!(zones.lower, None);
+        assert!(zones.is_member(LOWER_MANAGER));
+
+        zones.configure(UPPER_MANAGER, 0);
+        assert!(!zones.is_enabled());
+    }
+}
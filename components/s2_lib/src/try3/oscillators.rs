@@ -4,6 +4,16 @@
 //   always call the other oscs with offset 0. can
 //   just remove the offset parameter entirely.
 
+/// How the square, pulse, saw and triangle oscillators handle their discontinuities.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub enum BandLimit {
+    /// Sample the ideal waveform directly, which aliases at high pitches.
+    Naive,
+    /// Smooth each discontinuity with the `poly_blep` corrections.
+    PolyBlep,
+}
+
 pub mod basic {
     use std::simd::prelude::*;
     use super::super::math::*;
@@ -18,6 +28,17 @@ pub mod basic {
         pub period: [SampleOffset; 16],
     }
 
+    /// A square wave that is high for `width` of each cycle.
+    pub struct PulseOscillator {
+        pub period: SampleOffset,
+        pub width: Unipolar<1>,
+    }
+
+    pub struct PulseOscillatorX16 {
+        pub period: [SampleOffset; 16],
+        pub width: [Unipolar<1>; 16],
+    }
+
     pub struct SawOscillator {
         pub period: SampleOffset,
     }
@@ -73,12 +94,39 @@ pub mod basic {
             let sample = offset_lt_half_period.select(one, n_one);
 
             let sample = sample.to_array();
-            let sample = sample.map(|s| Bipolar(s));
+            let sample = sample.map(Bipolar);
 
             sample
         }
     }
 
+    impl PulseOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let period = self.period.0;
+            let offset = offset.0;
+            let offset = offset % period;
+
+            let high_period = period * self.width.0;
+            let sample = if offset < high_period { 1.0 } else { -1.0 };
+
+            Bipolar(sample)
+        }
+    }
+
+    impl PulseOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let width = f32x16::from_array(self.width.map(|w| w.0));
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let offset = offset % period;
+
+            let high_period = period * width;
+            let sample = offset.simd_lt(high_period).select(f32x16::splat(1.0), f32x16::splat(-1.0));
+
+            sample.to_array().map(Bipolar)
+        }
+    }
+
     impl SawOscillator {
         pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
             let period = self.period.0;
@@ -112,7 +160,7 @@ pub mod basic {
             let sample = line_y_value_with_y_offset_x16(y_rise, x_run, x_value, y_offset);
 
             let sample = sample.to_array();
-            let sample = sample.map(|s| Bipolar(s));
+            let sample = sample.map(Bipolar);
 
             sample
         }
@@ -176,7 +224,7 @@ pub mod basic {
             let sample = offset_lt_half_period.select(sample_first_half, sample_second_half);
 
             let sample = sample.to_array();
-            let sample = sample.map(|s| Bipolar(s));
+            let sample = sample.map(Bipolar);
 
             sample
         }
@@ -194,14 +242,265 @@ pub mod basic {
                 self.table,
                 offset.map(|o| o.0),
                 self.period.map(|p| p.0)
-            ).map(|s| Bipolar(s))
+            ).map(Bipolar)
         }
     }
 }
 
-pub mod phased {
+/// Band-limited versions of the `basic` square, pulse, saw and triangle oscillators.
+///
+/// The naive waveforms jump, or turn a corner, between two samples. That
+/// has harmonics far above nyquist, which alias back down as inharmonic
+/// tones. These add a polynomial approximation of the difference between
+/// a band-limited and a naive step (PolyBLEP) to the two samples around
+/// each jump, and of its integral (PolyBLAMP) around each corner.
+///
+/// - Välimäki, Pekonen and Nam, "Perceptually informed synthesis of
+///   bandlimited classical waveforms using integrated polynomial interpolation"
+/// - Esqueda, Välimäki and Bilbao, "Rounding corners with BLAMP"
+pub mod poly_blep {
+    use std::simd::prelude::*;
     use super::super::units::*;
     use super::basic;
+
+    pub struct SquareOscillator {
+        pub period: SampleOffset,
+    }
+
+    pub struct SquareOscillatorX16 {
+        pub period: [SampleOffset; 16],
+    }
+
+    pub struct PulseOscillator {
+        pub period: SampleOffset,
+        pub width: Unipolar<1>,
+    }
+
+    pub struct PulseOscillatorX16 {
+        pub period: [SampleOffset; 16],
+        pub width: [Unipolar<1>; 16],
+    }
+
+    pub struct SawOscillator {
+        pub period: SampleOffset,
+    }
+
+    pub struct SawOscillatorX16 {
+        pub period: [SampleOffset; 16],
+    }
+
+    pub struct TriangleOscillator {
+        pub period: SampleOffset,
+    }
+
+    pub struct TriangleOscillatorX16 {
+        pub period: [SampleOffset; 16],
+    }
+
+    /// How far through the cycle `offset` is, from 0 to 1,
+    /// and how far one sample moves it.
+    fn phase(period: f32, offset: f32) -> (f32, f32) {
+        let phase = (offset % period) / period;
+        // Any shorter and the corrections either side of a
+        // discontinuity would overlap.
+        let phase_delta = (1.0 / period).min(0.5);
+        (phase, phase_delta)
+    }
+
+    fn phase_x16(period: f32x16, offset: f32x16) -> (f32x16, f32x16) {
+        let phase = (offset % period) / period;
+        let phase_delta = (f32x16::splat(1.0) / period).simd_min(f32x16::splat(0.5));
+        (phase, phase_delta)
+    }
+
+    /// The band-limited minus the naive unit step, for a step at phase 0.
+    fn blep(phase: f32, phase_delta: f32) -> f32 {
+        if phase < phase_delta {
+            // Within a sample after the step.
+            let x = 1.0 - phase / phase_delta;
+            -x * x / 2.0
+        } else if phase > 1.0 - phase_delta {
+            // Within a sample before the step.
+            let x = 1.0 + (phase - 1.0) / phase_delta;
+            x * x / 2.0
+        } else {
+            0.0
+        }
+    }
+
+    fn blep_x16(phase: f32x16, phase_delta: f32x16) -> f32x16 {
+        let one = f32x16::splat(1.0);
+        let two = f32x16::splat(2.0);
+        let after = one - phase / phase_delta;
+        let before = one + (phase - one) / phase_delta;
+
+        let sample = phase.simd_lt(phase_delta).select(-after * after / two, f32x16::splat(0.0));
+        phase.simd_gt(one - phase_delta).select(before * before / two, sample)
+    }
+
+    /// The band-limited minus the naive corner, for a slope that
+    /// increases by one per sample at phase 0.
+    fn blamp(phase: f32, phase_delta: f32) -> f32 {
+        if phase < phase_delta {
+            let x = 1.0 - phase / phase_delta;
+            x * x * x / 6.0
+        } else if phase > 1.0 - phase_delta {
+            let x = 1.0 + (phase - 1.0) / phase_delta;
+            x * x * x / 6.0
+        } else {
+            0.0
+        }
+    }
+
+    fn blamp_x16(phase: f32x16, phase_delta: f32x16) -> f32x16 {
+        let one = f32x16::splat(1.0);
+        let six = f32x16::splat(6.0);
+        let after = one - phase / phase_delta;
+        let before = one + (phase - one) / phase_delta;
+
+        let sample = phase.simd_lt(phase_delta).select(after * after * after / six, f32x16::splat(0.0));
+        phase.simd_gt(one - phase_delta).select(before * before * before / six, sample)
+    }
+
+    fn half_cycle_later(phase: f32) -> f32 {
+        (phase + 0.5) % 1.0
+    }
+
+    fn half_cycle_later_x16(phase: f32x16) -> f32x16 {
+        (phase + f32x16::splat(0.5)) % f32x16::splat(1.0)
+    }
+
+    /// The phase relative to a pulse's fall, `width` through the cycle.
+    fn since_fall(phase: f32, width: f32) -> f32 {
+        (phase + 1.0 - width) % 1.0
+    }
+
+    fn since_fall_x16(phase: f32x16, width: f32x16) -> f32x16 {
+        let one = f32x16::splat(1.0);
+        (phase + one - width) % one
+    }
+
+    impl SquareOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let naive = basic::SquareOscillator { period: self.period }.sample(offset).0;
+            let (phase, phase_delta) = phase(self.period.0, offset.0);
+
+            // Steps up by 2 at the start of the cycle and down by 2 halfway.
+            let rise = blep(phase, phase_delta);
+            let fall = blep(half_cycle_later(phase), phase_delta);
+
+            Bipolar(naive + 2.0 * (rise - fall))
+        }
+    }
+
+    impl SquareOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let naive = basic::SquareOscillatorX16 { period: self.period }.sample(offset);
+            let naive = f32x16::from_array(naive.map(|s| s.0));
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let (phase, phase_delta) = phase_x16(period, offset);
+
+            let rise = blep_x16(phase, phase_delta);
+            let fall = blep_x16(half_cycle_later_x16(phase), phase_delta);
+            let sample = naive + f32x16::splat(2.0) * (rise - fall);
+
+            sample.to_array().map(Bipolar)
+        }
+    }
+
+    impl PulseOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let naive = basic::PulseOscillator { period: self.period, width: self.width }.sample(offset).0;
+            let (phase, phase_delta) = phase(self.period.0, offset.0);
+
+            // Steps up by 2 at the start of the cycle and down by 2 at `width`.
+            // At a width of 0 or 1 they cancel, leaving the naive constant.
+            let rise = blep(phase, phase_delta);
+            let fall = blep(since_fall(phase, self.width.0), phase_delta);
+
+            Bipolar(naive + 2.0 * (rise - fall))
+        }
+    }
+
+    impl PulseOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let naive = basic::PulseOscillatorX16 { period: self.period, width: self.width }.sample(offset);
+            let naive = f32x16::from_array(naive.map(|s| s.0));
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let width = f32x16::from_array(self.width.map(|w| w.0));
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let (phase, phase_delta) = phase_x16(period, offset);
+
+            let rise = blep_x16(phase, phase_delta);
+            let fall = blep_x16(since_fall_x16(phase, width), phase_delta);
+            let sample = naive + f32x16::splat(2.0) * (rise - fall);
+
+            sample.to_array().map(Bipolar)
+        }
+    }
+
+    impl SawOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let naive = basic::SawOscillator { period: self.period }.sample(offset).0;
+            let (phase, phase_delta) = phase(self.period.0, offset.0);
+
+            // Steps up by 2 at the start of the cycle.
+            Bipolar(naive + 2.0 * blep(phase, phase_delta))
+        }
+    }
+
+    impl SawOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let naive = basic::SawOscillatorX16 { period: self.period }.sample(offset);
+            let naive = f32x16::from_array(naive.map(|s| s.0));
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let (phase, phase_delta) = phase_x16(period, offset);
+
+            let sample = naive + f32x16::splat(2.0) * blep_x16(phase, phase_delta);
+
+            sample.to_array().map(Bipolar)
+        }
+    }
+
+    impl TriangleOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let naive = basic::TriangleOscillator { period: self.period }.sample(offset).0;
+            let (phase, phase_delta) = phase(self.period.0, offset.0);
+
+            // The slope turns from 4 to -4 per cycle at the peak,
+            // and back at the trough halfway.
+            let slope_change = 8.0 * phase_delta;
+            let peak = blamp(phase, phase_delta);
+            let trough = blamp(half_cycle_later(phase), phase_delta);
+
+            Bipolar(naive + slope_change * (trough - peak))
+        }
+    }
+
+    impl TriangleOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let naive = basic::TriangleOscillatorX16 { period: self.period }.sample(offset);
+            let naive = f32x16::from_array(naive.map(|s| s.0));
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let (phase, phase_delta) = phase_x16(period, offset);
+
+            let slope_change = f32x16::splat(8.0) * phase_delta;
+            let peak = blamp_x16(phase, phase_delta);
+            let trough = blamp_x16(half_cycle_later_x16(phase), phase_delta);
+            let sample = naive + slope_change * (trough - peak);
+
+            sample.to_array().map(Bipolar)
+        }
+    }
+}
+
+pub mod phased {
+    use super::super::units::*;
+    use super::super::wavetable::Wavetable;
+    use super::{basic, poly_blep, BandLimit};
     use std::simd::{f32x16, StdFloat};
 
     fn phased_offset(period: SampleOffset, phase: Unipolar<1>, offset: SampleOffset) -> SampleOffset {
@@ -230,101 +529,173 @@ pub mod phased {
             let phase_offset = period * phase;
             let new_offset = offset + phase_offset;
             let new_offset = new_offset.to_array();
-            new_offset.map(|o| SampleOffset(o))
+            new_offset.map(SampleOffset)
         } else {
             let new_offset = period.mul_add(phase, offset);
             let new_offset = new_offset.to_array();
-            new_offset.map(|o| SampleOffset(o))
+            new_offset.map(SampleOffset)
         }
     }
 
     pub struct SquareOscillator {
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl SquareOscillator {
         pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
             let offset = phased_offset(self.period, self.phase, offset);
-            let basic_osc = basic::SquareOscillator {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::SquareOscillator { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::SquareOscillator { period: self.period }.sample(offset)
+                }
+            }
         }
     }
 
     pub struct SquareOscillatorX16 {
         pub period: [SampleOffset; 16],
         pub phase: [Unipolar<1>; 16],
+        pub band_limit: BandLimit,
     }
 
     impl SquareOscillatorX16 {
         pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
             let offset = phased_offset_x16(self.period, self.phase, offset);
-            let basic_osc = basic::SquareOscillatorX16 {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::SquareOscillatorX16 { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::SquareOscillatorX16 { period: self.period }.sample(offset)
+                }
+            }
+        }
+    }
+
+    pub struct PulseOscillator {
+        pub period: SampleOffset,
+        pub phase: Unipolar<1>,
+        pub width: Unipolar<1>,
+        pub band_limit: BandLimit,
+    }
+
+    impl PulseOscillator {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let offset = phased_offset(self.period, self.phase, offset);
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::PulseOscillator { period: self.period, width: self.width }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::PulseOscillator { period: self.period, width: self.width }.sample(offset)
+                }
+            }
+        }
+    }
+
+    pub struct PulseOscillatorX16 {
+        pub period: [SampleOffset; 16],
+        pub phase: [Unipolar<1>; 16],
+        pub width: [Unipolar<1>; 16],
+        pub band_limit: BandLimit,
+    }
+
+    impl PulseOscillatorX16 {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let offset = phased_offset_x16(self.period, self.phase, offset);
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::PulseOscillatorX16 { period: self.period, width: self.width }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::PulseOscillatorX16 { period: self.period, width: self.width }.sample(offset)
+                }
+            }
         }
     }
 
     pub struct SawOscillator {
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl SawOscillator {
         pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
             let offset = phased_offset(self.period, self.phase, offset);
-            let basic_osc = basic::SawOscillator {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::SawOscillator { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::SawOscillator { period: self.period }.sample(offset)
+                }
+            }
         }
     }
 
     pub struct SawOscillatorX16 {
         pub period: [SampleOffset; 16],
         pub phase: [Unipolar<1>; 16],
+        pub band_limit: BandLimit,
     }
 
     impl SawOscillatorX16 {
         pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
             let offset = phased_offset_x16(self.period, self.phase, offset);
-            let basic_osc = basic::SawOscillatorX16 {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::SawOscillatorX16 { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::SawOscillatorX16 { period: self.period }.sample(offset)
+                }
+            }
         }
     }
 
     pub struct TriangleOscillator {
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl TriangleOscillator {
         pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
             let offset = phased_offset(self.period, self.phase, offset);
-            let basic_osc = basic::TriangleOscillator {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::TriangleOscillator { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::TriangleOscillator { period: self.period }.sample(offset)
+                }
+            }
         }
     }
 
     pub struct TriangleOscillatorX16 {
         pub period: [SampleOffset; 16],
         pub phase: [Unipolar<1>; 16],
+        pub band_limit: BandLimit,
     }
 
     impl TriangleOscillatorX16 {
         pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
             let offset = phased_offset_x16(self.period, self.phase, offset);
-            let basic_osc = basic::TriangleOscillatorX16 {
-                period: self.period,
-            };
-            basic_osc.sample(offset)
+            match self.band_limit {
+                BandLimit::Naive => {
+                    basic::TriangleOscillatorX16 { period: self.period }.sample(offset)
+                }
+                BandLimit::PolyBlep => {
+                    poly_blep::TriangleOscillatorX16 { period: self.period }.sample(offset)
+                }
+            }
         }
     }
 
@@ -361,6 +732,38 @@ pub mod phased {
             basic_osc.sample(offset)
         }
     }
+
+    pub struct WavetableOscillator<'this> {
+        pub wavetable: &'this Wavetable,
+        pub period: SampleOffset,
+        pub phase: Unipolar<1>,
+        pub position: Unipolar<1>,
+    }
+
+    impl<'this> WavetableOscillator<'this> {
+        pub fn sample(&self, offset: SampleOffset) -> Bipolar<1> {
+            let offset = phased_offset(self.period, self.phase, offset);
+            let phase = Unipolar((offset.0 % self.period.0) / self.period.0);
+            Bipolar(self.wavetable.sample(phase, self.period, self.position))
+        }
+    }
+
+    pub struct WavetableOscillatorX16<'this> {
+        pub wavetable: &'this Wavetable,
+        pub period: [SampleOffset; 16],
+        pub phase: [Unipolar<1>; 16],
+        pub position: [Unipolar<1>; 16],
+    }
+
+    impl<'this> WavetableOscillatorX16<'this> {
+        pub fn sample(&self, offset: [SampleOffset; 16]) -> [Bipolar<1>; 16] {
+            let offset = phased_offset_x16(self.period, self.phase, offset);
+            let offset = f32x16::from_array(offset.map(|o| o.0));
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let phase = ((offset % period) / period).to_array().map(Unipolar);
+            self.wavetable.sample_x16(phase, self.period, self.position).map(Bipolar)
+        }
+    }
 }
 
 /// Stateful oscillators that can be frequency modulated.
@@ -372,9 +775,10 @@ pub mod phased {
 /// - <https://dsp.stackexchange.com/questions/971/how-to-create-a-sine-wave-generator-that-can-smoothly-transition-between-frequen>
 pub mod phase_accumulating {
     use super::super::units::*;
-    use super::phased;
+    use super::super::wavetable::Wavetable;
+    use super::{phased, BandLimit};
 
-    fn accum_phase(phase: Unipolar<1>, period: SampleOffset) -> Unipolar<1> {
+    pub fn accum_phase(phase: Unipolar<1>, period: SampleOffset) -> Unipolar<1> {
         let phase_delta = 1.0 / period.0;
         let new_phase = (phase.0 + phase_delta) % 1.0;
         Unipolar(new_phase)
@@ -388,7 +792,7 @@ pub mod phase_accumulating {
     /// them.
     //
     // TODO: the modulus from accum_phase can possibly be lifted out to simd
-    fn accum_phase_x16(phase: Unipolar<1>, period: [SampleOffset; 16]) -> ([Unipolar<1>; 16], Unipolar<1>) {
+    pub fn accum_phase_x16(phase: Unipolar<1>, period: [SampleOffset; 16]) -> ([Unipolar<1>; 16], Unipolar<1>) {
         let mut phase_accum = phase;
         let mut phase = [phase; 16];
         for i in 1..16 {
@@ -409,6 +813,7 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl<'this> SquareOscillator<'this> {
@@ -418,6 +823,7 @@ pub mod phase_accumulating {
             let phased_osc = phased::SquareOscillator {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample(SampleOffset(0.0));
 
@@ -431,7 +837,8 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: [SampleOffset; 16],
         pub phase: Unipolar<1>,
-    }        
+        pub band_limit: BandLimit,
+    }
 
     impl<'this> SquareOscillatorX16<'this> {
         pub fn sample(&mut self) -> [Bipolar<1>; 16] {
@@ -441,6 +848,61 @@ pub mod phase_accumulating {
             let phased_osc = phased::SquareOscillatorX16 {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
+            };
+            let sample = phased_osc.sample([SampleOffset(0.0); 16]);
+
+            self.state.phase_accum = Some(phase_accum);
+
+            sample
+        }
+    }
+
+    pub struct PulseOscillator<'this> {
+        pub state: &'this mut OscillatorState,
+        pub period: SampleOffset,
+        pub phase: Unipolar<1>,
+        pub width: Unipolar<1>,
+        pub band_limit: BandLimit,
+    }
+
+    impl<'this> PulseOscillator<'this> {
+        pub fn sample(&mut self) -> Bipolar<1> {
+            let phase = self.state.phase_accum.unwrap_or(self.phase);
+
+            let phased_osc = phased::PulseOscillator {
+                period: self.period,
+                phase,
+                width: self.width,
+                band_limit: self.band_limit,
+            };
+            let sample = phased_osc.sample(SampleOffset(0.0));
+
+            self.state.phase_accum = Some(accum_phase(phase, self.period));
+
+            sample
+        }
+    }
+
+    pub struct PulseOscillatorX16<'this> {
+        pub state: &'this mut OscillatorState,
+        pub period: [SampleOffset; 16],
+        pub phase: Unipolar<1>,
+        /// The fraction of the cycle that's high, at each frame.
+        pub width: [Unipolar<1>; 16],
+        pub band_limit: BandLimit,
+    }
+
+    impl<'this> PulseOscillatorX16<'this> {
+        pub fn sample(&mut self) -> [Bipolar<1>; 16] {
+            let init_phase = self.state.phase_accum.unwrap_or(self.phase);
+            let (phase, phase_accum) = accum_phase_x16(init_phase, self.period);
+
+            let phased_osc = phased::PulseOscillatorX16 {
+                period: self.period,
+                phase,
+                width: self.width,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample([SampleOffset(0.0); 16]);
 
@@ -454,6 +916,7 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl<'this> SawOscillator<'this> {
@@ -463,6 +926,7 @@ pub mod phase_accumulating {
             let phased_osc = phased::SawOscillator {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample(SampleOffset(0.0));
 
@@ -476,6 +940,7 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: [SampleOffset; 16],
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl<'this> SawOscillatorX16<'this> {
@@ -486,6 +951,7 @@ pub mod phase_accumulating {
             let phased_osc = phased::SawOscillatorX16 {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample([SampleOffset(0.0); 16]);
 
@@ -499,6 +965,7 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: SampleOffset,
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl<'this> TriangleOscillator<'this> {
@@ -508,6 +975,7 @@ pub mod phase_accumulating {
             let phased_osc = phased::TriangleOscillator {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample(SampleOffset(0.0));
 
@@ -521,6 +989,7 @@ pub mod phase_accumulating {
         pub state: &'this mut OscillatorState,
         pub period: [SampleOffset; 16],
         pub phase: Unipolar<1>,
+        pub band_limit: BandLimit,
     }
 
     impl<'this> TriangleOscillatorX16<'this> {
@@ -531,6 +1000,7 @@ pub mod phase_accumulating {
             let phased_osc = phased::TriangleOscillatorX16 {
                 period: self.period,
                 phase,
+                band_limit: self.band_limit,
             };
             let sample = phased_osc.sample([SampleOffset(0.0); 16]);
 
@@ -622,4 +1092,415 @@ pub mod phase_accumulating {
             }.sample()
         }
     }
+
+    pub struct WavetableOscillator<'this> {
+        pub wavetable: &'this Wavetable,
+        pub state: &'this mut OscillatorState,
+        pub period: SampleOffset,
+        pub phase: Unipolar<1>,
+        pub position: Unipolar<1>,
+    }
+
+    impl<'this> WavetableOscillator<'this> {
+        pub fn sample(&mut self) -> Bipolar<1> {
+            let phase = self.state.phase_accum.unwrap_or(self.phase);
+
+            let phased_osc = phased::WavetableOscillator {
+                wavetable: self.wavetable,
+                period: self.period,
+                phase,
+                position: self.position,
+            };
+            let sample = phased_osc.sample(SampleOffset(0.0));
+
+            self.state.phase_accum = Some(accum_phase(phase, self.period));
+
+            sample
+        }
+    }
+
+    pub struct WavetableOscillatorX16<'this> {
+        pub wavetable: &'this Wavetable,
+        pub state: &'this mut OscillatorState,
+        pub period: [SampleOffset; 16],
+        pub phase: Unipolar<1>,
+        /// Where between the first and last frames of the table each frame plays.
+        pub position: [Unipolar<1>; 16],
+    }
+
+    impl<'this> WavetableOscillatorX16<'this> {
+        pub fn sample(&mut self) -> [Bipolar<1>; 16] {
+            let init_phase = self.state.phase_accum.unwrap_or(self.phase);
+            let (phase, phase_accum) = accum_phase_x16(init_phase, self.period);
+
+            let phased_osc = phased::WavetableOscillatorX16 {
+                wavetable: self.wavetable,
+                period: self.period,
+                phase,
+                position: self.position,
+            };
+            let sample = phased_osc.sample([SampleOffset(0.0); 16]);
+
+            self.state.phase_accum = Some(phase_accum);
+
+            sample
+        }
+    }
+}
+
+/// Phase accumulation for a stack of detuned oscillators, one per simd lane.
+///
+/// Each lane keeps its own phase, like the `phase_accumulating` oscillators,
+/// and the waveforms come from the `phased` oscillators sampled at those phases.
+pub mod unison {
+    use super::super::units::*;
+    use std::simd::f32x16;
+
+    #[derive(Default)]
+    #[derive(Copy, Clone)]
+    pub struct UnisonState {
+        /// `None` until the lanes are started.
+        pub phase_accum: Option<[Unipolar<1>; 16]>,
+    }
+
+    impl UnisonState {
+        /// Set the phase of each lane at the first frame, if not already started.
+        pub fn start(&mut self, phase: impl FnOnce() -> [Unipolar<1>; 16]) {
+            if self.phase_accum.is_none() {
+                self.phase_accum = Some(phase());
+            }
+        }
+    }
+
+    pub struct UnisonPhases<'this> {
+        pub state: &'this mut UnisonState,
+        pub period: [SampleOffset; 16],
+    }
+
+    impl<'this> UnisonPhases<'this> {
+        /// The phase of every lane for this frame, advancing them to the next frame.
+        ///
+        /// Lanes that weren't started begin at zero.
+        pub fn next(&mut self) -> [Unipolar<1>; 16] {
+            let phase = self.state.phase_accum.unwrap_or([Unipolar(0.0); 16]);
+
+            let one = f32x16::splat(1.0);
+            let period = f32x16::from_array(self.period.map(|p| p.0));
+            let phase_accum = f32x16::from_array(phase.map(|p| p.0));
+            let phase_accum = (phase_accum + one / period) % one;
+
+            self.state.phase_accum = Some(phase_accum.to_array().map(Unipolar));
+
+            phase
+        }
+    }
+}
+
+/// Phase accumulation for an oscillator hard synced to another.
+///
+/// The follower restarts its cycle whenever the leader's phase accumulator
+/// wraps. The leader is usually a sample into its new cycle by then, so the
+/// follower starts the same time into its own, rather than at zero.
+///
+/// The leader's phases are read from its own `phase_accumulating` state
+/// before it advances, so the follower restarts exactly as the leader does.
+pub mod hard_sync {
+    use super::super::units::*;
+    use super::phase_accumulating::OscillatorState;
+
+    pub struct SyncedPhase<'this> {
+        /// The leader's phase this frame.
+        pub leader_phase: Unipolar<1>,
+        pub leader_period: SampleOffset,
+        pub state: &'this mut OscillatorState,
+        pub period: SampleOffset,
+    }
+
+    pub struct SyncedPhasesX16<'this> {
+        /// The leader's phase each frame.
+        pub leader_phase: [Unipolar<1>; 16],
+        pub leader_period: [SampleOffset; 16],
+        pub state: &'this mut OscillatorState,
+        pub period: [SampleOffset; 16],
+    }
+
+    /// The follower's phase for this frame, advancing it to the next frame.
+    fn next_phase(
+        leader_phase: Unipolar<1>,
+        leader_period: SampleOffset,
+        state: &mut OscillatorState,
+        period: SampleOffset,
+    ) -> Unipolar<1> {
+        let mut phase = state.phase_accum.map_or(0.0, |p| p.0);
+
+        // The accumulator can only be less than one step into the
+        // cycle if it wrapped since the previous frame.
+        if leader_phase.0 < 1.0 / leader_period.0 {
+            let since_restart = leader_phase.0 * leader_period.0;
+            phase = (since_restart / period.0) % 1.0;
+        }
+
+        state.phase_accum = Some(Unipolar((phase + 1.0 / period.0) % 1.0));
+
+        Unipolar(phase)
+    }
+
+    impl<'this> SyncedPhase<'this> {
+        pub fn next(&mut self) -> Unipolar<1> {
+            next_phase(self.leader_phase, self.leader_period, self.state, self.period)
+        }
+    }
+
+    impl<'this> SyncedPhasesX16<'this> {
+        /// The follower's phase for each of 16 frames, advancing it past them.
+        pub fn next(&mut self) -> [Unipolar<1>; 16] {
+            std::array::from_fn(|i| {
+                next_phase(self.leader_phase[i], self.leader_period[i], self.state, self.period[i])
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::math;
+    use super::super::test_util;
+    use super::super::units::*;
+    use super::phase_accumulating::*;
+
+    const FFT_LEN: usize = 4096;
+
+    #[derive(Copy, Clone, Debug)]
+    enum Kind {
+        Square,
+        Pulse(f32),
+        Saw,
+        Triangle,
+    }
+
+    /// `FFT_LEN` frames of an oscillator whose fundamental is `bin`.
+    fn render(kind: Kind, band_limit: BandLimit, bin: f32) -> Vec<f32> {
+        let period = [SampleOffset(FFT_LEN as f32 / bin); 16];
+        let phase = Unipolar(0.0);
+        let mut state = OscillatorState::default();
+        let mut samples = Vec::with_capacity(FFT_LEN);
+        for _ in 0..FFT_LEN / 16 {
+            let state = &mut state;
+            let chunk = match kind {
+                Kind::Square => SquareOscillatorX16 { state, period, phase, band_limit }.sample(),
+                Kind::Pulse(width) => {
+                    let width = [Unipolar(width); 16];
+                    PulseOscillatorX16 { state, period, phase, width, band_limit }.sample()
+                }
+                Kind::Saw => SawOscillatorX16 { state, period, phase, band_limit }.sample(),
+                Kind::Triangle => TriangleOscillatorX16 { state, period, phase, band_limit }.sample(),
+            };
+            samples.extend(chunk.map(|s| s.0));
+        }
+        samples
+    }
+
+    /// The power in each bin up to nyquist, after a Hann window.
+    fn power_spectrum(samples: &[f32]) -> Vec<f64> {
+        let len = samples.len();
+        let mut re: Vec<f32> = samples.iter().enumerate().map(|(i, s)| {
+            let window = 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / len as f32).cos();
+            s * window
+        }).collect();
+        let mut im = vec![0.0; len];
+        math::fft(&mut re, &mut im);
+
+        (0..=len / 2).map(|i| (re[i] * re[i] + im[i] * im[i]) as f64).collect()
+    }
+
+    /// The fraction of the power that isn't near a harmonic of `bin`.
+    ///
+    /// Harmonics above nyquist fold back between the real harmonics,
+    /// so this is the aliasing, plus a little window leakage.
+    fn alias_ratio(samples: &[f32], bin: f32) -> f64 {
+        const NEAR: f32 = 3.0;
+        let power = power_spectrum(samples);
+
+        let mut alias = 0.0;
+        let mut total = 0.0;
+        for (i, power) in power.iter().enumerate().skip(NEAR as usize) {
+            let harmonic = (i as f32 / bin).round() * bin;
+            if (i as f32 - harmonic).abs() > NEAR {
+                alias += power;
+            }
+            total += power;
+        }
+        alias / total
+    }
+
+    #[test]
+    fn test_poly_blep_alias_energy() {
+        // Fundamentals around 550hz, 2.1khz and 4.3khz at 48khz,
+        // off the bins so the aliases fall between harmonics.
+        for bin in [47.3, 183.1, 371.7] {
+            for kind in [Kind::Square, Kind::Pulse(0.2), Kind::Saw, Kind::Triangle] {
+                let naive = alias_ratio(&render(kind, BandLimit::Naive, bin), bin);
+                let poly_blep = alias_ratio(&render(kind, BandLimit::PolyBlep, bin), bin);
+
+                // The triangle's harmonics fall off fast enough that it
+                // barely aliases at low notes, leaving mostly window leakage.
+                let min_improvement = match kind {
+                    Kind::Square | Kind::Pulse(_) | Kind::Saw => 10.0,
+                    Kind::Triangle if bin > 100.0 => 4.0,
+                    Kind::Triangle => 1.0,
+                };
+                assert!(
+                    poly_blep * min_improvement < naive,
+                    "{:?} at bin {}: naive {:e}, polyblep {:e}", kind, bin, naive, poly_blep,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_poly_blep_x16_matches_scalar() {
+        let phase = Unipolar(0.0);
+        let band_limit = BandLimit::PolyBlep;
+
+        for kind in [Kind::Square, Kind::Saw, Kind::Triangle] {
+            let mut scalar_state = OscillatorState::default();
+            let mut x16_state = OscillatorState::default();
+            test_util::assert_x16_matches_scalar(
+                8,
+                1e-4,
+                |frame| {
+                    let state = &mut scalar_state;
+                    let period = test_util::varying_period(37.3, frame);
+                    let sample = match kind {
+                        Kind::Square => SquareOscillator { state, period, phase, band_limit }.sample(),
+                        Kind::Saw => SawOscillator { state, period, phase, band_limit }.sample(),
+                        Kind::Triangle => TriangleOscillator { state, period, phase, band_limit }.sample(),
+                        Kind::Pulse(_) => unreachable!(),
+                    };
+                    sample.0
+                },
+                |start| {
+                    let state = &mut x16_state;
+                    let period = std::array::from_fn(|lane| test_util::varying_period(37.3, start + lane));
+                    let samples = match kind {
+                        Kind::Square => SquareOscillatorX16 { state, period, phase, band_limit }.sample(),
+                        Kind::Saw => SawOscillatorX16 { state, period, phase, band_limit }.sample(),
+                        Kind::Triangle => TriangleOscillatorX16 { state, period, phase, band_limit }.sample(),
+                        Kind::Pulse(_) => unreachable!(),
+                    };
+                    samples.map(|s| s.0)
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn test_pulse_width() {
+        for band_limit in [BandLimit::Naive, BandLimit::PolyBlep] {
+            // At half width it's the square.
+            let square = render(Kind::Square, band_limit, 47.3);
+            let pulse = render(Kind::Pulse(0.5), band_limit, 47.3);
+            for (square, pulse) in square.iter().zip(pulse) {
+                assert!((square - pulse).abs() < 1e-4, "{} != {}", square, pulse);
+            }
+
+            // High for a quarter of the cycle, so the average is halfway to -1.
+            let pulse = render(Kind::Pulse(0.25), band_limit, 32.0);
+            let mean = pulse.iter().sum::<f32>() / pulse.len() as f32;
+            assert!((mean + 0.5).abs() < 1e-3, "{}", mean);
+        }
+    }
+
+    #[test]
+    fn test_pulse_width_modulation_x16_matches_scalar() {
+        let phase = Unipolar(0.0);
+        let band_limit = BandLimit::PolyBlep;
+        // Sweeps the whole range over the test.
+        let width = |frame: usize| Unipolar(frame as f32 / 128.0);
+
+        let mut scalar_state = OscillatorState::default();
+        let mut x16_state = OscillatorState::default();
+        test_util::assert_x16_matches_scalar(
+            8,
+            1e-4,
+            |frame| {
+                PulseOscillator {
+                    state: &mut scalar_state,
+                    period: test_util::varying_period(37.3, frame),
+                    phase,
+                    width: width(frame),
+                    band_limit,
+                }.sample().0
+            },
+            |start| {
+                PulseOscillatorX16 {
+                    state: &mut x16_state,
+                    period: std::array::from_fn(|lane| test_util::varying_period(37.3, start + lane)),
+                    phase,
+                    width: std::array::from_fn(|lane| width(start + lane)),
+                    band_limit,
+                }.sample().map(|s| s.0)
+            },
+        );
+    }
+
+    #[test]
+    fn test_unison_starts_once() {
+        let mut state = unison::UnisonState::default();
+        state.start(|| [Unipolar(0.25); 16]);
+        state.start(|| unreachable!("already started"));
+
+        let mut phases = unison::UnisonPhases {
+            state: &mut state,
+            period: [SampleOffset(4.0); 16],
+        };
+        assert_eq!(phases.next(), [Unipolar(0.25); 16]);
+        assert_eq!(phases.next(), [Unipolar(0.5); 16]);
+    }
+
+    #[test]
+    fn test_hard_sync_restarts_with_leader() {
+        use super::hard_sync::*;
+
+        // A power of two, so the leader wraps exactly every 64 frames.
+        let leader_period = SampleOffset(64.0);
+        let period = SampleOffset(23.7);
+
+        let mut leader_phase = Unipolar(0.0);
+        let mut state = OscillatorState::default();
+        let phases: Vec<f32> = (0..256).map(|_| {
+            let phase = SyncedPhase {
+                leader_phase,
+                leader_period,
+                state: &mut state,
+                period,
+            }.next().0;
+            leader_phase = accum_phase(leader_phase, leader_period);
+            phase
+        }).collect();
+
+        for (i, phase) in phases.iter().enumerate() {
+            if i % 64 == 0 {
+                assert_eq!(*phase, 0.0, "frame {}", i);
+            }
+            if i >= 64 {
+                assert_eq!(*phase, phases[i - 64], "frame {}", i);
+            }
+        }
+
+        let mut leader_phase = Unipolar(0.0);
+        let mut state = OscillatorState::default();
+        for chunk in phases.chunks(16) {
+            let leader_phases;
+            (leader_phases, leader_phase) = accum_phase_x16(leader_phase, [leader_period; 16]);
+            let x16 = SyncedPhasesX16 {
+                leader_phase: leader_phases,
+                leader_period: [leader_period; 16],
+                state: &mut state,
+                period: [period; 16],
+            }.next();
+            assert_eq!(x16.map(|p| p.0), chunk);
+        }
+    }
 }
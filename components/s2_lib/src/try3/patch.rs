@@ -0,0 +1,986 @@
+//! The `.synth2` patch language.
+//!
+//! A patch describes a single `sc::Layer`:
+//!
+//! ```text
+//! synth mySynth {
+//!     osc {
+//!         kind = saw
+//!         gain = 1.0
+//!     }
+//!     noise = 0.0
+//!     lpf {
+//!         freq = 200hz
+//!     }
+//!     amp_env {
+//!         attack = 100ms
+//!         decay = 100ms
+//!         sustain = 0.5
+//!         release = 100ms
+//!     }
+//!     // ...
+//! }
+//! ```
+//!
+//! Any key that is not mentioned keeps its value from `Synth::default_config`.
+//! Frequencies accept `hz` and `khz` suffixes, times accept `ms` and `s`,
+//! detune accepts `cents`, and bare numbers are in those base units.
+//! Switches are `true` or `false`.
+//! File names are double-quoted, and relative to the patch file.
+//!
+//! Patches can also be stored as JSON, with every field spelled out,
+//! for presets that are generated or edited by tools.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use serde::{Serialize, Deserialize};
+use super::static_config as sc;
+use super::synth::Synth;
+use super::units::*;
+use super::wavetable::Wavetable;
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Patch {
+    pub name: String,
+    pub layer: sc::Layer,
+    /// A WAV file for the wavetable oscillator, relative to the patch file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wavetable: Option<String>,
+}
+
+/// A parse or validation error with the 1-based position it occurred at.
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError { }
+
+/// Read and parse a patch file.
+///
+/// Files with a `.json` extension are read as JSON,
+/// anything else as the `.synth2` language.
+///
+/// Errors are reported as `path:line:column: message`.
+pub fn load(path: &Path) -> Result<Patch> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("reading patch {}", path.display()))?;
+    if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+        parse_json(&source)
+            .map_err(|e| anyhow::anyhow!("{}:{}", path.display(), e))
+    } else {
+        parse(&source)
+            .map_err(|e| anyhow::anyhow!("{}:{}", path.display(), e))
+    }
+}
+
+/// Load the wavetable of a patch read from `path`,
+/// or the default sine table if it has none.
+pub fn load_wavetable(path: &Path, patch: &Patch) -> Result<Arc<Wavetable>> {
+    let Some(wavetable_path) = wavetable_path(path, patch) else {
+        return Ok(Wavetable::sine());
+    };
+    Ok(Arc::new(Wavetable::load(&wavetable_path)?))
+}
+
+/// The wavetable file named by a patch read from `path`, if any.
+pub fn wavetable_path(path: &Path, patch: &Patch) -> Option<PathBuf> {
+    let file = patch.wavetable.as_ref()?;
+    Some(path.parent().unwrap_or(Path::new("")).join(file))
+}
+
+/// Parse a JSON patch.
+///
+/// Out-of-range values are rejected by the `units` conversions.
+pub fn parse_json(source: &str) -> Result<Patch, ParseError> {
+    serde_json::from_str(source)
+        .map_err(|e| ParseError {
+            line: e.line() as u32,
+            column: e.column() as u32,
+            message: e.to_string(),
+        })
+}
+
+pub fn to_json(patch: &Patch) -> Result<String> {
+    Ok(serde_json::to_string_pretty(patch)?)
+}
+
+pub fn parse(source: &str) -> Result<Patch, ParseError> {
+    let tokens = lex(source)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        end: end_span(source),
+    };
+
+    parser.expect_keyword("synth")?;
+    let (name, _) = parser.expect_ident()?;
+    let body = parser.parse_block()?;
+    parser.expect_end()?;
+
+    let mut layer = Synth::default_config();
+    let mut wavetable = None;
+    load_layer(&body, &mut layer, &mut wavetable)?;
+
+    Ok(Patch {
+        name,
+        layer,
+        wavetable,
+    })
+}
+
+#[derive(Copy, Clone)]
+struct Span {
+    line: u32,
+    column: u32,
+}
+
+impl Span {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+fn end_span(source: &str) -> Span {
+    let line = source.lines().count().max(1) as u32;
+    let column = source.lines().last().map(|l| l.chars().count()).unwrap_or(0) as u32 + 1;
+    Span { line, column }
+}
+
+enum TokenKind {
+    Ident(String),
+    Number {
+        value: f32,
+        unit: Option<String>,
+    },
+    String(String),
+    OpenBrace,
+    CloseBrace,
+    Equals,
+}
+
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+fn lex(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    let mut column = 1;
+
+    while let Some(&ch) = chars.peek() {
+        let span = Span { line, column };
+
+        if ch == '\n' {
+            chars.next();
+            line += 1;
+            column = 1;
+        } else if ch.is_whitespace() {
+            chars.next();
+            column += 1;
+        } else if ch == '/' {
+            chars.next();
+            column += 1;
+            if chars.peek() != Some(&'/') {
+                return Err(span.error("unexpected character `/`"));
+            }
+            while chars.peek().map(|&c| c != '\n').unwrap_or(false) {
+                chars.next();
+            }
+        } else if ch == '{' || ch == '}' || ch == '=' {
+            chars.next();
+            column += 1;
+            let kind = match ch {
+                '{' => TokenKind::OpenBrace,
+                '}' => TokenKind::CloseBrace,
+                _ => TokenKind::Equals,
+            };
+            tokens.push(Token { kind, span });
+        } else if ch == '"' {
+            chars.next();
+            column += 1;
+            let string = take_while(&mut chars, &mut column, |c| c != '"' && c != '\n');
+            if chars.next() != Some('"') {
+                return Err(span.error("unterminated string"));
+            }
+            column += 1;
+            tokens.push(Token { kind: TokenKind::String(string), span });
+        } else if ch.is_ascii_alphabetic() || ch == '_' {
+            let word = take_while(&mut chars, &mut column, |c| c.is_ascii_alphanumeric() || c == '_');
+            tokens.push(Token { kind: TokenKind::Ident(word), span });
+        } else if ch.is_ascii_digit() || ch == '-' || ch == '.' {
+            let number = take_while(&mut chars, &mut column, |c| c.is_ascii_digit() || c == '-' || c == '.');
+            let value = number.parse::<f32>()
+                .map_err(|_| span.error(format!("invalid number `{}`", number)))?;
+            let unit = take_while(&mut chars, &mut column, |c| c.is_ascii_alphabetic());
+            let unit = if unit.is_empty() { None } else { Some(unit) };
+            tokens.push(Token { kind: TokenKind::Number { value, unit }, span });
+        } else {
+            return Err(span.error(format!("unexpected character `{}`", ch)));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn take_while(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    column: &mut u32,
+    pred: impl Fn(char) -> bool,
+) -> String {
+    let mut s = String::new();
+    while let Some(&c) = chars.peek() {
+        if !pred(c) {
+            break;
+        }
+        s.push(c);
+        chars.next();
+        *column += 1;
+    }
+    s
+}
+
+struct Block {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    key: String,
+    key_span: Span,
+    value: Value,
+}
+
+enum Value {
+    Number {
+        value: f32,
+        unit: Option<String>,
+        span: Span,
+    },
+    Ident {
+        name: String,
+        span: Span,
+    },
+    String {
+        value: String,
+        span: Span,
+    },
+    Block(Block, Span),
+}
+
+struct Parser<'this> {
+    tokens: &'this [Token],
+    position: usize,
+    end: Span,
+}
+
+impl<'this> Parser<'this> {
+    fn next(&mut self) -> Option<&'this Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&'this Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        let (ident, span) = self.expect_ident()?;
+        if ident == keyword {
+            Ok(())
+        } else {
+            Err(span.error(format!("expected `{}`, found `{}`", keyword, ident)))
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<(String, Span), ParseError> {
+        match self.next() {
+            Some(Token { kind: TokenKind::Ident(ident), span }) => Ok((ident.clone(), *span)),
+            Some(token) => Err(token.span.error("expected identifier")),
+            None => Err(self.end.error("expected identifier, found end of file")),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), ParseError> {
+        match self.next() {
+            Some(token) => Err(token.span.error("unexpected token after end of synth")),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_block(&mut self) -> Result<Block, ParseError> {
+        match self.next() {
+            Some(Token { kind: TokenKind::OpenBrace, .. }) => { },
+            Some(token) => return Err(token.span.error("expected `{`")),
+            None => return Err(self.end.error("expected `{`, found end of file")),
+        }
+
+        let mut entries = vec![];
+
+        loop {
+            match self.peek() {
+                Some(Token { kind: TokenKind::CloseBrace, .. }) => {
+                    self.next();
+                    break;
+                }
+                Some(_) => {
+                    entries.push(self.parse_entry()?);
+                }
+                None => {
+                    return Err(self.end.error("expected `}`, found end of file"));
+                }
+            }
+        }
+
+        Ok(Block { entries })
+    }
+
+    fn parse_entry(&mut self) -> Result<Entry, ParseError> {
+        let (key, key_span) = self.expect_ident()?;
+
+        let value = match self.peek() {
+            Some(Token { kind: TokenKind::OpenBrace, span }) => {
+                let span = *span;
+                Value::Block(self.parse_block()?, span)
+            }
+            Some(Token { kind: TokenKind::Equals, .. }) => {
+                self.next();
+                match self.next() {
+                    Some(Token { kind: TokenKind::Number { value, unit }, span }) => {
+                        Value::Number { value: *value, unit: unit.clone(), span: *span }
+                    }
+                    Some(Token { kind: TokenKind::Ident(name), span }) => {
+                        Value::Ident { name: name.clone(), span: *span }
+                    }
+                    Some(Token { kind: TokenKind::String(value), span }) => {
+                        Value::String { value: value.clone(), span: *span }
+                    }
+                    Some(token) => return Err(token.span.error("expected value")),
+                    None => return Err(self.end.error("expected value, found end of file")),
+                }
+            }
+            Some(token) => return Err(token.span.error(format!("expected `=` or `{{` after `{}`", key))),
+            None => return Err(self.end.error("expected `=` or `{`, found end of file")),
+        };
+
+        Ok(Entry { key, key_span, value })
+    }
+}
+
+impl Block {
+    /// The entries of the block, rejecting keys that appear more than once.
+    fn unique_entries(&self) -> Result<&[Entry], ParseError> {
+        for (index, entry) in self.entries.iter().enumerate() {
+            if self.entries[..index].iter().any(|e| e.key == entry.key) {
+                return Err(entry.key_span.error(format!("duplicate key `{}`", entry.key)));
+            }
+        }
+        Ok(&self.entries)
+    }
+}
+
+impl Entry {
+    fn unknown_key(&self, section: &str) -> ParseError {
+        self.key_span.error(format!("unknown key `{}` in `{}`", self.key, section))
+    }
+
+    fn block(&self) -> Result<&Block, ParseError> {
+        match &self.value {
+            Value::Block(block, _) => Ok(block),
+            _ => Err(self.key_span.error(format!("`{}` expects a `{{ ... }}` block", self.key))),
+        }
+    }
+
+    fn ident(&self) -> Result<(&str, Span), ParseError> {
+        match &self.value {
+            Value::Ident { name, span } => Ok((name, *span)),
+            _ => Err(self.key_span.error(format!("`{}` expects a name", self.key))),
+        }
+    }
+
+    fn bool(&self) -> Result<bool, ParseError> {
+        match &self.value {
+            Value::Ident { name, .. } if name == "true" => Ok(true),
+            Value::Ident { name, .. } if name == "false" => Ok(false),
+            _ => Err(self.key_span.error(format!("`{}` expects true or false", self.key))),
+        }
+    }
+
+    fn string(&self) -> Result<&str, ParseError> {
+        match &self.value {
+            Value::String { value, .. } => Ok(value),
+            _ => Err(self.key_span.error(format!("`{}` expects a quoted string", self.key))),
+        }
+    }
+
+    /// A number, with its unit scaled to the base unit.
+    ///
+    /// `units` maps suffixes to multipliers; a missing suffix is a multiplier of 1.
+    fn number(&self, units: &[(&str, f32)]) -> Result<(f32, Span), ParseError> {
+        match &self.value {
+            Value::Number { value, unit: None, span } => Ok((*value, *span)),
+            Value::Number { value, unit: Some(unit), span } => {
+                match units.iter().find(|(name, _)| name == unit) {
+                    Some((_, scale)) => Ok((value * scale, *span)),
+                    None => Err(span.error(format!("invalid unit `{}` for `{}`", unit, self.key))),
+                }
+            }
+            _ => Err(self.key_span.error(format!("`{}` expects a number", self.key))),
+        }
+    }
+
+    fn unipolar<const N: u16>(&self) -> Result<Unipolar<N>, ParseError> {
+        let (value, span) = self.number(&[])?;
+        Unipolar::<N>::try_from(value)
+            .map_err(|e| span.error(format!("`{}`: {}", self.key, e)))
+    }
+
+    fn bipolar<const N: u16>(&self) -> Result<Bipolar<N>, ParseError> {
+        let (value, span) = self.number(&[])?;
+        Bipolar::<N>::try_from(value)
+            .map_err(|e| span.error(format!("`{}`: {}", self.key, e)))
+    }
+
+    fn hz(&self) -> Result<Hz, ParseError> {
+        let (value, span) = self.number(&[("hz", 1.0), ("khz", 1000.0)])?;
+        Hz::try_from(value)
+            .map_err(|e| span.error(format!("`{}`: {}", self.key, e)))
+    }
+
+    fn cents(&self) -> Result<Cents, ParseError> {
+        let (value, span) = self.number(&[("cents", 1.0)])?;
+        Cents::try_from(value)
+            .map_err(|e| span.error(format!("`{}`: {}", self.key, e)))
+    }
+
+    fn ms(&self) -> Result<Ms, ParseError> {
+        let (value, span) = self.number(&[("ms", 1.0), ("s", 1000.0)])?;
+        Ms::try_from(value)
+            .map_err(|e| span.error(format!("`{}`: {}", self.key, e)))
+    }
+}
+
+fn load_layer(
+    block: &Block,
+    layer: &mut sc::Layer,
+    wavetable: &mut Option<String>,
+) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "wavetable" => *wavetable = Some(entry.string()?.to_string()),
+            "osc" => load_oscillator(entry.block()?, &mut layer.osc)?,
+            "osc2" => load_oscillator2(entry.block()?, &mut layer.osc2)?,
+            "fm" => load_fm(entry.block()?, &mut layer.fm)?,
+            "unison" => load_unison(entry.block()?, &mut layer.unison)?,
+            "noise" => layer.noise = entry.unipolar()?,
+            "pan" => layer.pan = entry.bipolar()?,
+            "lpf" => load_lpf(entry.block()?, &mut layer.lpf)?,
+            "velocity" => load_velocity(entry.block()?, &mut layer.velocity)?,
+            "amp_env" => load_adsr(entry.block()?, "amp_env", &mut layer.amp_env)?,
+            "mod_env" => load_adsr(entry.block()?, "mod_env", &mut layer.mod_env)?,
+            "modulations" => load_modulations(entry.block()?, &mut layer.modulations)?,
+            _ => return Err(entry.unknown_key("synth")),
+        }
+    }
+    Ok(())
+}
+
+fn load_oscillator(block: &Block, osc: &mut sc::Oscillator) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "kind" => osc.kind = load_oscillator_kind(entry)?,
+            "gain" => osc.gain = entry.unipolar()?,
+            "band_limit" => osc.band_limit = load_band_limit(entry)?,
+            "wavetable_position" => osc.wavetable_position = entry.unipolar()?,
+            "pulse_width" => osc.pulse_width = entry.unipolar()?,
+            _ => return Err(entry.unknown_key("osc")),
+        }
+    }
+    Ok(())
+}
+
+fn load_oscillator2(block: &Block, osc2: &mut sc::Oscillator2) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "kind" => osc2.kind = load_oscillator2_kind(entry)?,
+            "band_limit" => osc2.band_limit = load_band_limit(entry)?,
+            "wavetable_position" => osc2.wavetable_position = entry.unipolar()?,
+            "pulse_width" => osc2.pulse_width = entry.unipolar()?,
+            "coarse" => osc2.coarse = entry.bipolar()?,
+            "fine" => osc2.fine = entry.cents()?,
+            "sync" => osc2.sync = entry.bool()?,
+            "mix" => osc2.mix = entry.unipolar()?,
+            "ring_mod" => osc2.ring_mod = entry.unipolar()?,
+            _ => return Err(entry.unknown_key("osc2")),
+        }
+    }
+    Ok(())
+}
+
+fn load_oscillator_kind(entry: &Entry) -> Result<sc::OscillatorKind, ParseError> {
+    let (name, span) = entry.ident()?;
+    match name {
+        "square" => Ok(sc::OscillatorKind::Square),
+        "pulse" => Ok(sc::OscillatorKind::Pulse),
+        "saw" => Ok(sc::OscillatorKind::Saw),
+        "triangle" => Ok(sc::OscillatorKind::Triangle),
+        "sine" => Ok(sc::OscillatorKind::Sine),
+        "wavetable" => Ok(sc::OscillatorKind::Wavetable),
+        "fm" => Ok(sc::OscillatorKind::Fm),
+        _ => Err(span.error(format!(
+            "unknown oscillator kind `{}`, expected one of square, pulse, saw, triangle, sine, wavetable, fm",
+            name,
+        ))),
+    }
+}
+
+/// Like `load_oscillator_kind`, without `fm`, which only `osc` can run.
+fn load_oscillator2_kind(entry: &Entry) -> Result<sc::OscillatorKind, ParseError> {
+    match load_oscillator_kind(entry)? {
+        sc::OscillatorKind::Fm => {
+            let (_, span) = entry.ident()?;
+            Err(span.error("`osc2` can't be `fm`, only `osc` can run the operators"))
+        }
+        kind => Ok(kind),
+    }
+}
+
+fn load_fm(block: &Block, fm: &mut sc::Fm) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "algorithm" => {
+                let (value, span) = entry.number(&[])?;
+                if value.fract() != 0.0 || value < 1.0 || value > f32::from(sc::FM_ALGORITHMS) {
+                    return Err(span.error(format!(
+                        "`algorithm` must be a whole number from 1 to {}", sc::FM_ALGORITHMS,
+                    )));
+                }
+                fm.algorithm = value as u8;
+            }
+            key => {
+                // `op1` to `op4`
+                let operator = key.strip_prefix("op")
+                    .and_then(|number| number.parse::<usize>().ok())
+                    .and_then(|number| number.checked_sub(1))
+                    .and_then(|index| fm.operators.get_mut(index));
+                match operator {
+                    Some(operator) => load_fm_operator(entry.block()?, key, operator)?,
+                    None => return Err(entry.unknown_key("fm")),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_fm_operator(block: &Block, section: &str, operator: &mut sc::FmOperator) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "ratio" => {
+                let (value, span) = entry.number(&[])?;
+                if value <= 0.0 || value > sc::MAX_FM_RATIO {
+                    return Err(span.error(format!(
+                        "`ratio` must be above 0 and at most {}", sc::MAX_FM_RATIO,
+                    )));
+                }
+                operator.ratio = value;
+            }
+            "level" => operator.level = entry.unipolar()?,
+            "feedback" => operator.feedback = entry.unipolar()?,
+            "env" => load_adsr(entry.block()?, "env", &mut operator.env)?,
+            _ => return Err(entry.unknown_key(section)),
+        }
+    }
+    Ok(())
+}
+
+fn load_band_limit(entry: &Entry) -> Result<sc::BandLimit, ParseError> {
+    let (name, span) = entry.ident()?;
+    match name {
+        "naive" => Ok(sc::BandLimit::Naive),
+        "polyblep" => Ok(sc::BandLimit::PolyBlep),
+        _ => Err(span.error(format!(
+            "unknown band limit `{}`, expected naive or polyblep",
+            name,
+        ))),
+    }
+}
+
+fn load_unison(block: &Block, unison: &mut sc::Unison) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "voices" => {
+                let (value, span) = entry.number(&[])?;
+                if value.fract() != 0.0 || value < 1.0 || value > f32::from(sc::MAX_UNISON) {
+                    return Err(span.error(format!(
+                        "`voices` must be a whole number from 1 to {}", sc::MAX_UNISON,
+                    )));
+                }
+                unison.voices = value as u8;
+            }
+            "detune" => unison.detune = entry.cents()?,
+            "spread" => unison.spread = entry.unipolar()?,
+            "phase" => {
+                unison.phase = match &entry.value {
+                    Value::Ident { name, .. } if name == "random" => sc::UnisonPhase::Random,
+                    Value::Ident { name, span } => {
+                        return Err(span.error(format!(
+                            "unknown unison phase `{}`, expected `random` or a number", name,
+                        )));
+                    }
+                    _ => sc::UnisonPhase::Fixed(entry.unipolar()?),
+                };
+            }
+            _ => return Err(entry.unknown_key("unison")),
+        }
+    }
+    Ok(())
+}
+
+fn load_lpf(block: &Block, lpf: &mut sc::LowPassFilter) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "freq" => lpf.freq = entry.hz()?,
+            _ => return Err(entry.unknown_key("lpf")),
+        }
+    }
+    Ok(())
+}
+
+fn load_velocity(block: &Block, velocity: &mut sc::Velocity) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "sensitivity" => velocity.sensitivity = entry.unipolar()?,
+            "curve" => {
+                let (name, span) = entry.ident()?;
+                velocity.curve = match name {
+                    "linear" => sc::VelocityCurve::Linear,
+                    "soft" => sc::VelocityCurve::Soft,
+                    "hard" => sc::VelocityCurve::Hard,
+                    _ => return Err(span.error(format!(
+                        "unknown velocity curve `{}`, expected one of linear, soft, hard",
+                        name,
+                    ))),
+                };
+            }
+            _ => return Err(entry.unknown_key("velocity")),
+        }
+    }
+    Ok(())
+}
+
+fn load_adsr(block: &Block, section: &str, adsr: &mut sc::Adsr) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "attack" => adsr.attack = entry.ms()?,
+            "decay" => adsr.decay = entry.ms()?,
+            "sustain" => adsr.sustain = entry.unipolar()?,
+            "release" => adsr.release = entry.ms()?,
+            _ => return Err(entry.unknown_key(section)),
+        }
+    }
+    Ok(())
+}
+
+fn load_modulations(block: &Block, modulations: &mut sc::Modulations) -> Result<(), ParseError> {
+    for entry in block.unique_entries()? {
+        match entry.key.as_str() {
+            "mod_env_to_osc_freq" => modulations.mod_env_to_osc_freq = entry.bipolar()?,
+            "mod_env_to_lpf_freq" => modulations.mod_env_to_lpf_freq = entry.bipolar()?,
+            "velocity_to_lpf_freq" => modulations.velocity_to_lpf_freq = entry.bipolar()?,
+            "velocity_to_mod_env" => modulations.velocity_to_mod_env = entry.unipolar()?,
+            "pitch_bend_range" => modulations.pitch_bend_range = entry.unipolar()?,
+            "mod_wheel_to_osc_freq" => modulations.mod_wheel_to_osc_freq = entry.bipolar()?,
+            "mod_wheel_to_lpf_freq" => modulations.mod_wheel_to_lpf_freq = entry.bipolar()?,
+            "pressure_to_osc_freq" => modulations.pressure_to_osc_freq = entry.bipolar()?,
+            "pressure_to_lpf_freq" => modulations.pressure_to_lpf_freq = entry.bipolar()?,
+            "timbre_to_lpf_freq" => modulations.timbre_to_lpf_freq = entry.bipolar()?,
+            "poly_pressure_to_osc_freq" => modulations.poly_pressure_to_osc_freq = entry.bipolar()?,
+            "poly_pressure_to_lpf_freq" => modulations.poly_pressure_to_lpf_freq = entry.bipolar()?,
+            "mod_env_to_pan" => modulations.mod_env_to_pan = entry.bipolar()?,
+            "timbre_to_pan" => modulations.timbre_to_pan = entry.bipolar()?,
+            "mod_env_to_wavetable_position" => modulations.mod_env_to_wavetable_position = entry.bipolar()?,
+            "mod_wheel_to_wavetable_position" => modulations.mod_wheel_to_wavetable_position = entry.bipolar()?,
+            "timbre_to_wavetable_position" => modulations.timbre_to_wavetable_position = entry.bipolar()?,
+            "mod_env_to_pulse_width" => modulations.mod_env_to_pulse_width = entry.bipolar()?,
+            "mod_wheel_to_pulse_width" => modulations.mod_wheel_to_pulse_width = entry.bipolar()?,
+            "timbre_to_pulse_width" => modulations.timbre_to_pulse_width = entry.bipolar()?,
+            _ => return Err(entry.unknown_key("modulations")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_patch() {
+        let source = r#"
+            synth test {
+                wavetable = "tables/pad.wav"
+                osc {
+                    kind = pulse
+                    gain = 0.25
+                    band_limit = naive
+                    wavetable_position = 0.5
+                    pulse_width = 0.25
+                }
+                osc2 {
+                    kind = sine
+                    coarse = -12
+                    fine = 7cents
+                    sync = true
+                    mix = 0.5
+                }
+                fm {
+                    algorithm = 5
+                    op2 {
+                        ratio = 3.5
+                        level = 0.75
+                        feedback = 0.25
+                        env { decay = 200ms }
+                    }
+                }
+                unison {
+                    voices = 7
+                    detune = 12cents
+                    phase = random
+                }
+                noise = 0.5
+                pan = -0.25
+                lpf { freq = 1.5khz }
+                amp_env {
+                    attack = 1s
+                    sustain = 0.75
+                }
+                velocity { curve = hard }
+                modulations {
+                    mod_env_to_lpf_freq = -2
+                    velocity_to_mod_env = 0.5
+                    pitch_bend_range = 12
+                    mod_wheel_to_lpf_freq = 3
+                    mod_wheel_to_wavetable_position = -0.5
+                    mod_env_to_pulse_width = 0.5
+                }
+            }
+        "#;
+        let patch = parse(source).expect("parse");
+        let default = Synth::default_config();
+
+        assert_eq!(patch.name, "test");
+        assert!(matches!(patch.layer.osc.kind, sc::OscillatorKind::Pulse));
+        assert_eq!(patch.layer.osc.gain.0, 0.25);
+        assert_eq!(patch.layer.osc.band_limit, sc::BandLimit::Naive);
+        assert_eq!(patch.layer.osc.wavetable_position.0, 0.5);
+        assert_eq!(patch.layer.osc.pulse_width.0, 0.25);
+        assert!(matches!(patch.layer.osc2.kind, sc::OscillatorKind::Sine));
+        assert_eq!(patch.layer.osc2.coarse.0, -12.0);
+        assert_eq!(patch.layer.osc2.fine.0, 7.0);
+        assert!(patch.layer.osc2.sync);
+        assert_eq!(patch.layer.osc2.mix.0, 0.5);
+        assert_eq!(patch.layer.osc2.ring_mod.0, default.osc2.ring_mod.0);
+        assert_eq!(patch.layer.fm.algorithm, 5);
+        assert_eq!(patch.layer.fm.operators[1].ratio, 3.5);
+        assert_eq!(patch.layer.fm.operators[1].level.0, 0.75);
+        assert_eq!(patch.layer.fm.operators[1].feedback.0, 0.25);
+        assert_eq!(patch.layer.fm.operators[1].env.decay.0, 200.0);
+        assert_eq!(patch.layer.fm.operators[1].env.sustain.0, default.fm.operators[1].env.sustain.0);
+        assert_eq!(patch.layer.fm.operators[0], default.fm.operators[0]);
+        assert_eq!(patch.wavetable.as_deref(), Some("tables/pad.wav"));
+        assert_eq!(patch.layer.unison.voices, 7);
+        assert_eq!(patch.layer.unison.detune.0, 12.0);
+        assert_eq!(patch.layer.unison.spread.0, default.unison.spread.0);
+        assert_eq!(patch.layer.unison.phase, sc::UnisonPhase::Random);
+        assert_eq!(patch.layer.noise.0, 0.5);
+        assert_eq!(patch.layer.pan.0, -0.25);
+        assert_eq!(patch.layer.lpf.freq.0, 1500.0);
+        assert_eq!(patch.layer.amp_env.attack.0, 1000.0);
+        assert_eq!(patch.layer.amp_env.decay.0, default.amp_env.decay.0);
+        assert_eq!(patch.layer.amp_env.sustain.0, 0.75);
+        assert_eq!(patch.layer.velocity.curve, sc::VelocityCurve::Hard);
+        assert_eq!(patch.layer.velocity.sensitivity.0, default.velocity.sensitivity.0);
+        assert_eq!(patch.layer.modulations.mod_env_to_lpf_freq.0, -2.0);
+        assert_eq!(patch.layer.modulations.velocity_to_mod_env.0, 0.5);
+        assert_eq!(patch.layer.modulations.pitch_bend_range.0, 12.0);
+        assert_eq!(patch.layer.modulations.mod_wheel_to_lpf_freq.0, 3.0);
+        assert_eq!(patch.layer.modulations.mod_wheel_to_wavetable_position.0, -0.5);
+        assert_eq!(patch.layer.modulations.mod_env_to_pulse_width.0, 0.5);
+        assert_eq!(patch.layer.modulations.mod_env_to_osc_freq.0, default.modulations.mod_env_to_osc_freq.0);
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let source = include_str!("../../../../example.synth2");
+        parse(source).expect("parse");
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let source = include_str!("../../../../example.synth2");
+        let patch = parse(source).expect("parse");
+        let json = to_json(&patch).expect("json");
+        let patch2 = parse_json(&json).expect("parse json");
+        assert_eq!(patch, patch2);
+
+        let patch = Patch {
+            name: "default".to_string(),
+            layer: Synth::default_config(),
+            wavetable: None,
+        };
+        let json = to_json(&patch).expect("json");
+        let patch2 = parse_json(&json).expect("parse json");
+        assert_eq!(patch, patch2);
+    }
+
+    #[test]
+    fn test_json_wavetable() {
+        let mut patch = parse(include_str!("../../../../example.synth2")).expect("parse");
+        let json = to_json(&patch).expect("json");
+        assert!(!json.contains("wavetable\""));
+
+        patch.wavetable = Some("pad.wav".to_string());
+        let json = to_json(&patch).expect("json");
+        assert_eq!(parse_json(&json).expect("parse json"), patch);
+    }
+
+    #[test]
+    fn test_json_out_of_range() {
+        let patch = Patch {
+            name: "default".to_string(),
+            layer: Synth::default_config(),
+            wavetable: None,
+        };
+        let json = to_json(&patch).expect("json");
+        let mut value: serde_json::Value = serde_json::from_str(&json).expect("value");
+
+        value["layer"]["amp_env"]["sustain"] = serde_json::json!(1.5);
+        let err = parse_json(&value.to_string()).expect_err("error");
+        assert!(err.message.contains("out of [0, 1] range"), "{}", err);
+
+        value["layer"]["amp_env"]["sustain"] = serde_json::json!(0.5);
+        value["layer"]["modulations"]["mod_env_to_lpf_freq"] = serde_json::json!(-10.5);
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["modulations"]["mod_env_to_lpf_freq"] = serde_json::json!(-10.0);
+        value["layer"]["lpf"]["freq"] = serde_json::json!(0.0);
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["lpf"]["freq"] = serde_json::json!(100.0);
+        value["layer"]["osc"]["kind"] = serde_json::json!("supersaw");
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["osc"]["kind"] = serde_json::json!("sine");
+        value["layer"]["unison"]["voices"] = serde_json::json!(0);
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["unison"]["voices"] = serde_json::json!(16);
+        parse_json(&value.to_string()).expect("parse json");
+
+        value["layer"]["fm"]["algorithm"] = serde_json::json!(9);
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["fm"]["algorithm"] = serde_json::json!(8);
+        value["layer"]["fm"]["operators"][2]["ratio"] = serde_json::json!(0.0);
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["fm"]["operators"][2]["ratio"] = serde_json::json!(0.5);
+        parse_json(&value.to_string()).expect("parse json");
+
+        value["layer"]["osc2"]["kind"] = serde_json::json!("fm");
+        assert!(parse_json(&value.to_string()).is_err());
+
+        value["layer"]["osc2"]["kind"] = serde_json::json!("sine");
+        parse_json(&value.to_string()).expect("parse json");
+
+        value["layer"].as_object_mut().expect("object").remove("unison");
+        value["layer"].as_object_mut().expect("object").remove("osc2");
+        value["layer"].as_object_mut().expect("object").remove("fm");
+        value["layer"].as_object_mut().expect("object").remove("velocity");
+        value["layer"]["modulations"].as_object_mut().expect("object").remove("velocity_to_lpf_freq");
+        value["layer"]["modulations"].as_object_mut().expect("object").remove("pitch_bend_range");
+        value["layer"]["osc"].as_object_mut().expect("object").remove("pulse_width");
+        let patch = parse_json(&value.to_string()).expect("parse json");
+        assert_eq!(patch.layer.unison, sc::Unison::default());
+        assert_eq!(patch.layer.osc2, sc::Oscillator2::default());
+        assert_eq!(patch.layer.fm, sc::Fm::default());
+        assert_eq!(patch.layer.velocity, sc::Velocity::default());
+        assert_eq!(patch.layer.modulations.velocity_to_lpf_freq.0, 0.0);
+        assert_eq!(patch.layer.modulations.pitch_bend_range.0, 2.0);
+        assert_eq!(patch.layer.osc.pulse_width.0, 0.5);
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let source = "synth test {\n    lpf {\n        resonance = 1\n    }\n}";
+        let err = parse(source).expect_err("error");
+        assert_eq!((err.line, err.column), (3, 9));
+        assert_eq!(err.message, "unknown key `resonance` in `lpf`");
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let source = "synth test {\n    amp_env { sustain = 1.5 }\n}";
+        let err = parse(source).expect_err("error");
+        assert_eq!((err.line, err.column), (2, 25));
+
+        let source = "synth test { modulations { mod_env_to_osc_freq = -11 } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { amp_env { attack = 10hz } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { unison { voices = 17 } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { unison { voices = 2.5 } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { wavetable = pad }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { osc2 { sync = 1 } }";
+        let err = parse(source).expect_err("error");
+        assert_eq!(err.message, "`sync` expects true or false");
+
+        let source = "synth test { osc2 { coarse = 49 } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { osc2 { kind = fm } }";
+        let err = parse(source).expect_err("error");
+        assert_eq!(err.message, "`osc2` can't be `fm`, only `osc` can run the operators");
+
+        let source = "synth test { fm { algorithm = 9 } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { fm { op1 { ratio = 0 } } }";
+        assert!(parse(source).is_err());
+
+        let source = "synth test { fm { op5 { level = 1 } } }";
+        let err = parse(source).expect_err("error");
+        assert_eq!(err.message, "unknown key `op5` in `fm`");
+
+        let source = "synth test {\n    wavetable = \"pad.wav\n}";
+        let err = parse(source).expect_err("error");
+        assert_eq!((err.line, err.column), (2, 17));
+        assert_eq!(err.message, "unterminated string");
+    }
+}
@@ -1,8 +1,10 @@
 use crate::old::simdtest;
 use std::simd::{Simd, u32x16, f32x16};
+use std::simd::num::SimdFloat;
 use sleef::Sleef; // pow
 use super::filters::*;
 use super::oscillators::phase_accumulating::*;
+use super::oscillators::{phased, unison, hard_sync, BandLimit};
 use super::hashnoise::*;
 use super::render_plan as rp;
 use super::state as st;
@@ -10,167 +12,506 @@ use super::static_config as sc;
 use super::units::*;
 use super::math;
 use super::envelopes;
+use super::tables;
+use super::controllers::ControllerFrame;
+use super::wavetable::Wavetable;
+use super::fm;
+
+/// Per-voice values that modulate the patch over a buffer.
+#[derive(Copy, Clone)]
+pub struct VoiceInputs<'this> {
+    pub pitch: Hz,
+    pub velocity: Unipolar<1>,
+    /// The controllers as they apply to this voice, one per frame.
+    pub controllers: &'this [ControllerFrame],
+    /// Frames since the voice started, at the first frame.
+    pub offset: u32,
+    /// The offset the voice was released at, once released.
+    pub release_offset: Option<u32>,
+}
+
+impl VoiceInputs<'_> {
+    /// Frames since the voice started, at `frame`.
+    fn offset(&self, frame: usize) -> u32 {
+        u32::try_from(frame).ok()
+            .and_then(|frame| self.offset.checked_add(frame))
+            .expect("overflow")
+    }
+
+    /// The inputs from `frame` on.
+    fn skip(&self, frame: usize) -> Self {
+        VoiceInputs {
+            controllers: &self.controllers[frame..],
+            offset: self.offset(frame),
+            ..*self
+        }
+    }
+}
 
 pub fn process_layer_buf_simd(
     static_config: &sc::Layer,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
-    buf: &mut [f32],
+    buf_left: &mut [f32],
+    buf_right: &mut [f32],
 ) {
-    let mut offset = offset;
+    assert_eq!(buf_left.len(), buf_right.len());
+    assert_eq!(buf_left.len(), inputs.controllers.len());
+
+    let mut frame = 0;
 
-    let mut chunks = buf.array_chunks_mut::<16>();
+    let mut chunks_left = buf_left.array_chunks_mut::<16>();
+    let mut chunks_right = buf_right.array_chunks_mut::<16>();
 
-    while let Some(chunk) = chunks.next() {
-        *chunk = process_layer_x16(
+    while let (Some(chunk_left), Some(chunk_right)) = (chunks_left.next(), chunks_right.next()) {
+        (*chunk_left, *chunk_right) = process_layer_x16(
             static_config,
+            wavetable,
             state,
-            pitch,
+            inputs,
+            frame,
             sample_rate,
-            offset,
-            release_offset
         );
-        offset = offset.checked_add(16).expect("overflow");
+        frame += 16;
     }
 
-    let remainder = chunks.into_remainder();
     process_layer_buf_sisd(
         static_config,
+        wavetable,
         state,
-        pitch,
+        inputs.skip(frame),
         sample_rate,
-        offset,
-        release_offset,
-        remainder,
+        chunks_left.into_remainder(),
+        chunks_right.into_remainder(),
     );
 }
 
 pub fn process_layer_buf_sisd(
     static_config: &sc::Layer,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
-    buf: &mut [f32],
+    buf_left: &mut [f32],
+    buf_right: &mut [f32],
 ) {
-    let mut offset = offset;
+    assert_eq!(buf_left.len(), buf_right.len());
+    assert_eq!(buf_left.len(), inputs.controllers.len());
 
-    for byte in buf {
-        *byte = process_layer(
+    let frames = buf_left.iter_mut().zip(buf_right.iter_mut()).enumerate();
+    for (frame, (left, right)) in frames {
+        (*left, *right) = process_layer(
             static_config,
+            wavetable,
             state,
-            pitch,
+            inputs,
+            frame,
             sample_rate,
-            offset,
-            release_offset,
         );
-        offset = offset.checked_add(1).expect("overflow");
     }
 }
 
 pub fn process_layer(
     static_config: &sc::Layer,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
+    frame: usize,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
-) -> f32 {
-    let render_plan = prepare_frame(static_config, pitch, sample_rate, offset, release_offset);
-    let sample = sample_voice(&render_plan, state, offset);
+) -> (f32, f32) {
+    let render_plan = prepare_frame(static_config, inputs, frame, sample_rate);
+    let sample = sample_voice(&render_plan, wavetable, state, inputs.offset(frame));
     sample
 }
 
+/// Sixteen frames starting at `frame`.
 pub fn process_layer_x16(
     static_config: &sc::Layer,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
+    frame: usize,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
-) -> [f32; 16] {
-    let render_plan = prepare_frame_x16(static_config, pitch, sample_rate, offset, release_offset);
-    let sample = sample_voice_x16(render_plan, state, offset);
+) -> ([f32; 16], [f32; 16]) {
+    let render_plan = prepare_frame_x16(static_config, inputs, frame, sample_rate);
+    let sample = sample_voice_x16(render_plan, wavetable, state, inputs.offset(frame));
     sample
 }
 
 fn prepare_frame(
     layer: &sc::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
+    frame: usize,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
 ) -> rp::Layer {
+    let controller = inputs.controllers[frame];
+    let offset = inputs.offset(frame);
+    let release_offset = inputs.release_offset;
+    let velocity = layer.velocity.curve.apply(inputs.velocity);
     let amp_env_sample = sample_envelope(layer.amp_env, sample_rate, offset, release_offset);
     let mod_env_sample = sample_envelope(layer.mod_env, sample_rate, offset, release_offset);
+    let mod_env_sample = Unipolar(mod_env_sample.0 * mod_env_depth(layer, velocity));
+    let (osc_octaves, lpf_octaves) = controller_octaves(layer, controller);
     let modulated_osc_freq =
-        modulate_freq_unipolar(pitch, mod_env_sample, layer.modulations.mod_env_to_osc_freq);
+        modulate_freq_unipolar(inputs.pitch, mod_env_sample, layer.modulations.mod_env_to_osc_freq);
+    let modulated_osc_freq = Hz(modulated_osc_freq.0 * 2_f32.powf(osc_octaves));
     let modulated_lpf_freq = modulate_freq_unipolar(
-        layer.lpf.freq,
+        velocity_lpf_freq(layer, velocity),
         mod_env_sample,
         layer.modulations.mod_env_to_lpf_freq,
     );
+    let modulated_lpf_freq = Hz(modulated_lpf_freq.0 * 2_f32.powf(lpf_octaves));
+    let osc_period = modulated_osc_freq.as_samples(sample_rate);
+    let fm = prepare_fm(layer, osc_period, sample_rate, offset, release_offset);
     rp::Layer {
         osc: rp::Oscillator {
-            period: modulated_osc_freq.as_samples(sample_rate),
-            kind: match layer.osc.kind {
-                sc::OscillatorKind::Square => rp::OscillatorKind::Square,
-                sc::OscillatorKind::Saw => rp::OscillatorKind::Saw,
-                sc::OscillatorKind::Triangle => rp::OscillatorKind::Triangle,
-                sc::OscillatorKind::Sine => rp::OscillatorKind::Sine,
-            },
+            period: osc_period,
+            kind: oscillator_kind(layer.osc.kind),
+            band_limit: band_limit(layer.osc.band_limit),
+            wavetable_position: wavetable_position(layer, layer.osc.wavetable_position, mod_env_sample, controller),
+            pulse_width: pulse_width(layer, layer.osc.pulse_width, mod_env_sample, controller),
             gain: layer.osc.gain,
         },
+        osc2: prepare_osc2(layer, osc_period, mod_env_sample, controller),
+        fm,
+        // The operators play without unison.
+        unison: if fm.is_some() { None } else { prepare_unison(&layer.unison) },
         noise: layer.noise,
         lpf: rp::LowPassFilter {
             freq: modulated_lpf_freq,
             sample_rate,
         },
-        gain: amp_env_sample,
+        gain: Unipolar(amp_env_sample.0 * velocity_gain(layer, inputs.velocity)),
+        pan: voice_pan(layer, mod_env_sample, controller),
     }
 }
 
 fn prepare_frame_x16(
     layer: &sc::Layer,
-    pitch: Hz,
+    inputs: VoiceInputs,
+    frame: usize,
     sample_rate: SampleRateKhz,
-    offset: u32,
-    release_offset: Option<u32>,
 ) -> rp::LayerX<16> {
+    let controllers: &[ControllerFrame; 16] = inputs.controllers[frame..][..16]
+        .try_into()
+        .expect("16 frames");
+    let offset = inputs.offset(frame);
+    let release_offset = inputs.release_offset;
+    let velocity = layer.velocity.curve.apply(inputs.velocity);
     let amp_env_samples = sample_envelope_x16(layer.amp_env, sample_rate, offset, release_offset);
     let mod_env_samples = sample_envelope_x16(layer.mod_env, sample_rate, offset, release_offset);
+    let mod_env_depth = mod_env_depth(layer, velocity);
+    let mod_env_samples = mod_env_samples.map(|s| Unipolar(s.0 * mod_env_depth));
+    let controller_octaves = controllers.map(|c| controller_octaves(layer, c));
     let modulated_osc_freqs =
-        modulate_freq_unipolar_x16(pitch, mod_env_samples, layer.modulations.mod_env_to_osc_freq);
+        modulate_freq_unipolar_x16(inputs.pitch, mod_env_samples, layer.modulations.mod_env_to_osc_freq);
+    let modulated_osc_freqs = transpose_x16(modulated_osc_freqs, controller_octaves.map(|(osc, _)| osc));
     let modulated_lpf_freqs = modulate_freq_unipolar_x16(
-        layer.lpf.freq,
+        velocity_lpf_freq(layer, velocity),
         mod_env_samples,
         layer.modulations.mod_env_to_lpf_freq,
     );
+    let modulated_lpf_freqs = transpose_x16(modulated_lpf_freqs, controller_octaves.map(|(_, lpf)| lpf));
 
     let modulated_osc_periods = modulated_osc_freqs.as_samples(sample_rate);
+    let fm = prepare_fm_x16(layer, modulated_osc_periods, sample_rate, offset, release_offset);
 
     rp::LayerX {
         osc: rp::OscillatorX {
-            kind: match layer.osc.kind {
-                sc::OscillatorKind::Square => rp::OscillatorKind::Square,
-                sc::OscillatorKind::Saw => rp::OscillatorKind::Saw,
-                sc::OscillatorKind::Triangle => rp::OscillatorKind::Triangle,
-                sc::OscillatorKind::Sine => rp::OscillatorKind::Sine,
-            },
+            kind: oscillator_kind(layer.osc.kind),
+            band_limit: band_limit(layer.osc.band_limit),
             periods: modulated_osc_periods,
+            wavetable_positions: std::array::from_fn(|i| {
+                wavetable_position(layer, layer.osc.wavetable_position, mod_env_samples[i], controllers[i])
+            }),
+            pulse_widths: std::array::from_fn(|i| {
+                pulse_width(layer, layer.osc.pulse_width, mod_env_samples[i], controllers[i])
+            }),
             gain: layer.osc.gain,
         },
+        osc2: prepare_osc2_x16(layer, modulated_osc_periods, &mod_env_samples, controllers),
+        fm,
+        unison: if fm.is_some() { None } else { prepare_unison(&layer.unison) },
         noise: layer.noise,
         lpf: rp::LowPassFilterX {
             sample_rate,
             freqs: modulated_lpf_freqs,
         },
-        gains: amp_env_samples,
+        gains: {
+            let velocity_gain = velocity_gain(layer, inputs.velocity);
+            amp_env_samples.map(|s| Unipolar(s.0 * velocity_gain))
+        },
+        pans: std::array::from_fn(|i| voice_pan(layer, mod_env_samples[i], controllers[i])),
+    }
+}
+
+/// The level of a note played at `velocity`, before the amp envelope.
+pub fn velocity_gain(layer: &sc::Layer, velocity: Unipolar<1>) -> f32 {
+    let velocity = layer.velocity.curve.apply(velocity);
+    1.0 - layer.velocity.sensitivity.0 * (1.0 - velocity.0)
+}
+
+/// Scale of the mod envelope for a curved velocity.
+fn mod_env_depth(layer: &sc::Layer, velocity: Unipolar<1>) -> f32 {
+    1.0 - layer.modulations.velocity_to_mod_env.0 * (1.0 - velocity.0)
+}
+
+/// The voice's position in the stereo field, after modulation.
+fn voice_pan(layer: &sc::Layer, mod_env_sample: Unipolar<1>, controller: ControllerFrame) -> Bipolar<1> {
+    let modulations = &layer.modulations;
+    let pan = layer.pan.0
+        + mod_env_sample.0 * modulations.mod_env_to_pan.0
+        + controller.timbre.0 * modulations.timbre_to_pan.0;
+    Bipolar(pan.clamp(-1.0, 1.0))
+}
+
+/// An oscillator's position through the wavetable, modulated from `position`.
+fn wavetable_position(
+    layer: &sc::Layer,
+    position: Unipolar<1>,
+    mod_env_sample: Unipolar<1>,
+    controller: ControllerFrame,
+) -> Unipolar<1> {
+    let modulations = &layer.modulations;
+    let position = position.0
+        + mod_env_sample.0 * modulations.mod_env_to_wavetable_position.0
+        + controller.mod_wheel.0 * modulations.mod_wheel_to_wavetable_position.0
+        + controller.timbre.0 * modulations.timbre_to_wavetable_position.0;
+    Unipolar(position.clamp(0.0, 1.0))
+}
+
+/// A pulse oscillator's width, modulated from `width`.
+fn pulse_width(
+    layer: &sc::Layer,
+    width: Unipolar<1>,
+    mod_env_sample: Unipolar<1>,
+    controller: ControllerFrame,
+) -> Unipolar<1> {
+    let modulations = &layer.modulations;
+    let width = width.0
+        + mod_env_sample.0 * modulations.mod_env_to_pulse_width.0
+        + controller.mod_wheel.0 * modulations.mod_wheel_to_pulse_width.0
+        + controller.timbre.0 * modulations.timbre_to_pulse_width.0;
+    Unipolar(width.clamp(0.0, 1.0))
+}
+
+/// The second oscillator, or `None` if it's silent.
+fn prepare_osc2(
+    layer: &sc::Layer,
+    osc_period: SampleOffset,
+    mod_env_sample: Unipolar<1>,
+    controller: ControllerFrame,
+) -> Option<rp::Oscillator2> {
+    let osc2 = &layer.osc2;
+    if osc2.mix.0 == 0.0 && osc2.ring_mod.0 == 0.0 {
+        return None;
+    }
+
+    Some(rp::Oscillator2 {
+        osc: rp::Oscillator {
+            period: SampleOffset(osc_period.0 / osc2_ratio(osc2)),
+            kind: oscillator_kind(osc2.kind),
+            band_limit: band_limit(osc2.band_limit),
+            wavetable_position: wavetable_position(layer, osc2.wavetable_position, mod_env_sample, controller),
+            pulse_width: pulse_width(layer, osc2.pulse_width, mod_env_sample, controller),
+            gain: Unipolar(1.0),
+        },
+        sync: osc2.sync,
+        mix: osc2.mix,
+        ring_mod: osc2.ring_mod,
+    })
+}
+
+fn prepare_osc2_x16(
+    layer: &sc::Layer,
+    osc_periods: [SampleOffset; 16],
+    mod_env_samples: &[Unipolar<1>; 16],
+    controllers: &[ControllerFrame; 16],
+) -> Option<rp::Oscillator2X<16>> {
+    let osc2 = &layer.osc2;
+    if osc2.mix.0 == 0.0 && osc2.ring_mod.0 == 0.0 {
+        return None;
+    }
+
+    let ratio = f32x16::splat(osc2_ratio(osc2));
+    let periods = f32x16::from_array(osc_periods.map(|p| p.0)) / ratio;
+
+    Some(rp::Oscillator2X {
+        osc: rp::OscillatorX {
+            kind: oscillator_kind(osc2.kind),
+            band_limit: band_limit(osc2.band_limit),
+            periods: periods.to_array().map(SampleOffset),
+            wavetable_positions: std::array::from_fn(|i| {
+                wavetable_position(layer, osc2.wavetable_position, mod_env_samples[i], controllers[i])
+            }),
+            pulse_widths: std::array::from_fn(|i| {
+                pulse_width(layer, osc2.pulse_width, mod_env_samples[i], controllers[i])
+            }),
+            gain: Unipolar(1.0),
+        },
+        sync: osc2.sync,
+        mix: osc2.mix,
+        ring_mod: osc2.ring_mod,
+    })
+}
+
+/// The FM operators, if they play in place of the oscillator.
+fn prepare_fm(
+    layer: &sc::Layer,
+    osc_period: SampleOffset,
+    sample_rate: SampleRateKhz,
+    offset: u32,
+    release_offset: Option<u32>,
+) -> Option<rp::Fm> {
+    if layer.osc.kind != sc::OscillatorKind::Fm {
+        return None;
+    }
+
+    let env_samples = sample_operator_envelopes(&layer.fm, sample_rate, offset, release_offset);
+
+    Some(rp::Fm {
+        algorithm: fm::algorithm_number(layer.fm.algorithm),
+        operators: std::array::from_fn(|index| {
+            let operator = &layer.fm.operators[index];
+            fm::Operator {
+                period: SampleOffset(osc_period.0 / operator.ratio),
+                level: Unipolar(operator.level.0 * env_samples[index].0),
+                feedback: operator.feedback,
+            }
+        }),
+    })
+}
+
+/// Every operator's envelope at `offset`, sampled together with a lane per operator.
+fn sample_operator_envelopes(
+    fm: &sc::Fm,
+    sample_rate: SampleRateKhz,
+    offset: u32,
+    release_offset: Option<u32>,
+) -> [Unipolar<1>; sc::FM_OPERATORS] {
+    // The operators repeat to fill the lanes.
+    let envs: [sc::Adsr; 16] = std::array::from_fn(|lane| fm.operators[lane % sc::FM_OPERATORS].env);
+    let adsr = simdtest::AdsrX16 {
+        attack: f32x16::from_array(envs.map(|env| env.attack.as_samples(sample_rate).0)),
+        decay: f32x16::from_array(envs.map(|env| env.decay.as_samples(sample_rate).0)),
+        sustain: f32x16::from_array(envs.map(|env| env.sustain.0)),
+        release: f32x16::from_array(envs.map(|env| env.release.as_samples(sample_rate).0)),
+    };
+
+    let samples = adsr.sample(u32x16::splat(offset), release_offset).to_array();
+    std::array::from_fn(|index| Unipolar(samples[index]))
+}
+
+fn prepare_fm_x16(
+    layer: &sc::Layer,
+    osc_periods: [SampleOffset; 16],
+    sample_rate: SampleRateKhz,
+    offset: u32,
+    release_offset: Option<u32>,
+) -> Option<rp::FmX<16>> {
+    if layer.osc.kind != sc::OscillatorKind::Fm {
+        return None;
+    }
+
+    let osc_periods = f32x16::from_array(osc_periods.map(|p| p.0));
+
+    Some(rp::FmX {
+        algorithm: fm::algorithm_number(layer.fm.algorithm),
+        operators: layer.fm.operators.map(|operator| {
+            let periods = osc_periods / f32x16::splat(operator.ratio);
+            // A silent operator's envelope doesn't matter.
+            let levels = if operator.level.0 == 0.0 {
+                f32x16::splat(0.0)
+            } else {
+                let env_samples = sample_envelope_x16(operator.env, sample_rate, offset, release_offset);
+                f32x16::from_array(env_samples.map(|s| s.0)) * f32x16::splat(operator.level.0)
+            };
+            fm::OperatorX {
+                periods: periods.to_array().map(SampleOffset),
+                levels: levels.to_array().map(Unipolar),
+                feedback: operator.feedback,
+            }
+        }),
+    })
+}
+
+/// The second oscillator's frequency relative to the first.
+fn osc2_ratio(osc2: &sc::Oscillator2) -> f32 {
+    Cents(osc2.coarse.0 * 100.0 + osc2.fine.0).as_ratio()
+}
+
+/// Octaves of oscillator and filter modulation from the controllers.
+fn controller_octaves(layer: &sc::Layer, controller: ControllerFrame) -> (f32, f32) {
+    let modulations = &layer.modulations;
+    let pitch_bend = controller.pitch_bend.0 * modulations.pitch_bend_range.0 + controller.note_pitch_bend;
+    let osc_octaves = pitch_bend / 12.0
+        + controller.mod_wheel.0 * modulations.mod_wheel_to_osc_freq.0
+        + controller.pressure.0 * modulations.pressure_to_osc_freq.0
+        + controller.poly_pressure.0 * modulations.poly_pressure_to_osc_freq.0;
+    let lpf_octaves = controller.mod_wheel.0 * modulations.mod_wheel_to_lpf_freq.0
+        + controller.pressure.0 * modulations.pressure_to_lpf_freq.0
+        + controller.timbre.0 * modulations.timbre_to_lpf_freq.0
+        + controller.poly_pressure.0 * modulations.poly_pressure_to_lpf_freq.0;
+    (osc_octaves, lpf_octaves)
+}
+
+fn transpose_x16(freqs: [Hz; 16], octaves: [f32; 16]) -> [Hz; 16] {
+    let freqs = f32x16::from_array(freqs.map(|f| f.0));
+    let octaves = f32x16::from_array(octaves);
+    let two = f32x16::splat(2.0);
+    let freqs = two.pow(octaves) * freqs;
+    freqs.to_array().map(Hz)
+}
+
+/// The filter cutoff for a curved velocity, before envelope modulation.
+fn velocity_lpf_freq(layer: &sc::Layer, velocity: Unipolar<1>) -> Hz {
+    modulate_freq_unipolar(layer.lpf.freq, velocity, layer.modulations.velocity_to_lpf_freq)
+}
+
+/// Spread the unison lanes evenly over the detune and stereo spread.
+///
+/// Returns `None` for a single voice, which doesn't need the lanes.
+fn prepare_unison(unison: &sc::Unison) -> Option<rp::Unison> {
+    let voices = unison.voices.clamp(1, sc::MAX_UNISON);
+    if voices == 1 {
+        return None;
+    }
+
+    // Keep the total level close to a single voice.
+    let level = 1.0 / (voices as f32).sqrt();
+
+    let mut ratios = [1.0; 16];
+    let mut gains_left = [0.0; 16];
+    let mut gains_right = [0.0; 16];
+
+    for lane in 0..voices as usize {
+        // From -1 for the first lane to 1 for the last.
+        let position = lane as f32 * 2.0 / (voices - 1) as f32 - 1.0;
+        ratios[lane] = Cents(unison.detune.0 * position).as_ratio();
+        let (left, right) = pan_gains(Bipolar(unison.spread.0 * position));
+        gains_left[lane] = left * level;
+        gains_right[lane] = right * level;
     }
+
+    Some(rp::Unison {
+        ratios,
+        gains_left,
+        gains_right,
+        stereo: unison.spread.0 > 0.0,
+        phase: match unison.phase {
+            sc::UnisonPhase::Fixed(phase) => rp::UnisonPhase::Fixed(phase),
+            sc::UnisonPhase::Random => rp::UnisonPhase::Random,
+        },
+    })
+}
+
+/// Left and right gains, with the center at full level in both channels.
+pub fn pan_gains(pan: Bipolar<1>) -> (f32, f32) {
+    let left = (1.0 - pan.0).min(1.0);
+    let right = (1.0 + pan.0).min(1.0);
+    (left, right)
 }
 
 pub fn sample_envelope(
@@ -205,11 +546,34 @@ pub fn sample_envelope_x16(
 
     let samples = adsr.sample(offsets, release_offset);
     let samples = samples.to_array();
-    let samples = samples.map(|s| Unipolar(s));
+    let samples = samples.map(Unipolar);
 
     samples
 }
 
+/// Whether the envelope has reached its end stage at `offset`.
+///
+/// Follows `AdsrX16` in not starting the release until the decay has finished.
+pub fn envelope_finished(
+    adsr_config: sc::Adsr,
+    sample_rate: SampleRateKhz,
+    offset: u32,
+    release_offset: Option<u32>,
+) -> bool {
+    let Some(release_offset) = release_offset else {
+        return false;
+    };
+
+    let attack = adsr_config.attack.as_samples(sample_rate).0;
+    let decay = adsr_config.decay.as_samples(sample_rate).0;
+    let release = adsr_config.release.as_samples(sample_rate).0;
+
+    let release_offset = (release_offset as f32).max(attack + decay);
+    let end_offset = release_offset + release;
+
+    offset as f32 >= end_offset
+}
+
 fn offsets_x16(offset: u32) -> [u32; 16] {
     let indexes = math::indexes_u32::<16>();
     let indexes = u32x16::from_array(indexes);
@@ -244,136 +608,775 @@ fn modulate_freq_unipolar_x16(
     let freq = two.pow(modulation_amount_) * freq;
 
     let freq = freq.to_array();
-    let freq = freq.map(|f| Hz(f));
+    let freq = freq.map(Hz);
 
     freq
 }
 
 pub fn sample_voice(
     render_plan: &rp::Layer,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
     offset: u32,
+) -> (f32, f32) {
+    // The first oscillator's phase this frame, for the second to sync to.
+    let osc_phase = state.osc.phase_accum.unwrap_or(Unipolar(0.0));
+    let (osc_left, osc_right) = match (&render_plan.fm, &render_plan.unison) {
+        (Some(fm), _) => {
+            let sample = fm::FmOscillator {
+                state: &mut state.fm,
+                algorithm: fm.algorithm,
+                operators: fm.operators,
+            }.sample().0;
+            (sample, sample)
+        }
+        (None, None) => {
+            let sample = sample_oscillator(&render_plan.osc, wavetable, &mut state.osc);
+            (sample, sample)
+        }
+        (None, Some(unison)) => sample_unison(&render_plan.osc, wavetable, unison, state),
+    };
+    if render_plan.fm.is_some() || render_plan.unison.is_some() {
+        state.osc.phase_accum = Some(accum_phase(osc_phase, render_plan.osc.period));
+    }
+    let (osc_left, osc_right) = match &render_plan.osc2 {
+        None => {
+            // Restarted when it sounds again, rather than from a stale phase.
+            state.osc2 = st::OscillatorState::default();
+            (osc_left, osc_right)
+        }
+        Some(osc2) => {
+            let sample = sample_oscillator2(osc2, osc_phase, render_plan.osc.period, wavetable, &mut state.osc2);
+            (
+                mix_oscillators(osc_left, sample, osc2.mix, osc2.ring_mod),
+                mix_oscillators(osc_right, sample, osc2.mix, osc2.ring_mod),
+            )
+        }
+    };
+    let osc_gain = render_plan.osc.gain.0;
+
+    let noise_sample = HashNoise {
+        seed: state.noise.seed,
+    }.sample(SampleOffset(offset as f32));
+    let noise_sample = noise_sample.0 * render_plan.noise.0;
+
+    let sample_left = osc_left * osc_gain + noise_sample;
+    let sample_right = osc_right * osc_gain + noise_sample;
+
+    let stereo = render_plan.unison.map(|u| u.stereo).unwrap_or(false);
+
+    let mut lpf = LowPassFilter {
+        state: &mut state.lpf,
+        sample_rate: render_plan.lpf.sample_rate,
+        freq: render_plan.lpf.freq,
+    };
+    let sample_left = lpf.process(sample_left);
+    let sample_right = if stereo {
+        LowPassFilter {
+            state: &mut state.lpf_right,
+            sample_rate: render_plan.lpf.sample_rate,
+            freq: render_plan.lpf.freq,
+        }.process(sample_right)
+    } else {
+        state.lpf_right = state.lpf;
+        sample_left
+    };
+
+    let gain = render_plan.gain.0;
+    let (pan_left, pan_right) = pan_gains(render_plan.pan);
+    (sample_left * gain * pan_left, sample_right * gain * pan_right)
+}
+
+fn oscillator_kind(kind: sc::OscillatorKind) -> rp::OscillatorKind {
+    match kind {
+        sc::OscillatorKind::Square => rp::OscillatorKind::Square,
+        sc::OscillatorKind::Pulse => rp::OscillatorKind::Pulse,
+        sc::OscillatorKind::Saw => rp::OscillatorKind::Saw,
+        sc::OscillatorKind::Triangle => rp::OscillatorKind::Triangle,
+        sc::OscillatorKind::Sine => rp::OscillatorKind::Sine,
+        sc::OscillatorKind::Wavetable => rp::OscillatorKind::Wavetable,
+        // The operators play in place of the first oscillator, so it's
+        // never sampled, and the second can't be `fm`.
+        sc::OscillatorKind::Fm => rp::OscillatorKind::Sine,
+    }
+}
+
+fn band_limit(band_limit: sc::BandLimit) -> BandLimit {
+    match band_limit {
+        sc::BandLimit::Naive => BandLimit::Naive,
+        sc::BandLimit::PolyBlep => BandLimit::PolyBlep,
+    }
+}
+
+fn sample_oscillator(
+    osc: &rp::Oscillator,
+    wavetable: &Wavetable,
+    state: &mut st::OscillatorState,
 ) -> f32 {
-    let osc_sample = match render_plan.osc.kind {
+    let period = osc.period;
+    let band_limit = osc.band_limit;
+    let sample = match osc.kind {
         rp::OscillatorKind::Square => {
             SquareOscillator {
-                state: &mut state.osc,
-                period: render_plan.osc.period,
+                state,
+                period,
+                phase: Unipolar(0.0),
+                band_limit,
+            }.sample()
+        },
+        rp::OscillatorKind::Pulse => {
+            PulseOscillator {
+                state,
+                period,
                 phase: Unipolar(0.0),
+                width: osc.pulse_width,
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Saw => {
             SawOscillator {
-                state: &mut state.osc,
-                period: render_plan.osc.period,
+                state,
+                period,
                 phase: Unipolar(0.0),
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Triangle => {
             TriangleOscillator {
-                state: &mut state.osc,
-                period: render_plan.osc.period,
+                state,
+                period,
                 phase: Unipolar(0.0),
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Sine => {
             SineOscillator {
-                state: &mut state.osc,
-                period: render_plan.osc.period,
+                state,
+                period,
                 phase: Unipolar(0.0),
             }.sample()
         },
+        rp::OscillatorKind::Wavetable => {
+            WavetableOscillator {
+                wavetable,
+                state,
+                period,
+                phase: Unipolar(0.0),
+                position: osc.wavetable_position,
+            }.sample()
+        },
     };
-    let osc_sample = osc_sample.0 * render_plan.osc.gain.0;
+    sample.0
+}
 
-    let noise_sample = HashNoise {
-        seed: state.noise.seed,
-    }.sample(SampleOffset(offset as f32));
-    let noise_sample = noise_sample.0 * render_plan.noise.0;
+/// One frame of every unison lane, mixed to left and right.
+fn sample_unison(
+    osc: &rp::Oscillator,
+    wavetable: &Wavetable,
+    unison: &rp::Unison,
+    state: &mut st::Layer,
+) -> (f32, f32) {
+    let ratios = f32x16::from_array(unison.ratios);
+    let periods = f32x16::splat(osc.period.0) / ratios;
+    let period = periods.to_array().map(SampleOffset);
 
-    let sample = osc_sample + noise_sample;
+    let seed = state.noise.seed;
+    state.unison.start(|| unison_phases(unison.phase, seed));
+    let phase = unison::UnisonPhases {
+        state: &mut state.unison,
+        period,
+    }.next();
 
-    let mut lpf = LowPassFilter {
-        state: &mut state.lpf,
-        sample_rate: render_plan.lpf.sample_rate,
-        freq: render_plan.lpf.freq,
+    let lanes = rp::OscillatorX {
+        kind: osc.kind,
+        band_limit: osc.band_limit,
+        periods: period,
+        wavetable_positions: [osc.wavetable_position; 16],
+        pulse_widths: [osc.pulse_width; 16],
+        gain: osc.gain,
     };
-    let sample = lpf.process(sample);
-    let sample = sample * render_plan.gain.0;
-    sample
+    let samples = f32x16::from_array(sample_phased_x16(&lanes, wavetable, phase));
+
+    let left = samples * f32x16::from_array(unison.gains_left);
+    let right = samples * f32x16::from_array(unison.gains_right);
+
+    (left.reduce_sum(), right.reduce_sum())
+}
+
+/// One frame of the second oscillator, synced to the first at `osc_phase` if enabled.
+fn sample_oscillator2(
+    osc2: &rp::Oscillator2,
+    osc_phase: Unipolar<1>,
+    osc_period: SampleOffset,
+    wavetable: &Wavetable,
+    state: &mut st::OscillatorState,
+) -> f32 {
+    if !osc2.sync {
+        return sample_oscillator(&osc2.osc, wavetable, state);
+    }
+
+    let phase = hard_sync::SyncedPhase {
+        leader_phase: osc_phase,
+        leader_period: osc_period,
+        state,
+        period: osc2.osc.period,
+    }.next();
+
+    sample_phased(&osc2.osc, wavetable, phase)
+}
+
+/// One frame of an oscillator at `phase`, without advancing any state.
+fn sample_phased(
+    osc: &rp::Oscillator,
+    wavetable: &Wavetable,
+    phase: Unipolar<1>,
+) -> f32 {
+    let period = osc.period;
+    let band_limit = osc.band_limit;
+    let offset = SampleOffset(0.0);
+    let sample = match osc.kind {
+        rp::OscillatorKind::Square => {
+            phased::SquareOscillator { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Pulse => {
+            let width = osc.pulse_width;
+            phased::PulseOscillator { period, phase, width, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Saw => {
+            phased::SawOscillator { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Triangle => {
+            phased::TriangleOscillator { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Sine => {
+            phased::TableOscillator { table: &tables::SIN_TABLE, period, phase }.sample(offset)
+        },
+        rp::OscillatorKind::Wavetable => {
+            let position = osc.wavetable_position;
+            phased::WavetableOscillator { wavetable, period, phase, position }.sample(offset)
+        },
+    };
+    sample.0
+}
+
+/// Sixteen oscillator samples, each lane at its own period and phase.
+fn sample_phased_x16(
+    osc: &rp::OscillatorX<16>,
+    wavetable: &Wavetable,
+    phase: [Unipolar<1>; 16],
+) -> [f32; 16] {
+    let period = osc.periods;
+    let band_limit = osc.band_limit;
+    let offset = [SampleOffset(0.0); 16];
+    let samples = match osc.kind {
+        rp::OscillatorKind::Square => {
+            phased::SquareOscillatorX16 { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Pulse => {
+            let width = osc.pulse_widths;
+            phased::PulseOscillatorX16 { period, phase, width, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Saw => {
+            phased::SawOscillatorX16 { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Triangle => {
+            phased::TriangleOscillatorX16 { period, phase, band_limit }.sample(offset)
+        },
+        rp::OscillatorKind::Sine => {
+            phased::TableOscillatorX16 { table: &tables::SIN_TABLE, period, phase }.sample(offset)
+        },
+        rp::OscillatorKind::Wavetable => {
+            let position = osc.wavetable_positions;
+            phased::WavetableOscillatorX16 { wavetable, period, phase, position }.sample(offset)
+        },
+    };
+    samples.map(|s| s.0)
+}
+
+/// Crossfade from the first oscillator to the second, then to their product.
+fn mix_oscillators(osc: f32, osc2: f32, mix: Unipolar<1>, ring_mod: Unipolar<1>) -> f32 {
+    let mixed = osc + (osc2 - osc) * mix.0;
+    mixed + (osc * osc2 - mixed) * ring_mod.0
+}
+
+fn mix_oscillators_x16(osc: f32x16, osc2: f32x16, mix: Unipolar<1>, ring_mod: Unipolar<1>) -> f32x16 {
+    let mix = f32x16::splat(mix.0);
+    let ring_mod = f32x16::splat(ring_mod.0);
+    let mixed = osc + (osc2 - osc) * mix;
+    mixed + (osc * osc2 - mixed) * ring_mod
+}
+
+/// The phase of each unison lane when the voice starts.
+fn unison_phases(phase: rp::UnisonPhase, seed: u32) -> [Unipolar<1>; 16] {
+    match phase {
+        rp::UnisonPhase::Fixed(phase) => [phase; 16],
+        rp::UnisonPhase::Random => {
+            let offsets = offsets_x16(0).map(|o| SampleOffset(o as f32));
+            let noise = HashNoiseX16 { seed }.sample(offsets);
+            noise.map(|n| Unipolar(((n.0 + 1.0) / 2.0).min(0.999)))
+        }
+    }
 }
 
 pub fn sample_voice_x16(
     render_plan: rp::LayerX<16>,
+    wavetable: &Wavetable,
     state: &mut st::Layer,
     offset: u32,
+) -> ([f32; 16], [f32; 16]) {
+    // The first oscillator's phases these frames, for the second to sync to.
+    let osc_phase = state.osc.phase_accum.unwrap_or(Unipolar(0.0));
+    let (osc_phases, next_osc_phase) = accum_phase_x16(osc_phase, render_plan.osc.periods);
+    let (osc_left, osc_right) = match (&render_plan.fm, &render_plan.unison) {
+        (Some(fm), _) => {
+            let samples = fm::FmOscillatorX16 {
+                state: &mut state.fm,
+                algorithm: fm.algorithm,
+                operators: fm.operators,
+            }.sample();
+            let samples = f32x16::from_array(samples.map(|s| s.0));
+            (samples, samples)
+        }
+        (None, None) => {
+            let samples = sample_oscillator_x16(&render_plan.osc, wavetable, &mut state.osc);
+            let samples = f32x16::from_array(samples);
+            (samples, samples)
+        }
+        (None, Some(unison)) => {
+            let mut left = [0.0; 16];
+            let mut right = [0.0; 16];
+            for i in 0..16 {
+                let osc = rp::Oscillator {
+                    period: render_plan.osc.periods[i],
+                    kind: render_plan.osc.kind,
+                    band_limit: render_plan.osc.band_limit,
+                    wavetable_position: render_plan.osc.wavetable_positions[i],
+                    pulse_width: render_plan.osc.pulse_widths[i],
+                    gain: render_plan.osc.gain,
+                };
+                (left[i], right[i]) = sample_unison(&osc, wavetable, unison, state);
+            }
+            (f32x16::from_array(left), f32x16::from_array(right))
+        }
+    };
+    if render_plan.fm.is_some() || render_plan.unison.is_some() {
+        state.osc.phase_accum = Some(next_osc_phase);
+    }
+    let (osc_left, osc_right) = match &render_plan.osc2 {
+        None => {
+            // Restarted when it sounds again, rather than from a stale phase.
+            state.osc2 = st::OscillatorState::default();
+            (osc_left, osc_right)
+        }
+        Some(osc2) => {
+            let samples = sample_oscillator2_x16(osc2, osc_phases, render_plan.osc.periods, wavetable, &mut state.osc2);
+            let samples = f32x16::from_array(samples);
+            (
+                mix_oscillators_x16(osc_left, samples, osc2.mix, osc2.ring_mod),
+                mix_oscillators_x16(osc_right, samples, osc2.mix, osc2.ring_mod),
+            )
+        }
+    };
+    let osc_gain = f32x16::splat(render_plan.osc.gain.0);
+
+    let offsets = offsets_x16(offset);
+    let offsets = offsets.map(|o| SampleOffset(o as f32));
+    let noise_samples = HashNoiseX16 {
+        seed: state.noise.seed,
+    }.sample(offsets);
+    let noise_samples = noise_samples.map(|s| s.0);
+    let noise_samples = {
+        f32x16::from_array(noise_samples)
+            * f32x16::splat(render_plan.noise.0)
+    };
+
+    let samples_left = (osc_left * osc_gain + noise_samples).to_array();
+    let samples_right = (osc_right * osc_gain + noise_samples).to_array();
+
+    let stereo = render_plan.unison.map(|u| u.stereo).unwrap_or(false);
+
+    let sample_rate = render_plan.lpf.sample_rate;
+    let lpf_freqs = render_plan.lpf.freqs;
+
+    let samples_left = filter_x16(&mut state.lpf, sample_rate, lpf_freqs, samples_left);
+    let samples_right = if stereo {
+        filter_x16(&mut state.lpf_right, sample_rate, lpf_freqs, samples_right)
+    } else {
+        state.lpf_right = state.lpf;
+        samples_left
+    };
+
+    let gains = render_plan.gains.map(|g| g.0);
+    let gains = f32x16::from_array(gains);
+    let pan_gains = render_plan.pans.map(pan_gains);
+    let pan_left = f32x16::from_array(pan_gains.map(|(left, _)| left));
+    let pan_right = f32x16::from_array(pan_gains.map(|(_, right)| right));
+    let samples_left = f32x16::from_array(samples_left) * gains * pan_left;
+    let samples_right = f32x16::from_array(samples_right) * gains * pan_right;
+
+    (samples_left.to_array(), samples_right.to_array())
+}
+
+fn sample_oscillator_x16(
+    osc: &rp::OscillatorX<16>,
+    wavetable: &Wavetable,
+    state: &mut st::OscillatorState,
 ) -> [f32; 16] {
-    let osc_samples = match render_plan.osc.kind {
+    let period = osc.periods;
+    let band_limit = osc.band_limit;
+    let samples = match osc.kind {
         rp::OscillatorKind::Square => {
             SquareOscillatorX16 {
-                state: &mut state.osc,
-                period: render_plan.osc.periods,
-                phase: Unipolar(0.0)
+                state,
+                period,
+                phase: Unipolar(0.0),
+                band_limit,
+            }.sample()
+        },
+        rp::OscillatorKind::Pulse => {
+            PulseOscillatorX16 {
+                state,
+                period,
+                phase: Unipolar(0.0),
+                width: osc.pulse_widths,
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Saw => {
             SawOscillatorX16 {
-                state: &mut state.osc,
-                period: render_plan.osc.periods,
-                phase: Unipolar(0.0)
+                state,
+                period,
+                phase: Unipolar(0.0),
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Triangle => {
             TriangleOscillatorX16 {
-                state: &mut state.osc,
-                period: render_plan.osc.periods,
-                phase: Unipolar(0.0)
+                state,
+                period,
+                phase: Unipolar(0.0),
+                band_limit,
             }.sample()
         },
         rp::OscillatorKind::Sine => {
             SineOscillatorX16 {
-                state: &mut state.osc,
-                period: render_plan.osc.periods,
+                state,
+                period,
                 phase: Unipolar(0.0)
             }.sample()
         },
+        rp::OscillatorKind::Wavetable => {
+            WavetableOscillatorX16 {
+                wavetable,
+                state,
+                period,
+                phase: Unipolar(0.0),
+                position: osc.wavetable_positions,
+            }.sample()
+        },
     };
-    let osc_samples = osc_samples.map(|s| s.0);
-    let osc_samples = {
-        f32x16::from_array(osc_samples)
-            + f32x16::splat(render_plan.osc.gain.0)
-    };
+    samples.map(|s| s.0)
+}
 
-    let offsets = offsets_x16(offset);
-    let offsets = offsets.map(|o| SampleOffset(o as f32));
-    let noise_samples = HashNoiseX16 {
-        seed: state.noise.seed,
-    }.sample(offsets);
-    let noise_samples = noise_samples.map(|s| s.0);
-    let noise_samples = {
-        f32x16::from_array(noise_samples)
-            + f32x16::splat(render_plan.noise.0)
-    };
+/// Sixteen frames of the second oscillator, synced to the first at `osc_phases` if enabled.
+fn sample_oscillator2_x16(
+    osc2: &rp::Oscillator2X<16>,
+    osc_phases: [Unipolar<1>; 16],
+    osc_periods: [SampleOffset; 16],
+    wavetable: &Wavetable,
+    state: &mut st::OscillatorState,
+) -> [f32; 16] {
+    if !osc2.sync {
+        return sample_oscillator_x16(&osc2.osc, wavetable, state);
+    }
 
-    let samples = (osc_samples + noise_samples).to_array();
+    let phase = hard_sync::SyncedPhasesX16 {
+        leader_phase: osc_phases,
+        leader_period: osc_periods,
+        state,
+        period: osc2.osc.periods,
+    }.next();
 
-    let sample_rate = render_plan.lpf.sample_rate;
-    let lpf_freqs = render_plan.lpf.freqs;
+    sample_phased_x16(&osc2.osc, wavetable, phase)
+}
 
-    let samples = std::array::from_fn(|i| (samples[i], lpf_freqs[i]));
-    let samples = samples.map(|(sample, lpf_freq)| {
+fn filter_x16(
+    state: &mut LowPassFilterState,
+    sample_rate: SampleRateKhz,
+    freqs: [Hz; 16],
+    samples: [f32; 16],
+) -> [f32; 16] {
+    let samples = std::array::from_fn(|i| (samples[i], freqs[i]));
+    samples.map(|(sample, freq)| {
         let mut lpf = LowPassFilter {
-            state: &mut state.lpf,
-            sample_rate: sample_rate,
-            freq: lpf_freq,
+            state: &mut *state,
+            sample_rate,
+            freq,
         };
         lpf.process(sample)
-    });
+    })
+}
 
-    let samples = f32x16::from_array(samples);
-    let gains = render_plan.gains.map(|g| g.0);
-    let gains = f32x16::from_array(gains);
-    let samples = samples * gains;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_util;
+
+    fn render_stereo(unison: sc::Unison) -> (Vec<f32>, Vec<f32>) {
+        let mut config = super::super::synth::Synth::default_config();
+        config.unison = unison;
+        let mut state = st::Layer::default();
+        let mut left = vec![0.0; 1000];
+        let mut right = vec![0.0; 1000];
+        process_layer_buf_simd(
+            &config,
+            &Wavetable::sine(),
+            &mut state,
+            VoiceInputs {
+                pitch: Hz(220.0),
+                velocity: Unipolar(1.0),
+                controllers: &[ControllerFrame::default(); 1000],
+                offset: 0,
+                release_offset: None,
+            },
+            SampleRateKhz(48000),
+            &mut left,
+            &mut right,
+        );
+        (left, right)
+    }
 
-    samples.to_array()
+    fn render_mono(config: &sc::Layer, simd: bool) -> Vec<f32> {
+        render_mono_from(config, &mut st::Layer::default(), simd)
+    }
+
+    /// Like `render_mono`, but continuing from `state`.
+    fn render_mono_from(config: &sc::Layer, state: &mut st::Layer, simd: bool) -> Vec<f32> {
+        let mut left = vec![0.0; 1000];
+        let mut right = vec![0.0; 1000];
+        let process = if simd { process_layer_buf_simd } else { process_layer_buf_sisd };
+        process(
+            config,
+            &Wavetable::sine(),
+            state,
+            VoiceInputs {
+                pitch: Hz(220.0),
+                velocity: Unipolar(1.0),
+                controllers: &[ControllerFrame::default(); 1000],
+                offset: 0,
+                release_offset: None,
+            },
+            SampleRateKhz(48000),
+            &mut left,
+            &mut right,
+        );
+        left
+    }
+
+    /// Assert that the 16-frame path matches the frame-at-a-time path,
+    /// with the pitch bend and mod wheel moving every frame.
+    fn assert_simd_matches_sisd(config: &sc::Layer) {
+        let controllers: Vec<_> = (0..1024).map(|frame| ControllerFrame {
+            pitch_bend: Bipolar((frame as f32 * 0.05).sin()),
+            mod_wheel: test_util::varying_level(frame),
+            ..ControllerFrame::default()
+        }).collect();
+        let inputs = VoiceInputs {
+            pitch: Hz(220.0),
+            velocity: Unipolar(1.0),
+            controllers: &controllers,
+            offset: 0,
+            release_offset: None,
+        };
+        let wavetable = Wavetable::sine();
+        let sample_rate = SampleRateKhz(48000);
+
+        let mut sisd_state = st::Layer::default();
+        let mut simd_state = st::Layer::default();
+        test_util::assert_x16_matches_scalar(
+            controllers.len() / 16,
+            1e-3,
+            |frame| process_layer(config, &wavetable, &mut sisd_state, inputs, frame, sample_rate).0,
+            |start| process_layer_x16(config, &wavetable, &mut simd_state, inputs, start, sample_rate).0,
+        );
+    }
+
+    #[test]
+    fn test_osc2_mix() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.lpf.freq = Hz(20000.0);
+        let osc = render_mono(&config, true);
+
+        // An identical second oscillator sounds the same, synced or not.
+        for sync in [false, true] {
+            config.osc2 = sc::Oscillator2 {
+                kind: config.osc.kind,
+                sync,
+                mix: Unipolar(1.0),
+                ..sc::Oscillator2::default()
+            };
+            let osc2 = render_mono(&config, true);
+            for (osc, osc2) in osc.iter().zip(&osc2) {
+                assert!((osc - osc2).abs() < 1e-5, "sync {}: {} != {}", sync, osc, osc2);
+            }
+        }
+
+        config.osc2.coarse = Bipolar(7.0);
+        assert_ne!(render_mono(&config, true), osc);
+    }
+
+    #[test]
+    fn test_osc2_simd_matches_sisd() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.osc2 = sc::Oscillator2 {
+            kind: sc::OscillatorKind::Pulse,
+            coarse: Bipolar(7.0),
+            fine: Cents(-5.0),
+            sync: true,
+            mix: Unipolar(0.5),
+            ring_mod: Unipolar(0.5),
+            ..sc::Oscillator2::default()
+        };
+        config.modulations.mod_wheel_to_pulse_width = Bipolar(0.5);
+        assert_simd_matches_sisd(&config);
+    }
+
+    #[test]
+    fn test_osc2_syncs_to_fundamental() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.lpf.freq = Hz(20000.0);
+        config.osc2 = sc::Oscillator2 {
+            kind: sc::OscillatorKind::Saw,
+            coarse: Bipolar(7.0),
+            sync: true,
+            mix: Unipolar(1.0),
+            ..sc::Oscillator2::default()
+        };
+
+        // Only the second oscillator sounds, and it restarts with the
+        // fundamental whatever plays in place of the first.
+        let mut unison = config;
+        unison.unison = sc::Unison {
+            voices: 3,
+            detune: Cents(10.0),
+            spread: Unipolar(0.0),
+            phase: sc::UnisonPhase::Random,
+        };
+        let mut fm = config;
+        fm.osc.kind = sc::OscillatorKind::Fm;
+
+        for simd in [true, false] {
+            let synced = render_mono(&config, simd);
+            for other in [&unison, &fm] {
+                let other = render_mono(other, simd);
+                for (i, (synced, other)) in synced.iter().zip(&other).enumerate() {
+                    assert!((synced - other).abs() < 1e-5, "frame {}: {} != {}", i, synced, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_silent_osc2_restarts() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.osc2.mix = Unipolar(1.0);
+        for simd in [true, false] {
+            let mut state = st::Layer::default();
+            render_mono_from(&config, &mut state, simd);
+            assert!(state.osc2.phase_accum.is_some());
+
+            let silent = sc::Layer {
+                osc2: sc::Oscillator2 { mix: Unipolar(0.0), ..config.osc2 },
+                ..config
+            };
+            render_mono_from(&silent, &mut state, simd);
+            assert!(state.osc2.phase_accum.is_none());
+        }
+    }
+
+    #[test]
+    fn test_simd_gains_match_sisd() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.osc.gain = Unipolar(0.25);
+        config.noise = Unipolar(0.5);
+        let simd = render_mono(&config, true);
+        let sisd = render_mono(&config, false);
+        for (i, (simd, sisd)) in simd.iter().zip(&sisd).enumerate() {
+            assert!((simd - sisd).abs() < 1e-3, "frame {}: {} != {}", i, simd, sisd);
+        }
+    }
+
+    #[test]
+    fn test_fm() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.lpf.freq = Hz(20000.0);
+        config.osc.kind = sc::OscillatorKind::Sine;
+        let sine = render_mono(&config, true);
+
+        // The default operators are a lone sine carrier.
+        config.osc.kind = sc::OscillatorKind::Fm;
+        let fm = render_mono(&config, true);
+        for (sine, fm) in sine.iter().zip(&fm) {
+            assert!((sine - fm).abs() < 1e-2, "{} != {}", sine, fm);
+        }
+
+        config.fm.operators[1].level = Unipolar(0.5);
+        config.fm.operators[1].ratio = 2.0;
+        assert_ne!(render_mono(&config, true), fm);
+    }
+
+    #[test]
+    fn test_fm_simd_matches_sisd() {
+        let mut config = super::super::synth::Synth::default_config();
+        config.osc.kind = sc::OscillatorKind::Fm;
+        config.unison.voices = 3;
+        config.fm.algorithm = 4;
+        for (operator, ratio) in config.fm.operators.iter_mut().zip([1.0, 2.0, 3.5, 0.5]) {
+            operator.ratio = ratio;
+            operator.level = Unipolar(0.5);
+            operator.env.decay = Ms(10.0);
+            operator.env.sustain = Unipolar(0.5);
+        }
+        config.fm.operators[3].feedback = Unipolar(0.5);
+        assert_simd_matches_sisd(&config);
+    }
+
+    #[test]
+    fn test_operator_envelopes() {
+        let mut fm = sc::Fm::default();
+        for (operator, attack) in fm.operators.iter_mut().zip([0.0, 5.0, 10.0, 20.0]) {
+            operator.env = sc::Adsr {
+                attack: Ms(attack),
+                decay: Ms(10.0),
+                sustain: Unipolar(0.5),
+                release: Ms(10.0),
+            };
+        }
+        let sample_rate = SampleRateKhz(48000);
+        for (offset, release_offset) in [(0, None), (300, None), (1000, None), (1200, Some(1000))] {
+            let samples = sample_operator_envelopes(&fm, sample_rate, offset, release_offset);
+            for (operator, sample) in fm.operators.iter().zip(samples) {
+                let expected = sample_envelope_x16(operator.env, sample_rate, offset, release_offset)[0];
+                assert_eq!(sample.0, expected.0, "offset {}", offset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unison_spread() {
+        let unison = sc::Unison {
+            voices: 3,
+            detune: Cents(10.0),
+            spread: Unipolar(0.0),
+            phase: sc::UnisonPhase::Random,
+        };
+        let (left, right) = render_stereo(unison);
+        assert_eq!(left, right);
+
+        let (left, right) = render_stereo(sc::Unison {
+            spread: Unipolar(1.0),
+            ..unison
+        });
+        assert_ne!(left, right);
+    }
 }
@@ -1,26 +1,74 @@
 use super::units::*;
+use super::oscillators::BandLimit;
+use super::fm;
+use super::static_config::FM_OPERATORS;
 
 #[derive(Copy, Clone)]
 pub struct Layer {
     pub osc: Oscillator,
+    pub osc2: Option<Oscillator2>,
+    /// In place of `osc`.
+    pub fm: Option<Fm>,
+    pub unison: Option<Unison>,
     pub noise: Unipolar<1>,
     pub lpf: LowPassFilter,
     pub gain: Unipolar<1>,
+    pub pan: Bipolar<1>,
 }
 
 #[derive(Copy, Clone)]
 pub struct Oscillator {
     pub period: SampleOffset,
     pub kind: OscillatorKind,
+    pub band_limit: BandLimit,
+    pub wavetable_position: Unipolar<1>,
+    pub pulse_width: Unipolar<1>,
     pub gain: Unipolar<1>,
 }
 
+/// The second oscillator, and how it's mixed with the first.
+///
+/// The mix sets its level, rather than its own gain.
+#[derive(Copy, Clone)]
+pub struct Oscillator2 {
+    pub osc: Oscillator,
+    pub sync: bool,
+    pub mix: Unipolar<1>,
+    pub ring_mod: Unipolar<1>,
+}
+
+#[derive(Copy, Clone)]
+pub struct Fm {
+    pub algorithm: &'static fm::Algorithm,
+    pub operators: [fm::Operator; FM_OPERATORS],
+}
+
 #[derive(Copy, Clone)]
 pub enum OscillatorKind {
     Square,
+    Pulse,
     Saw,
     Triangle,
     Sine,
+    Wavetable,
+}
+
+/// Unison lanes, one per simd lane. Unused lanes have zero gain.
+#[derive(Copy, Clone)]
+pub struct Unison {
+    /// Frequency of each lane relative to the voice pitch.
+    pub ratios: [f32; 16],
+    pub gains_left: [f32; 16],
+    pub gains_right: [f32; 16],
+    /// Whether the left and right channels differ.
+    pub stereo: bool,
+    pub phase: UnisonPhase,
+}
+
+#[derive(Copy, Clone)]
+pub enum UnisonPhase {
+    Fixed(Unipolar<1>),
+    Random,
 }
 
 #[derive(Copy, Clone)]
@@ -32,18 +80,39 @@ pub struct LowPassFilter {
 #[derive(Copy, Clone)]
 pub struct LayerX<const N: usize> {
     pub osc: OscillatorX<N>,
+    pub osc2: Option<Oscillator2X<N>>,
+    pub fm: Option<FmX<N>>,
+    pub unison: Option<Unison>,
     pub noise: Unipolar<1>,
     pub lpf: LowPassFilterX<N>,
     pub gains: [Unipolar<1>; N],
+    pub pans: [Bipolar<1>; N],
 }
 
 #[derive(Copy, Clone)]
 pub struct OscillatorX<const N: usize> {
     pub kind: OscillatorKind,
+    pub band_limit: BandLimit,
     pub periods: [SampleOffset; N],
+    pub wavetable_positions: [Unipolar<1>; N],
+    pub pulse_widths: [Unipolar<1>; N],
     pub gain: Unipolar<1>,
 }
 
+#[derive(Copy, Clone)]
+pub struct Oscillator2X<const N: usize> {
+    pub osc: OscillatorX<N>,
+    pub sync: bool,
+    pub mix: Unipolar<1>,
+    pub ring_mod: Unipolar<1>,
+}
+
+#[derive(Copy, Clone)]
+pub struct FmX<const N: usize> {
+    pub algorithm: &'static fm::Algorithm,
+    pub operators: [fm::OperatorX<N>; FM_OPERATORS],
+}
+
 #[derive(Copy, Clone)]
 pub struct LowPassFilterX<const N: usize> {
     pub sample_rate: SampleRateKhz,
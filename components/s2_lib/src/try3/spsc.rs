@@ -0,0 +1,243 @@
+//! A bounded, lock-free, single-producer single-consumer queue.
+//!
+//! For handing small `Copy` values between realtime threads,
+//! like MIDI events to the synth and audio frames to the device,
+//! without locking or allocating once the queue is created.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Create a queue holding at least `capacity` values.
+///
+/// The capacity is rounded up to a power of two.
+pub fn channel<T: Copy + Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+    let shared = Arc::new(Shared {
+        slots,
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+    });
+    (
+        Producer { shared: shared.clone() },
+        Consumer { shared },
+    )
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// The count of values ever popped, only written by the consumer.
+    read: AtomicUsize,
+    /// The count of values ever pushed, only written by the producer.
+    write: AtomicUsize,
+}
+
+// The producer only writes slots the consumer has finished with,
+// and the consumer only reads slots the producer has published.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        // The capacity is a power of two, so this stays correct
+        // when the counts wrap around.
+        self.slots[index & (self.slots.len() - 1)].get()
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    /// Add a value, or give it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let write = self.shared.write.load(Ordering::Relaxed);
+        let read = self.shared.read.load(Ordering::Acquire);
+        if write.wrapping_sub(read) == self.shared.slots.len() {
+            return Err(value);
+        }
+
+        unsafe {
+            (*self.shared.slot(write)).write(value);
+        }
+        self.shared.write.store(write.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Add as many of `values` as fit, returning how many that was.
+    pub fn push_slice(&mut self, values: &[T]) -> usize {
+        let write = self.shared.write.load(Ordering::Relaxed);
+        let read = self.shared.read.load(Ordering::Acquire);
+        let free = self.shared.slots.len() - write.wrapping_sub(read);
+        let count = values.len().min(free);
+
+        for (i, value) in values[..count].iter().enumerate() {
+            unsafe {
+                (*self.shared.slot(write.wrapping_add(i))).write(*value);
+            }
+        }
+        self.shared.write.store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// How many values are waiting for the consumer.
+    ///
+    /// The consumer may take some at any time.
+    pub fn len(&self) -> usize {
+        let write = self.shared.write.load(Ordering::Relaxed);
+        let read = self.shared.read.load(Ordering::Acquire);
+        write.wrapping_sub(read)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// Whether the consumer has been dropped.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Take the oldest value, if there is one.
+    pub fn pop(&mut self) -> Option<T> {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let write = self.shared.write.load(Ordering::Acquire);
+        if read == write {
+            return None;
+        }
+
+        let value = unsafe {
+            (*self.shared.slot(read)).assume_init()
+        };
+        self.shared.read.store(read.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// The oldest value, without taking it.
+    pub fn peek(&self) -> Option<T> {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let write = self.shared.write.load(Ordering::Acquire);
+        if read == write {
+            return None;
+        }
+
+        Some(unsafe { (*self.shared.slot(read)).assume_init() })
+    }
+
+    /// Fill `values` with the oldest values, returning how many there were.
+    pub fn pop_slice(&mut self, values: &mut [T]) -> usize {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let write = self.shared.write.load(Ordering::Acquire);
+        let count = values.len().min(write.wrapping_sub(read));
+
+        for (i, value) in values[..count].iter_mut().enumerate() {
+            *value = unsafe {
+                (*self.shared.slot(read.wrapping_add(i))).assume_init()
+            };
+        }
+        self.shared.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// How many values are waiting.
+    ///
+    /// More may arrive at any time.
+    pub fn len(&self) -> usize {
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let write = self.shared.write.load(Ordering::Acquire);
+        write.wrapping_sub(read)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pop values until the queue is empty.
+    pub fn try_iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let (mut tx, mut rx) = channel::<u32>(3);
+        assert_eq!(rx.pop(), None);
+
+        for i in 0..4 {
+            assert_eq!(tx.push(i), Ok(()));
+        }
+        assert_eq!(tx.push(4), Err(4));
+        assert_eq!(rx.len(), 4);
+
+        assert_eq!(rx.pop(), Some(0));
+        assert_eq!(tx.push(4), Ok(()));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn test_slices() {
+        let (mut tx, mut rx) = channel::<u32>(4);
+        assert_eq!(tx.push_slice(&[1, 2, 3]), 3);
+        assert_eq!(tx.push_slice(&[4, 5, 6]), 1);
+        assert_eq!(tx.len(), 4);
+        assert_eq!(rx.peek(), Some(1));
+
+        let mut values = [0; 3];
+        assert_eq!(rx.pop_slice(&mut values), 3);
+        assert_eq!(values, [1, 2, 3]);
+
+        // Wraps around the end of the slots.
+        assert_eq!(tx.push_slice(&[5, 6, 7]), 3);
+        let mut values = [0; 8];
+        assert_eq!(rx.pop_slice(&mut values), 4);
+        assert_eq!(values[..4], [4, 5, 6, 7]);
+
+        assert!(!tx.is_abandoned());
+        drop(rx);
+        assert!(tx.is_abandoned());
+    }
+
+    #[test]
+    fn test_across_threads() {
+        const COUNT: u32 = 100_000;
+        let (mut tx, mut rx) = channel::<u32>(16);
+
+        let producer = std::thread::spawn(move || {
+            for i in 0..COUNT {
+                while tx.push(i).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match rx.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+
+        producer.join().expect("producer");
+    }
+}
@@ -1,21 +1,34 @@
 pub use super::oscillators::phase_accumulating::{
     OscillatorState,
 };
+pub use super::oscillators::unison::{
+    UnisonState,
+};
 pub use super::filters::{
     LowPassFilterState,
 };
+pub use super::fm::{
+    FmState,
+};
 
 #[derive(Default)]
 #[derive(Copy, Clone)]
 pub struct Layer {
+    /// Keeps the first oscillator's cycle even while unison lanes or FM operators
+    /// play in its place, for hard syncing the second.
     pub osc: OscillatorState,
+    pub osc2: OscillatorState,
+    pub fm: FmState,
+    pub unison: UnisonState,
     pub noise: NoiseState,
     pub lpf: LowPassFilterState,
+    /// Only used when unison voices are spread across the stereo field.
+    pub lpf_right: LowPassFilterState,
 }
 
 #[derive(Default)]
 #[derive(Copy, Clone)]
 pub struct NoiseState {
-    // todo don't default this
+    /// Set per voice by the synth. Also seeds random unison phases.
     pub seed: u32,
 }
@@ -1,41 +1,372 @@
+use serde::{Serialize, Deserialize};
 use super::units::*;
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
 pub struct Layer {
     pub osc: Oscillator,
+    #[serde(default)]
+    pub osc2: Oscillator2,
+    /// Played in place of `osc` when its kind is `fm`.
+    #[serde(default)]
+    pub fm: Fm,
+    #[serde(default)]
+    pub unison: Unison,
     pub noise: Unipolar<1>,
+    /// From full left to full right.
+    #[serde(default)]
+    pub pan: Bipolar<1>,
     pub lpf: LowPassFilter,
+    #[serde(default)]
+    pub velocity: Velocity,
     pub amp_env: Adsr,
     pub mod_env: Adsr,
     pub modulations: Modulations,
 }
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
 pub struct Modulations {
     pub mod_env_to_osc_freq: Bipolar<10>,
     pub mod_env_to_lpf_freq: Bipolar<10>,
+    /// Octaves at full velocity, after the velocity curve.
+    #[serde(default)]
+    pub velocity_to_lpf_freq: Bipolar<10>,
+    /// How much velocity scales the depth of the mod envelope.
+    /// At 1 the depth is proportional to velocity.
+    #[serde(default)]
+    pub velocity_to_mod_env: Unipolar<1>,
+    /// Semitones at full bend in either direction.
+    #[serde(default = "default_pitch_bend_range")]
+    pub pitch_bend_range: Unipolar<48>,
+    #[serde(default)]
+    pub mod_wheel_to_osc_freq: Bipolar<10>,
+    #[serde(default)]
+    pub mod_wheel_to_lpf_freq: Bipolar<10>,
+    /// Channel pressure, or aftertouch.
+    #[serde(default)]
+    pub pressure_to_osc_freq: Bipolar<10>,
+    #[serde(default)]
+    pub pressure_to_lpf_freq: Bipolar<10>,
+    /// CC74, or MPE timbre.
+    #[serde(default)]
+    pub timbre_to_lpf_freq: Bipolar<10>,
+    /// Polyphonic aftertouch, per note.
+    #[serde(default)]
+    pub poly_pressure_to_osc_freq: Bipolar<10>,
+    #[serde(default)]
+    pub poly_pressure_to_lpf_freq: Bipolar<10>,
+    /// Pan modulations add to the patch pan, stopping at either side.
+    #[serde(default)]
+    pub mod_env_to_pan: Bipolar<1>,
+    #[serde(default)]
+    pub timbre_to_pan: Bipolar<1>,
+    /// Wavetable position modulations scan from each oscillator's position,
+    /// stopping at the first and last frames.
+    #[serde(default)]
+    pub mod_env_to_wavetable_position: Bipolar<1>,
+    #[serde(default)]
+    pub mod_wheel_to_wavetable_position: Bipolar<1>,
+    #[serde(default)]
+    pub timbre_to_wavetable_position: Bipolar<1>,
+    /// Pulse width modulations widen the pulse when positive and narrow it
+    /// when negative, from each oscillator's width.
+    #[serde(default)]
+    pub mod_env_to_pulse_width: Bipolar<1>,
+    #[serde(default)]
+    pub mod_wheel_to_pulse_width: Bipolar<1>,
+    #[serde(default)]
+    pub timbre_to_pulse_width: Bipolar<1>,
+}
+
+fn default_pitch_bend_range() -> Unipolar<48> {
+    Unipolar(2.0)
 }
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
 pub struct Oscillator {
     pub kind: OscillatorKind,
+    /// The level of the oscillators, after mixing in `osc2`.
     pub gain: Unipolar<1>,
+    #[serde(default)]
+    pub band_limit: BandLimit,
+    /// From the first frame of the wavetable to the last.
+    #[serde(default)]
+    pub wavetable_position: Unipolar<1>,
+    /// The fraction of each cycle the pulse is high. At 0.5 it's a square.
+    #[serde(default = "default_pulse_width")]
+    pub pulse_width: Unipolar<1>,
+}
+
+fn default_pulse_width() -> Unipolar<1> {
+    Unipolar(0.5)
 }
 
+/// A second oscillator, tuned relative to the first and mixed with it.
+///
+/// It follows the first oscillator's pitch and modulations, and plays
+/// without unison.
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Oscillator2 {
+    /// Anything but `fm`.
+    #[serde(deserialize_with = "deserialize_oscillator2_kind")]
+    pub kind: OscillatorKind,
+    #[serde(default)]
+    pub band_limit: BandLimit,
+    #[serde(default)]
+    pub wavetable_position: Unipolar<1>,
+    #[serde(default = "default_pulse_width")]
+    pub pulse_width: Unipolar<1>,
+    /// Semitones above or below the first oscillator.
+    pub coarse: Bipolar<48>,
+    pub fine: Cents,
+    /// Restart the cycle whenever the first oscillator's cycle restarts.
+    pub sync: bool,
+    /// From only the first oscillator at 0 to only this one at 1.
+    pub mix: Unipolar<1>,
+    /// Crossfades the mix to the product of the two oscillators.
+    pub ring_mod: Unipolar<1>,
+}
+
+impl Default for Oscillator2 {
+    fn default() -> Oscillator2 {
+        Oscillator2 {
+            kind: OscillatorKind::Saw,
+            band_limit: BandLimit::default(),
+            wavetable_position: Unipolar(0.0),
+            pulse_width: default_pulse_width(),
+            coarse: Bipolar(0.0),
+            fine: Cents(0.0),
+            sync: false,
+            mix: Unipolar(0.0),
+            ring_mod: Unipolar(0.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum OscillatorKind {
     Square,
+    /// A square with an adjustable width.
+    Pulse,
     Saw,
     Triangle,
     Sine,
+    /// The patch's wavetable, or a sine if it has none.
+    Wavetable,
+    /// The layer's FM operators, without unison.
+    /// Only the first oscillator can run them.
+    Fm,
+}
+
+fn deserialize_oscillator2_kind<'de, D>(deserializer: D) -> Result<OscillatorKind, D::Error>
+where D: serde::Deserializer<'de>,
+{
+    match OscillatorKind::deserialize(deserializer)? {
+        OscillatorKind::Fm => Err(serde::de::Error::custom("osc2 kind can't be fm")),
+        kind => Ok(kind),
+    }
+}
+
+/// Four sine operators modulating each other's phase, as on the DX9 and TX81Z.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Fm {
+    /// From 1 to `FM_ALGORITHMS`, numbered as on the TX81Z.
+    #[serde(deserialize_with = "deserialize_fm_algorithm")]
+    pub algorithm: u8,
+    pub operators: [FmOperator; FM_OPERATORS],
+}
+
+pub const FM_OPERATORS: usize = 4;
+pub const FM_ALGORITHMS: u8 = 8;
+pub const MAX_FM_RATIO: f32 = 32.0;
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct FmOperator {
+    /// Frequency relative to the voice pitch, above 0 and up to `MAX_FM_RATIO`.
+    #[serde(deserialize_with = "deserialize_fm_ratio")]
+    pub ratio: f32,
+    /// For carriers, the level in the mix. For modulators, the depth of modulation.
+    pub level: Unipolar<1>,
+    /// How much the operator modulates itself.
+    pub feedback: Unipolar<1>,
+    pub env: Adsr,
+}
+
+impl Default for Fm {
+    fn default() -> Fm {
+        let operator = FmOperator {
+            ratio: 1.0,
+            level: Unipolar(0.0),
+            feedback: Unipolar(0.0),
+            env: Adsr {
+                attack: Ms(0.0),
+                decay: Ms(0.0),
+                sustain: Unipolar(1.0),
+                release: Ms(100.0),
+            },
+        };
+        let mut operators = [operator; FM_OPERATORS];
+        // A plain sine until the modulators are turned up.
+        operators[0].level = Unipolar(1.0);
+
+        Fm {
+            algorithm: 1,
+            operators,
+        }
+    }
+}
+
+fn deserialize_fm_algorithm<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where D: serde::Deserializer<'de>,
+{
+    let algorithm = u8::deserialize(deserializer)?;
+    if (1..=FM_ALGORITHMS).contains(&algorithm) {
+        Ok(algorithm)
+    } else {
+        Err(serde::de::Error::custom(format_args!("fm algorithm out of [1, {}] range", FM_ALGORITHMS)))
+    }
+}
+
+fn deserialize_fm_ratio<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where D: serde::Deserializer<'de>,
+{
+    let ratio = f32::deserialize(deserializer)?;
+    if ratio > 0.0 && ratio <= MAX_FM_RATIO {
+        Ok(ratio)
+    } else {
+        Err(serde::de::Error::custom(format_args!("fm ratio out of (0, {}] range", MAX_FM_RATIO)))
+    }
+}
+
+/// How the square, pulse, saw and triangle oscillators avoid aliasing.
+#[derive(Default)]
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandLimit {
+    /// The ideal waveform, which aliases at high notes.
+    Naive,
+    /// Discontinuities smoothed by polynomial band-limited steps.
+    #[default]
+    PolyBlep,
+}
+
+/// Detuned copies of the oscillator played by each voice.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Unison {
+    /// From 1 to `MAX_UNISON`.
+    #[serde(deserialize_with = "deserialize_unison_voices")]
+    pub voices: u8,
+    /// The detune of the outermost copies. The others are spaced evenly between.
+    pub detune: Cents,
+    /// How far the outermost copies are panned to each side.
+    pub spread: Unipolar<1>,
+    pub phase: UnisonPhase,
+}
+
+pub const MAX_UNISON: u8 = 16;
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnisonPhase {
+    /// Every copy starts at the same phase.
+    Fixed(Unipolar<1>),
+    /// Every copy starts at a different random phase.
+    Random,
+}
+
+impl Default for Unison {
+    fn default() -> Unison {
+        Unison {
+            voices: 1,
+            detune: Cents(0.0),
+            spread: Unipolar(0.0),
+            phase: UnisonPhase::Fixed(Unipolar(0.0)),
+        }
+    }
+}
+
+fn deserialize_unison_voices<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where D: serde::Deserializer<'de>,
+{
+    let voices = u8::deserialize(deserializer)?;
+    if (1..=MAX_UNISON).contains(&voices) {
+        Ok(voices)
+    } else {
+        Err(serde::de::Error::custom(format_args!("unison voices out of [1, {}] range", MAX_UNISON)))
+    }
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Velocity {
+    /// How much quieter soft notes are. At 0 every note plays at full level.
+    pub sensitivity: Unipolar<1>,
+    pub curve: VelocityCurve,
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VelocityCurve {
+    Linear,
+    /// Reaches high levels with less force.
+    Soft,
+    /// Needs more force to reach high levels.
+    Hard,
+}
+
+impl Default for Velocity {
+    /// Patches without a velocity block play every note at full level.
+    fn default() -> Velocity {
+        Velocity {
+            sensitivity: Unipolar(0.0),
+            curve: VelocityCurve::Linear,
+        }
+    }
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: Unipolar<1>) -> Unipolar<1> {
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::Soft => Unipolar(velocity.0.sqrt()),
+            VelocityCurve::Hard => Unipolar(velocity.0 * velocity.0),
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
 pub struct LowPassFilter {
     pub freq: Hz,
 }
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
 pub struct Adsr {
     pub attack: Ms,
     pub decay: Ms,
@@ -1,14 +1,250 @@
 use std::simd::f32x16;
+use std::simd::prelude::*;
+use std::sync::Arc;
 use super::units::{Unipolar, Hz, Ms, Bipolar, SampleRateKhz};
 use super::static_config as sc;
 use super::state as st;
 use super::process;
+use super::controllers::{ChannelControllers, ControllerFrame, Smoothed};
+use super::mpe::{Channel, MpeZones};
+use super::patch::Patch;
+use super::wavetable::Wavetable;
 
-const NUM_VOICES: usize = 8;
+/// The most voices that can be sounding at once.
+pub const MAX_POLYPHONY: usize = 16;
+
+/// Extra voices for stolen voices to fade out in.
+const FADE_VOICES: usize = 4;
+
+const NUM_VOICES: usize = MAX_POLYPHONY + FADE_VOICES;
+
+/// How long a stolen voice takes to fade out.
+const STEAL_FADE: Ms = Ms(5.0);
+
+/// How long the previous patch keeps sounding after `set_patch`.
+const CONFIG_FADE: Ms = Ms(20.0);
+
+/// Room for retired wavetables between calls to `send_retired_wavetables`.
+const MAX_RETIRED_WAVETABLES: usize = 4;
+
+/// Peak level below which a released voice with a finished amp envelope is silent.
+const SILENCE_THRESHOLD: f32 = 1.0e-4;
 
 pub struct Synth {
     config: sc::Layer,
+    wavetable: Arc<Wavetable>,
+    allocation: VoiceAllocation,
+    mode: VoiceMode,
+    glide: Glide,
+    /// Held notes, in the order they were pressed, for mono mode.
+    note_stack: NoteStack,
+    /// The voice playing the mono note.
+    mono_voice: Option<usize>,
     voices: [Voice; NUM_VOICES],
+    fade: Option<ConfigFade>,
+    /// A patch set during `fade`, started once it finishes,
+    /// so that crossfades never cut each other off.
+    next_patch: Option<(sc::Layer, Arc<Wavetable>)>,
+    /// Wavetables no longer played, waiting for `send_retired_wavetables`.
+    ///
+    /// Freeing a wavetable could block the audio thread, and so could
+    /// growing a `Vec`, so when every slot is taken patches wait instead.
+    retired_wavetables: [Option<Arc<Wavetable>>; MAX_RETIRED_WAVETABLES],
+    controllers: ChannelControllers,
+    mpe: MpeZones,
+    /// Per-note expression on each MPE member channel.
+    member_controllers: [ChannelControllers; 16],
+    /// Whether the sustain pedal is down.
+    sustain: bool,
+    /// Whether the sostenuto pedal is down.
+    sostenuto: bool,
+    /// Seeds the noise and random unison phases of the next voice.
+    next_seed: u32,
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub struct VoiceAllocation {
+    /// How many voices may sound at once, up to `MAX_POLYPHONY`.
+    pub polyphony: usize,
+    pub steal: StealPolicy,
+    /// Play a note that is already sounding in a new voice,
+    /// fading out the old one, instead of layering them.
+    pub retrigger_same_note: bool,
+}
+
+/// Which voice to take for a new note when `polyphony` voices are sounding.
+///
+/// Released voices are always stolen before held voices.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub enum StealPolicy {
+    /// Steal the voice that started first.
+    Oldest,
+    /// Steal the voice with the lowest amp envelope level.
+    Quietest,
+    /// Keep low notes, stealing the highest note.
+    LowNotePriority,
+    /// Keep high notes, stealing the lowest note.
+    HighNotePriority,
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub enum VoiceMode {
+    Poly,
+    /// A single voice plays one of the held notes.
+    ///
+    /// When the note that is playing is released,
+    /// the voice returns to the next held note by priority.
+    Mono {
+        priority: NotePriority,
+        /// Changing notes while another is held doesn't
+        /// restart the envelopes.
+        legato: bool,
+    },
+}
+
+/// Which held note plays in mono mode.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+/// Portamento between notes in mono mode.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub struct Glide {
+    /// Zero disables glide.
+    pub time: Ms,
+    pub kind: GlideKind,
+}
+
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+pub enum GlideKind {
+    /// Every glide takes `time`.
+    ConstantTime,
+    /// Glides take `time` per octave.
+    ConstantRate,
+}
+
+impl Glide {
+    /// How many frames a glide between two notes lasts.
+    fn frames(&self, from: f32, to: f32, sample_rate: SampleRateKhz) -> f32 {
+        let frames = self.time.as_samples(sample_rate).0;
+        match self.kind {
+            GlideKind::ConstantTime => frames,
+            GlideKind::ConstantRate => frames * (to - from).abs() / 12.0,
+        }
+    }
+}
+
+impl Default for Glide {
+    fn default() -> Glide {
+        Glide {
+            time: Ms(0.0),
+            kind: GlideKind::ConstantTime,
+        }
+    }
+}
+
+impl std::str::FromStr for NotePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<NotePriority> {
+        match s {
+            "last" => Ok(NotePriority::Last),
+            "low" => Ok(NotePriority::Low),
+            "high" => Ok(NotePriority::High),
+            _ => Err(anyhow::anyhow!(
+                "unknown note priority `{}`, expected one of last, low, high", s
+            )),
+        }
+    }
+}
+
+/// Held notes without allocation. Each note appears at most once.
+#[derive(Copy, Clone)]
+struct NoteStack {
+    notes: [Note; 128],
+    len: usize,
+}
+
+impl NoteStack {
+    fn new() -> NoteStack {
+        NoteStack {
+            notes: [Note(0); 128],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, note: Note) {
+        self.remove(note);
+        if self.len < self.notes.len() {
+            self.notes[self.len] = note;
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, note: Note) {
+        if let Some(index) = self.notes[..self.len].iter().position(|n| *n == note) {
+            self.notes.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn select(&self, priority: NotePriority) -> Option<Note> {
+        let notes = self.notes[..self.len].iter().copied();
+        match priority {
+            NotePriority::Last => notes.last(),
+            NotePriority::Low => notes.min_by_key(|note| note.0),
+            NotePriority::High => notes.max_by_key(|note| note.0),
+        }
+    }
+}
+
+impl Default for VoiceAllocation {
+    fn default() -> VoiceAllocation {
+        VoiceAllocation {
+            polyphony: 8,
+            steal: StealPolicy::Oldest,
+            retrigger_same_note: false,
+        }
+    }
+}
+
+impl std::str::FromStr for StealPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<StealPolicy> {
+        match s {
+            "oldest" => Ok(StealPolicy::Oldest),
+            "quietest" => Ok(StealPolicy::Quietest),
+            "low-note" => Ok(StealPolicy::LowNotePriority),
+            "high-note" => Ok(StealPolicy::HighNotePriority),
+            _ => Err(anyhow::anyhow!(
+                "unknown steal policy `{}`, expected one of oldest, quietest, low-note, high-note", s
+            )),
+        }
+    }
+}
+
+/// The previous config, rendered alongside the current one
+/// while crossfading to it, so that patch changes don't click.
+struct ConfigFade {
+    config: sc::Layer,
+    wavetable: Arc<Wavetable>,
+    /// Per-voice state of the previous config.
+    states: [st::Layer; NUM_VOICES],
+    frames_done: u32,
 }
 
 #[derive(Eq, PartialEq)]
@@ -20,21 +256,75 @@ pub struct Velocity(pub Unipolar<1>);
 #[derive(Copy, Clone)]
 pub struct FrameOffset(pub u32);
 
+/// An event to apply at a frame within the buffer given to `Synth::sample_events`.
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+pub struct TimedEvent<E> {
+    pub frame: usize,
+    pub event: E,
+}
+
 #[derive(Copy, Clone)]
 pub struct Voice {
     note: Note,
     velocity: Velocity,
     current_frame_offset: Option<FrameOffset>,
     release_frame_offset: Option<FrameOffset>,
+    /// Frames since this voice was stolen and began fading out.
+    fade_out: Option<u32>,
+    /// The amp envelope level, scaled by velocity, as of the last rendered frame.
+    amp_level: f32,
+    /// The note number being played, which differs from `note` while gliding.
+    pitch: f32,
+    /// The note number a glide to `note` started at.
+    glide_from: Option<f32>,
+    glide_frames_done: u32,
+    /// Whether the key playing this voice is still down.
+    ///
+    /// An active voice whose key is up is being held by a pedal.
+    key_down: bool,
+    /// Held by the sostenuto pedal, because its key was down when the pedal was pressed.
+    sostenuto: bool,
+    /// The MPE member channel the note was played on.
+    channel: Option<Channel>,
+    /// Polyphonic key pressure on this voice's note.
+    poly_pressure: Smoothed,
     state: st::Layer,
 }
 
 impl Voice {
-    /// The 'active' voice for a note is the one that is currently being played (no note-off has been recieved).
+    /// The 'active' voice for a note is the one that is currently being played (it has not been released).
     ///
     /// There may be multiple voices for a single note making sound, but only one active.
+    /// An active voice may be held by a pedal after its note-off.
     fn is_active(&self) -> bool {
-        self.current_frame_offset.is_some() && self.release_frame_offset.is_none()
+        self.is_sounding() && self.release_frame_offset.is_none()
+    }
+
+    /// A sounding voice counts against the polyphony limit.
+    ///
+    /// Voices that have been stolen and are fading out don't.
+    fn is_sounding(&self) -> bool {
+        self.current_frame_offset.is_some() && self.fade_out.is_none()
+    }
+
+    /// An idle voice is not making sound and is free to be reused.
+    ///
+    /// Voices become idle after their release has finished.
+    fn is_idle(&self) -> bool {
+        self.current_frame_offset.is_none()
+    }
+
+    fn release(&mut self) {
+        if self.release_frame_offset.is_none() {
+            self.release_frame_offset = self.current_frame_offset;
+        }
+    }
+
+    fn set_idle(&mut self) {
+        self.current_frame_offset = None;
+        self.release_frame_offset = None;
+        self.fade_out = None;
     }
 }
 
@@ -45,6 +335,15 @@ impl Default for Voice {
             velocity: Velocity(Unipolar(0.0)),
             current_frame_offset: None,
             release_frame_offset: None,
+            fade_out: None,
+            amp_level: 0.0,
+            pitch: 0.0,
+            glide_from: None,
+            glide_frames_done: 0,
+            key_down: false,
+            sostenuto: false,
+            channel: None,
+            poly_pressure: Smoothed::default(),
             state: st::Layer::default(),
         }
     }
@@ -54,84 +353,530 @@ impl Synth {
     pub fn new() -> Synth {
         Synth {
             config: Synth::default_config(),
+            wavetable: Wavetable::sine(),
+            allocation: VoiceAllocation::default(),
+            mode: VoiceMode::Poly,
+            glide: Glide::default(),
+            note_stack: NoteStack::new(),
+            mono_voice: None,
             voices: [Voice::default(); NUM_VOICES],
+            fade: None,
+            next_patch: None,
+            retired_wavetables: Default::default(),
+            controllers: ChannelControllers::default(),
+            mpe: MpeZones::default(),
+            member_controllers: [ChannelControllers::default(); 16],
+            sustain: false,
+            sostenuto: false,
+            next_seed: 0,
         }
     }
 
+    /// Play `patch`, with its wavetable from `patch::load_wavetable`.
+    pub fn from_patch(patch: &Patch, wavetable: Arc<Wavetable>) -> Synth {
+        Synth {
+            config: patch.layer,
+            wavetable,
+            allocation: VoiceAllocation::default(),
+            mode: VoiceMode::Poly,
+            glide: Glide::default(),
+            note_stack: NoteStack::new(),
+            mono_voice: None,
+            voices: [Voice::default(); NUM_VOICES],
+            fade: None,
+            next_patch: None,
+            retired_wavetables: Default::default(),
+            controllers: ChannelControllers::default(),
+            mpe: MpeZones::default(),
+            member_controllers: [ChannelControllers::default(); 16],
+            sustain: false,
+            sostenuto: false,
+            next_seed: 0,
+        }
+    }
+
+    /// Replace the patch without interrupting sounding voices,
+    /// keeping the current wavetable.
+    ///
+    /// Hands `config` back if it can't be taken yet, as with `set_patch`.
+    #[must_use]
+    pub fn set_config(&mut self, config: sc::Layer) -> Option<sc::Layer> {
+        self.set_patch(config, self.wavetable.clone()).map(|(config, _)| config)
+    }
+
+    /// Replace the patch and its wavetable without interrupting sounding voices.
+    ///
+    /// The old and new patch are crossfaded over a few milliseconds.
+    /// A patch set during a crossfade waits for it to finish, replacing
+    /// any other patch already waiting.
+    ///
+    /// Hands the patch back if there's no room to retire the one it would
+    /// replace, until `send_retired_wavetables` makes some.
+    #[must_use]
+    pub fn set_patch(
+        &mut self,
+        config: sc::Layer,
+        wavetable: Arc<Wavetable>,
+    ) -> Option<(sc::Layer, Arc<Wavetable>)> {
+        if self.fade.is_none() {
+            self.start_fade(config, wavetable);
+            return None;
+        }
+
+        if let Some((skipped_config, skipped_wavetable)) = self.next_patch.take() {
+            if let Err(skipped_wavetable) = self.retire(skipped_wavetable) {
+                self.next_patch = Some((skipped_config, skipped_wavetable));
+                return Some((config, wavetable));
+            }
+        }
+        self.next_patch = Some((config, wavetable));
+        None
+    }
+
+    fn start_fade(&mut self, config: sc::Layer, wavetable: Arc<Wavetable>) {
+        let old_config = std::mem::replace(&mut self.config, config);
+        let old_wavetable = std::mem::replace(&mut self.wavetable, wavetable);
+        self.fade = Some(ConfigFade {
+            config: old_config,
+            wavetable: old_wavetable,
+            states: self.voices.map(|voice| voice.state),
+            frames_done: 0,
+        });
+    }
+
+    /// Keep `wavetable` for `send_retired_wavetables`,
+    /// or hand it back if every slot is taken.
+    fn retire(&mut self, wavetable: Arc<Wavetable>) -> Result<(), Arc<Wavetable>> {
+        match self.retired_wavetables.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(wavetable);
+                Ok(())
+            }
+            None => Err(wavetable),
+        }
+    }
+
+    /// Pass the wavetables the synth has stopped playing to `send`,
+    /// to be freed away from the audio thread.
+    ///
+    /// Stops at the first wavetable `send` hands back, keeping it
+    /// and the rest for the next call.
+    pub fn send_retired_wavetables<F>(&mut self, mut send: F)
+    where F: FnMut(Arc<Wavetable>) -> Result<(), Arc<Wavetable>>,
+    {
+        for slot in &mut self.retired_wavetables {
+            if let Some(wavetable) = slot.take() {
+                if let Err(wavetable) = send(wavetable) {
+                    *slot = Some(wavetable);
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn set_voice_allocation(&mut self, allocation: VoiceAllocation) {
+        self.allocation = allocation;
+        self.set_polyphony(allocation.polyphony);
+    }
+
+    /// Change the polyphony limit, fading out voices over the new limit.
+    pub fn set_polyphony(&mut self, polyphony: usize) {
+        let polyphony = polyphony.clamp(1, MAX_POLYPHONY);
+        self.allocation.polyphony = polyphony;
+
+        while self.voices.iter().filter(|voice| voice.is_sounding()).count() > polyphony {
+            let index = self.steal_candidate().expect("voice");
+            self.voices[index].fade_out = Some(0);
+        }
+    }
+
+    /// Switch between poly and mono, releasing all notes.
+    pub fn set_voice_mode(&mut self, mode: VoiceMode) {
+        if mode != self.mode {
+            for voice in &mut self.voices {
+                if voice.is_active() {
+                    voice.release();
+                }
+            }
+            self.note_stack.clear();
+            self.mono_voice = None;
+        }
+        self.mode = mode;
+    }
+
+    pub fn set_glide(&mut self, glide: Glide) {
+        self.glide = glide;
+    }
+
+    /// Full bend down to full bend up. The range is set by the patch.
+    pub fn set_pitch_bend(&mut self, pitch_bend: Bipolar<1>) {
+        self.controllers.pitch_bend.set(pitch_bend.0);
+    }
+
+    pub fn set_mod_wheel(&mut self, mod_wheel: Unipolar<1>) {
+        self.controllers.mod_wheel.set(mod_wheel.0);
+    }
+
+    /// Channel pressure, or aftertouch.
+    pub fn set_pressure(&mut self, pressure: Unipolar<1>) {
+        self.controllers.pressure.set(pressure.0);
+    }
+
+    /// Polyphonic aftertouch on a single held note.
+    ///
+    /// Ignored if the note isn't playing.
+    pub fn set_poly_pressure(&mut self, note: Note, pressure: Unipolar<1>) {
+        if let Some(voice) = self.find_active_voice(note, None) {
+            voice.poly_pressure.set(pressure.0);
+        }
+    }
+
+    /// Per-note CC74, or timbre, for every voice.
+    pub fn set_timbre(&mut self, timbre: Unipolar<1>) {
+        self.controllers.timbre.set(timbre.0);
+    }
+
+    /// The controllers sent on channels other than MPE member channels.
+    pub fn controllers(&self) -> &ChannelControllers {
+        &self.controllers
+    }
+
+    pub fn member_controllers(&self, channel: Channel) -> &ChannelControllers {
+        &self.member_controllers[channel.0 as usize]
+    }
+
+    pub fn mpe_zones(&self) -> MpeZones {
+        self.mpe
+    }
+
+    /// Apply an MPE configuration message received on a manager channel.
+    pub fn configure_mpe(&mut self, manager: Channel, member_channels: u8) {
+        self.mpe.configure(manager, member_channels);
+    }
+
+    /// Set the per-note pitch bend range, in semitones,
+    /// of the zone `channel` is a member of.
+    pub fn set_mpe_pitch_bend_range(&mut self, channel: Channel, semitones: f32) {
+        if let Some(zone) = self.mpe.member_zone_mut(channel) {
+            zone.pitch_bend_range = semitones;
+        }
+    }
+
+    /// Start a note on an MPE member channel.
+    ///
+    /// The note follows the pitch bend, pressure and timbre of its channel,
+    /// starting from the values sent before the note-on.
+    /// MPE notes are always played polyphonically.
+    pub fn note_on_member(&mut self, channel: Channel, note: Note, velocity: Velocity) {
+        self.member_controllers[channel.0 as usize].snap();
+        self.release_pedal_held(note, Some(channel));
+        let index = self.start_voice(note, velocity);
+        self.voices[index].channel = Some(channel);
+    }
+
+    pub fn note_off_member(&mut self, channel: Channel, note: Note) {
+        self.release_key(note, Some(channel));
+    }
+
+    pub fn set_member_pitch_bend(&mut self, channel: Channel, pitch_bend: Bipolar<1>) {
+        self.member_controllers[channel.0 as usize].pitch_bend.set(pitch_bend.0);
+    }
+
+    pub fn set_member_pressure(&mut self, channel: Channel, pressure: Unipolar<1>) {
+        self.member_controllers[channel.0 as usize].pressure.set(pressure.0);
+    }
+
+    pub fn set_member_timbre(&mut self, channel: Channel, timbre: Unipolar<1>) {
+        self.member_controllers[channel.0 as usize].timbre.set(timbre.0);
+    }
+
     pub fn note_on(&mut self, note: Note, velocity: Velocity) {
-        let voice = self.next_voice(note);
-        *voice = Voice {
+        match self.mode {
+            VoiceMode::Poly => {
+                // A note re-struck while a pedal holds it
+                // replaces the held voice instead of piling up.
+                self.release_pedal_held(note, None);
+                self.start_voice(note, velocity);
+            }
+            VoiceMode::Mono { priority, legato } => {
+                self.note_stack.push(note);
+                let note = self.note_stack.select(priority).expect("note");
+                self.play_mono_note(note, velocity, legato);
+            }
+        }
+    }
+
+    pub fn note_off(&mut self, note: Note) {
+        match self.mode {
+            VoiceMode::Poly => {
+                self.release_key(note, None);
+            }
+            VoiceMode::Mono { priority, legato } => {
+                self.note_stack.remove(note);
+                let mono_voice = self.mono_voice.map(|index| self.voices[index]);
+                let Some(mono_voice) = mono_voice.filter(Voice::is_active) else {
+                    return;
+                };
+                match self.note_stack.select(priority) {
+                    Some(next_note) => {
+                        self.play_mono_note(next_note, mono_voice.velocity, legato);
+                    }
+                    None => {
+                        self.release_key(mono_voice.note, None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start a note in a newly allocated voice.
+    fn start_voice(&mut self, note: Note, velocity: Velocity) -> usize {
+        let index = self.allocate_voice(note);
+        let state = st::Layer {
+            noise: st::NoiseState { seed: self.next_seed },
+            ..st::Layer::default()
+        };
+        self.next_seed = self.next_seed.wrapping_add(1);
+        if let Some(fade) = &mut self.fade {
+            fade.states[index] = state;
+        }
+        self.voices[index] = Voice {
             note,
             velocity,
             current_frame_offset: Some(FrameOffset(0)),
             release_frame_offset: None,
-            state: st::Layer::default(),
+            fade_out: None,
+            amp_level: 0.0,
+            pitch: note.0 as f32,
+            glide_from: None,
+            glide_frames_done: 0,
+            key_down: true,
+            sostenuto: false,
+            channel: None,
+            poly_pressure: Smoothed::default(),
+            state,
         };
+        index
     }
 
-    pub fn note_off(&mut self, note: Note) {
-        if let Some(voice) = self.find_active_voice(note) {
-            if voice.release_frame_offset.is_none() {
-                voice.release_frame_offset = voice.current_frame_offset;
-            } else {
-                log::warn!("note {} released twice", note.0);
+    /// Make the mono voice play `note`, if it isn't already.
+    ///
+    /// A held legato mono voice changes pitch, gliding if enabled.
+    /// Otherwise the note restarts in a new voice, gliding from the old one,
+    /// which fades out as if stolen so that its envelope doesn't jump.
+    fn play_mono_note(&mut self, note: Note, velocity: Velocity, legato: bool) {
+        let mono_voice = self.mono_voice.filter(|index| self.voices[*index].is_sounding());
+
+        let Some(index) = mono_voice else {
+            self.mono_voice = Some(self.start_voice(note, velocity));
+            return;
+        };
+
+        let glide = self.glide.time.0 > 0.0;
+        let voice = &mut self.voices[index];
+
+        if voice.is_active() {
+            voice.key_down = true;
+
+            if voice.note == note {
+                return;
             }
+
+            if legato {
+                voice.note = note;
+                if glide {
+                    voice.glide_from = Some(voice.pitch);
+                    voice.glide_frames_done = 0;
+                } else {
+                    voice.pitch = note.0 as f32;
+                    voice.glide_from = None;
+                }
+                return;
+            }
+
+            voice.fade_out = Some(0);
+        }
+
+        // The previous note is in its release or fading out.
+        // Glide from it into a new voice.
+        let glide_from = voice.pitch;
+        let index = self.start_voice(note, velocity);
+        if glide {
+            self.voices[index].pitch = glide_from;
+            self.voices[index].glide_from = Some(glide_from);
+        }
+        self.mono_voice = Some(index);
+    }
+
+    /// The key for `note` has come up.
+    ///
+    /// The voice is released, unless a pedal is holding it.
+    fn release_key(&mut self, note: Note, channel: Option<Channel>) {
+        let sustain = self.sustain;
+        let Some(voice) = self.find_active_voice(note, channel).filter(|voice| voice.key_down) else {
+            log::debug!("note off for note {} without a held key", note.0);
+            return;
+        };
+        voice.key_down = false;
+        if !sustain && !voice.sostenuto {
+            voice.release();
         }
     }
 
-    fn find_active_voice_index(&self, note: Note) -> Option<usize> {
+    /// A note re-struck while a pedal holds it
+    /// replaces the held voice instead of piling up.
+    fn release_pedal_held(&mut self, note: Note, channel: Option<Channel>) {
+        for voice in &mut self.voices {
+            if voice.note == note && voice.channel == channel && voice.is_active() && !voice.key_down {
+                voice.release();
+            }
+        }
+    }
+
+    pub fn sustain(&self) -> bool {
+        self.sustain
+    }
+
+    pub fn sostenuto(&self) -> bool {
+        self.sostenuto
+    }
+
+    /// While down, notes stay held after their keys come up.
+    pub fn set_sustain(&mut self, down: bool) {
+        self.sustain = down;
+        if !down {
+            for voice in &mut self.voices {
+                if voice.is_active() && !voice.key_down && !voice.sostenuto {
+                    voice.release();
+                }
+            }
+        }
+    }
+
+    /// While down, notes whose keys were down when the pedal was pressed
+    /// stay held after their keys come up. Later notes are unaffected.
+    pub fn set_sostenuto(&mut self, down: bool) {
+        if down == self.sostenuto {
+            return;
+        }
+        self.sostenuto = down;
+        let sustain = self.sustain;
+        for voice in &mut self.voices {
+            if down {
+                voice.sostenuto = voice.is_active() && voice.key_down;
+            } else if voice.sostenuto {
+                voice.sostenuto = false;
+                if voice.is_active() && !voice.key_down && !sustain {
+                    voice.release();
+                }
+            }
+        }
+    }
+
+    /// MPE notes are found by their member channel, and other notes by `None`.
+    fn find_active_voice_index(&self, note: Note, channel: Option<Channel>) -> Option<usize> {
         let mut found = None;
         for (index, voice) in self.voices.iter().enumerate() {
-            if voice.note == note && voice.is_active() {
+            if voice.note == note && voice.channel == channel && voice.is_active() {
                 found = Some(index);
             }
         }
         found
     }
 
-    fn find_active_voice(&mut self, note: Note) -> Option<&mut Voice> {
-        self.find_active_voice_index(note).map(|index| {
+    fn find_active_voice(&mut self, note: Note, channel: Option<Channel>) -> Option<&mut Voice> {
+        self.find_active_voice_index(note, channel).map(|index| {
             &mut self.voices[index]
         })
     }
 
-    /// Returns the preferred voice for the next note, without modifying it.
+    /// Returns the index of the voice to play the next note, without modifying it.
     ///
-    /// Just picks the oldest voice.
-    fn next_voice(&mut self, note: Note) -> &mut Voice {
-        let mut oldest: Option<&mut Voice> = None;
-        let mut oldest_index = 0;
-        for (index, voice) in self.voices.iter_mut().enumerate() {
-            if let Some(current_oldest) = oldest {
-                let this_frame_offset = voice.current_frame_offset.unwrap_or(FrameOffset(u32::max_value()));
-                let oldest_frame_offset = current_oldest.current_frame_offset.unwrap_or(FrameOffset(u32::max_value()));
-                if this_frame_offset > oldest_frame_offset {
-                    oldest = Some(voice);
-                    oldest_index = index;
-                } else {
-                    oldest = Some(current_oldest);
-                }
-            } else {
-                oldest = Some(voice);
-            }
+    /// If the note needs to steal a voice, the stolen voice begins fading out,
+    /// and the note is played in a different voice.
+    fn allocate_voice(&mut self, note: Note) -> usize {
+        let same_note = self.allocation.retrigger_same_note.then(|| {
+            self.voices.iter().position(|voice| voice.is_sounding() && voice.note == note)
+        }).flatten();
+        let sounding = self.voices.iter().filter(|voice| voice.is_sounding()).count();
+
+        let stolen = if same_note.is_some() {
+            same_note
+        } else if sounding >= self.allocation.polyphony {
+            self.steal_candidate()
+        } else {
+            None
+        };
+
+        if let Some(stolen) = stolen {
+            log::debug!("stealing voice index {} (note {}) for note {}", stolen, self.voices[stolen].note.0, note.0);
+            self.voices[stolen].fade_out = Some(0);
         }
-        log::debug!("using new voice index {} for note {}", oldest_index, note.0);
-        oldest.unwrap()
+
+        // There is always an idle voice unless many stolen voices are
+        // still fading out, in which case cut short the furthest along fade.
+        let index = self.voices.iter().position(Voice::is_idle)
+            .or_else(|| {
+                self.voices.iter().enumerate()
+                    .filter(|(_, voice)| voice.fade_out.is_some())
+                    .max_by_key(|(_, voice)| voice.fade_out)
+                    .map(|(index, _)| index)
+            })
+            .expect("voice");
+        log::debug!("using voice index {} for note {}", index, note.0);
+        index
+    }
+
+    /// The sounding voice to steal according to the steal policy.
+    fn steal_candidate(&self) -> Option<usize> {
+        let any_released = self.voices.iter()
+            .any(|voice| voice.is_sounding() && voice.release_frame_offset.is_some());
+        let candidates = self.voices.iter().enumerate()
+            .filter(|(_, voice)| voice.is_sounding())
+            .filter(|(_, voice)| voice.release_frame_offset.is_some() || !any_released);
+
+        let candidate = match self.allocation.steal {
+            StealPolicy::Oldest => {
+                candidates.max_by_key(|(_, voice)| voice.current_frame_offset.map(|offset| offset.0))
+            }
+            StealPolicy::Quietest => {
+                candidates.min_by(|(_, a), (_, b)| a.amp_level.total_cmp(&b.amp_level))
+            }
+            StealPolicy::LowNotePriority => {
+                candidates.max_by_key(|(_, voice)| voice.note.0)
+            }
+            StealPolicy::HighNotePriority => {
+                candidates.min_by_key(|(_, voice)| voice.note.0)
+            }
+        };
+
+        candidate.map(|(index, _)| index)
     }
 }
 
 impl Synth {
 
-    fn default_config() -> sc::Layer {
+    /// The patch used by `Synth::new`.
+    ///
+    /// Patches loaded from `.synth2` files start from these values.
+    pub fn default_config() -> sc::Layer {
         sc::Layer {
             osc: sc::Oscillator {
                 kind: sc::OscillatorKind::Saw,
                 gain: Unipolar(1.0),
+                band_limit: sc::BandLimit::PolyBlep,
+                wavetable_position: Unipolar(0.0),
+                pulse_width: Unipolar(0.5),
             },
+            osc2: sc::Oscillator2::default(),
+            fm: sc::Fm::default(),
+            unison: sc::Unison::default(),
             noise: Unipolar(0.0),
+            pan: Bipolar(0.0),
             lpf: sc::LowPassFilter {
                 freq: Hz(200.0),
             },
+            velocity: sc::Velocity::default(),
             amp_env: sc::Adsr {
                 attack: Ms(100.0),
                 decay: Ms(100.0),
@@ -147,66 +892,911 @@ impl Synth {
             modulations: sc::Modulations {
                 mod_env_to_osc_freq: Bipolar(0.0),
                 mod_env_to_lpf_freq: Bipolar(10.0),
+                velocity_to_lpf_freq: Bipolar(0.0),
+                velocity_to_mod_env: Unipolar(0.0),
+                pitch_bend_range: Unipolar(2.0),
+                mod_wheel_to_osc_freq: Bipolar(0.0),
+                mod_wheel_to_lpf_freq: Bipolar(0.0),
+                pressure_to_osc_freq: Bipolar(0.0),
+                pressure_to_lpf_freq: Bipolar(0.0),
+                timbre_to_lpf_freq: Bipolar(0.0),
+                poly_pressure_to_osc_freq: Bipolar(0.0),
+                poly_pressure_to_lpf_freq: Bipolar(0.0),
+                mod_env_to_pan: Bipolar(0.0),
+                timbre_to_pan: Bipolar(0.0),
+                mod_env_to_wavetable_position: Bipolar(0.0),
+                mod_wheel_to_wavetable_position: Bipolar(0.0),
+                timbre_to_wavetable_position: Bipolar(0.0),
+                mod_env_to_pulse_width: Bipolar(0.0),
+                mod_wheel_to_pulse_width: Bipolar(0.0),
+                timbre_to_pulse_width: Bipolar(0.0),
             },
         }
     }
 
+    /// Render mono, mixing the left and right channels.
     pub fn sample(&mut self,
                   buffer: &mut [f32],
                   sample_rate: SampleRateKhz) {
 
-        let mut chunks = buffer.array_chunks_mut::<16>();
+        for chunk in buffer.chunks_mut(16) {
+            let mut left = [0.0; 16];
+            let mut right = [0.0; 16];
+            let frames = chunk.len();
+            self.accumulate_frames(&mut left[..frames], &mut right[..frames], sample_rate);
+            for (sample, (left, right)) in chunk.iter_mut().zip(left.iter().zip(right)) {
+                *sample = (left + right) * 0.5;
+            }
+        }
+    }
+
+    /// Render planar stereo.
+    pub fn sample_stereo(&mut self,
+                         buffer_left: &mut [f32],
+                         buffer_right: &mut [f32],
+                         sample_rate: SampleRateKhz) {
+        assert_eq!(buffer_left.len(), buffer_right.len());
+
+        let mut chunks_left = buffer_left.array_chunks_mut::<16>();
+        let mut chunks_right = buffer_right.array_chunks_mut::<16>();
 
-        while let Some(chunk) = chunks.next() {
-            self.accumulate_frames(chunk, sample_rate);
+        while let (Some(chunk_left), Some(chunk_right)) = (chunks_left.next(), chunks_right.next()) {
+            self.accumulate_frames(chunk_left, chunk_right, sample_rate);
         }
 
-        let mut remainder = chunks.into_remainder();
+        let remainder_left = chunks_left.into_remainder();
+        let remainder_right = chunks_right.into_remainder();
 
-        if remainder.len() > 0 {
-            self.accumulate_frames(remainder, sample_rate);
+        if !remainder_left.is_empty() {
+            self.accumulate_frames(remainder_left, remainder_right, sample_rate);
         }
     }
 
+    /// Render planar stereo, applying each event exactly at its frame.
+    ///
+    /// Events must be in frame order. Events for frames past the end
+    /// of the buffer are applied after rendering all of it.
+    pub fn sample_events<E>(&mut self,
+                            buffer_left: &mut [f32],
+                            buffer_right: &mut [f32],
+                            sample_rate: SampleRateKhz,
+                            events: impl IntoIterator<Item = TimedEvent<E>>,
+                            mut apply: impl FnMut(&mut Synth, E)) {
+        assert_eq!(buffer_left.len(), buffer_right.len());
+        let mut rendered = 0;
+
+        for TimedEvent { frame, event } in events {
+            let frame = frame.clamp(rendered, buffer_left.len());
+            self.sample_stereo(
+                &mut buffer_left[rendered..frame],
+                &mut buffer_right[rendered..frame],
+                sample_rate,
+            );
+            rendered = frame;
+            apply(self, event);
+        }
+
+        self.sample_stereo(&mut buffer_left[rendered..], &mut buffer_right[rendered..], sample_rate);
+    }
+
     fn accumulate_frames(&mut self,
-                         buffer: &mut [f32],
+                         buffer_left: &mut [f32],
+                         buffer_right: &mut [f32],
                          sample_rate: SampleRateKhz) {
-        debug_assert!(buffer.len() <= 16);
-        let needed_frames = buffer.len();
-        let mut accum = f32x16::splat(0.0);
-        for voice in &mut self.voices {
+        debug_assert!(buffer_left.len() <= 16);
+        debug_assert_eq!(buffer_left.len(), buffer_right.len());
+        let needed_frames = buffer_left.len();
+        let mut accum_left = f32x16::splat(0.0);
+        let mut accum_right = f32x16::splat(0.0);
+
+        // Linear gains of the current and previous config for each frame.
+        let fade_gains = self.fade.as_ref().map(|fade| {
+            let fade_frames = CONFIG_FADE.as_samples(sample_rate).0.max(1.0);
+            let gains: [f32; 16] = std::array::from_fn(|i| {
+                ((fade.frames_done as f32 + i as f32) / fade_frames).min(1.0)
+            });
+            let gains = f32x16::from_array(gains);
+            (gains, f32x16::splat(1.0) - gains)
+        });
+
+        let steal_fade_frames = STEAL_FADE.as_samples(sample_rate).0.max(1.0);
+
+        let channel_controllers = self.controllers.next_frames(needed_frames, sample_rate);
+        let member_controllers = self.mpe.is_enabled().then(|| {
+            self.member_controllers.each_mut().map(|controllers| {
+                controllers.next_frames(needed_frames, sample_rate)
+            })
+        });
+
+        for (index, voice) in self.voices.iter_mut().enumerate() {
             if let Some(current_frame_offset) = voice.current_frame_offset {
-                let pitch = note_to_pitch(voice.note);
-                let offset = current_frame_offset.0;
+                let target_pitch = voice.note.0 as f32;
+                let glide_frames = voice.glide_from.map(|glide_from| {
+                    self.glide.frames(glide_from, target_pitch, sample_rate).max(1.0)
+                });
+                voice.pitch = match (voice.glide_from, glide_frames) {
+                    (Some(glide_from), Some(glide_frames)) => {
+                        let progress = (voice.glide_frames_done as f32 / glide_frames).min(1.0);
+                        glide_from + (target_pitch - glide_from) * progress
+                    }
+                    _ => target_pitch,
+                };
+                let mut controllers = match (voice.channel, &member_controllers) {
+                    (Some(channel), Some(member_controllers)) => {
+                        let member = &member_controllers[channel.0 as usize];
+                        let pitch_bend_range = self.mpe.member_zone(channel)
+                            .map(|zone| zone.pitch_bend_range)
+                            .unwrap_or(0.0);
+                        std::array::from_fn(|i| {
+                            channel_controllers[i].with_member(member[i], pitch_bend_range)
+                        })
+                    }
+                    _ => channel_controllers,
+                };
+                let poly_pressure = voice.poly_pressure.next_frames(needed_frames, sample_rate);
+                for (frame, poly_pressure) in controllers.iter_mut().zip(poly_pressure) {
+                    frame.poly_pressure = Unipolar(poly_pressure);
+                }
                 let release_offset = voice.release_frame_offset.map(|v| v.0);
 
-                let mut buf = [0.0; 16];
-                process::process_layer_buf_simd(
+                let inputs = process::VoiceInputs {
+                    pitch: note_to_pitch(voice.pitch),
+                    velocity: voice.velocity.0,
+                    controllers: &controllers[..needed_frames],
+                    offset: current_frame_offset.0,
+                    release_offset,
+                };
+
+                let (buf_left, buf_right) = render_voice(
                     &self.config,
+                    &self.wavetable,
                     &mut voice.state,
-                    pitch,
+                    inputs,
+                    sample_rate,
+                );
+                let mut peak = buf_left.abs().reduce_max().max(buf_right.abs().reduce_max());
+
+                let voice_gains = match voice.fade_out {
+                    Some(fade_frames_done) => {
+                        let gains: [f32; 16] = std::array::from_fn(|i| {
+                            1.0 - ((fade_frames_done as f32 + i as f32) / steal_fade_frames).min(1.0)
+                        });
+                        f32x16::from_array(gains)
+                    }
+                    None => f32x16::splat(1.0),
+                };
+
+                if let (Some(fade), Some((new_gains, old_gains))) = (&mut self.fade, fade_gains) {
+                    let (old_left, old_right) = render_voice(
+                        &fade.config,
+                        &fade.wavetable,
+                        &mut fade.states[index],
+                        inputs,
+                        sample_rate,
+                    );
+                    peak = peak.max(old_left.abs().reduce_max()).max(old_right.abs().reduce_max());
+                    accum_left += (buf_left * new_gains + old_left * old_gains) * voice_gains;
+                    accum_right += (buf_right * new_gains + old_right * old_gains) * voice_gains;
+                } else {
+                    accum_left += buf_left * voice_gains;
+                    accum_right += buf_right * voice_gains;
+                }
+
+                let next_frame_offset = current_frame_offset.0.saturating_add(needed_frames as u32);
+                voice.current_frame_offset = Some(FrameOffset(next_frame_offset));
+
+                if let Some(glide_frames) = glide_frames {
+                    voice.glide_frames_done = voice.glide_frames_done.saturating_add(needed_frames as u32);
+                    if voice.glide_frames_done as f32 >= glide_frames {
+                        voice.glide_from = None;
+                        voice.pitch = target_pitch;
+                    }
+                }
+
+                let finished = process::envelope_finished(
+                    self.config.amp_env,
                     sample_rate,
-                    offset,
+                    next_frame_offset,
                     release_offset,
-                    &mut buf[..needed_frames],
                 );
+                let fade_finished = self.fade.as_ref().map(|fade| {
+                    process::envelope_finished(fade.config.amp_env, sample_rate, next_frame_offset, release_offset)
+                }).unwrap_or(true);
 
-                let buf = f32x16::from_array(buf);
-                accum += buf;
+                voice.amp_level = process::sample_envelope(
+                    self.config.amp_env,
+                    sample_rate,
+                    next_frame_offset,
+                    release_offset,
+                ).0 * process::velocity_gain(&self.config, voice.velocity.0);
+
+                if let Some(fade_frames_done) = voice.fade_out {
+                    let fade_frames_done = fade_frames_done.saturating_add(needed_frames as u32);
+                    voice.fade_out = Some(fade_frames_done);
+                    if fade_frames_done as f32 >= steal_fade_frames {
+                        log::debug!("voice index {} for note {} faded out", index, voice.note.0);
+                        voice.set_idle();
+                    }
+                } else if finished && fade_finished && peak < SILENCE_THRESHOLD {
+                    log::debug!("voice index {} for note {} finished", index, voice.note.0);
+                    voice.set_idle();
+                }
+            }
+        }
 
-                voice.current_frame_offset = Some(FrameOffset(current_frame_offset.0.saturating_add(needed_frames as u32)));
+        if let Some(fade) = &mut self.fade {
+            fade.frames_done = fade.frames_done.saturating_add(needed_frames as u32);
+            if fade.frames_done as f32 >= CONFIG_FADE.as_samples(sample_rate).0 {
+                let fade = self.fade.take().expect("fade");
+                match self.retire(fade.wavetable) {
+                    Ok(()) => {
+                        if let Some((config, wavetable)) = self.next_patch.take() {
+                            self.start_fade(config, wavetable);
+                        }
+                    }
+                    // The old patch is silent by now, so it can keep
+                    // its wavetable until there's room to retire it.
+                    Err(wavetable) => self.fade = Some(ConfigFade { wavetable, ..fade }),
+                }
             }
         }
 
-        let accum = accum.to_array();
-        buffer.copy_from_slice(&accum[..needed_frames]);
+        buffer_left.copy_from_slice(&accum_left.to_array()[..needed_frames]);
+        buffer_right.copy_from_slice(&accum_right.to_array()[..needed_frames]);
     }
 
 }
 
+/// Up to 16 frames of one voice, left and right.
+fn render_voice(
+    config: &sc::Layer,
+    wavetable: &Wavetable,
+    state: &mut st::Layer,
+    inputs: process::VoiceInputs,
+    sample_rate: SampleRateKhz,
+) -> (f32x16, f32x16) {
+    let frames = inputs.controllers.len();
+    let mut buf_left = [0.0; 16];
+    let mut buf_right = [0.0; 16];
+    process::process_layer_buf_simd(
+        config,
+        wavetable,
+        state,
+        inputs,
+        sample_rate,
+        &mut buf_left[..frames],
+        &mut buf_right[..frames],
+    );
+    (f32x16::from_array(buf_left), f32x16::from_array(buf_right))
+}
+
 // todo lookup table
-fn note_to_pitch(note: Note) -> Hz {
-    let note = note.0 as f32;
+fn note_to_pitch(note: f32) -> Hz {
     let freq = 440.0 * 2_f32.powf((note - 69.0) / 12.0);
     Hz(freq)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::units::Cents;
+
+    const SAMPLE_RATE: SampleRateKhz = SampleRateKhz(48000);
+
+    fn render(synth: &mut Synth, ms: f32) {
+        let frames = Ms(ms).as_samples(SAMPLE_RATE).0 as usize;
+        let mut buf = vec![0.0; frames];
+        synth.sample(&mut buf, SAMPLE_RATE);
+    }
+
+    /// The largest change between consecutive samples.
+    fn max_step(buf: &[f32]) -> f32 {
+        buf.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max)
+    }
+
+    fn velocity() -> Velocity {
+        Velocity(Unipolar(1.0))
+    }
+
+    fn sounding_notes(synth: &Synth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth.voices.iter()
+            .filter(|voice| voice.is_sounding())
+            .map(|voice| voice.note.0)
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    fn fading_notes(synth: &Synth) -> Vec<u8> {
+        synth.voices.iter()
+            .filter(|voice| !voice.is_idle() && !voice.is_sounding())
+            .map(|voice| voice.note.0)
+            .collect()
+    }
+
+    /// Start `notes` in order, 1ms apart.
+    fn play_notes(synth: &mut Synth, notes: &[u8]) {
+        for note in notes {
+            synth.note_on(Note(*note), velocity());
+            render(synth, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_voices_become_idle_after_release() {
+        let mut synth = Synth::new();
+        synth.note_on(Note(60), velocity());
+        synth.note_on(Note(64), velocity());
+        render(&mut synth, 50.0);
+        assert_eq!(sounding_notes(&synth), vec![60, 64]);
+
+        synth.note_off(Note(60));
+        // Attack + decay + release of the default patch, and then some.
+        render(&mut synth, 400.0);
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        synth.note_off(Note(64));
+        render(&mut synth, 150.0);
+        assert!(sounding_notes(&synth).is_empty());
+    }
+
+    #[test]
+    fn test_allocation_prefers_idle_voices() {
+        let mut synth = Synth::new();
+        play_notes(&mut synth, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(sounding_notes(&synth), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        // A released voice is stolen before older held voices.
+        synth.note_off(Note(5));
+        synth.note_on(Note(100), velocity());
+        assert_eq!(sounding_notes(&synth), vec![0, 1, 2, 3, 4, 6, 7, 100]);
+        assert_eq!(fading_notes(&synth), vec![5]);
+
+        // Stolen voices fade out quickly.
+        render(&mut synth, 10.0);
+        assert!(fading_notes(&synth).is_empty());
+
+        // A voice freed by its release is used before stealing.
+        synth.note_off(Note(3));
+        render(&mut synth, 400.0);
+        synth.note_on(Note(101), velocity());
+        assert_eq!(sounding_notes(&synth), vec![0, 1, 2, 4, 6, 7, 100, 101]);
+        assert!(fading_notes(&synth).is_empty());
+
+        // Otherwise the oldest voice is stolen.
+        synth.note_on(Note(102), velocity());
+        assert_eq!(sounding_notes(&synth), vec![1, 2, 4, 6, 7, 100, 101, 102]);
+        assert_eq!(fading_notes(&synth), vec![0]);
+    }
+
+    #[test]
+    fn test_steal_policies() {
+        let steal = |policy: StealPolicy| {
+            let mut synth = Synth::new();
+            synth.set_voice_allocation(VoiceAllocation {
+                polyphony: 4,
+                steal: policy,
+                retrigger_same_note: false,
+            });
+            play_notes(&mut synth, &[60, 40, 80, 70]);
+            synth.note_on(Note(50), velocity());
+            fading_notes(&synth)
+        };
+
+        assert_eq!(steal(StealPolicy::Oldest), vec![60]);
+        // Later notes are still in their attack.
+        assert_eq!(steal(StealPolicy::Quietest), vec![70]);
+        assert_eq!(steal(StealPolicy::LowNotePriority), vec![80]);
+        assert_eq!(steal(StealPolicy::HighNotePriority), vec![40]);
+    }
+
+    #[test]
+    fn test_retrigger_same_note() {
+        let mut synth = Synth::new();
+        play_notes(&mut synth, &[60, 60]);
+        assert_eq!(sounding_notes(&synth), vec![60, 60]);
+
+        let mut synth = Synth::new();
+        synth.set_voice_allocation(VoiceAllocation {
+            retrigger_same_note: true,
+            ..VoiceAllocation::default()
+        });
+        play_notes(&mut synth, &[60, 60]);
+        assert_eq!(sounding_notes(&synth), vec![60]);
+        assert_eq!(fading_notes(&synth), vec![60]);
+    }
+
+    #[test]
+    fn test_set_polyphony() {
+        let mut synth = Synth::new();
+        play_notes(&mut synth, &[0, 1, 2, 3, 4, 5]);
+        synth.set_polyphony(4);
+        assert_eq!(sounding_notes(&synth), vec![2, 3, 4, 5]);
+        assert_eq!(fading_notes(&synth).len(), 2);
+
+        synth.note_on(Note(6), velocity());
+        assert_eq!(sounding_notes(&synth), vec![3, 4, 5, 6]);
+    }
+
+    fn mono_synth(priority: NotePriority, legato: bool) -> Synth {
+        let mut synth = Synth::new();
+        synth.set_voice_mode(VoiceMode::Mono { priority, legato });
+        synth
+    }
+
+    fn mono_voice(synth: &Synth) -> Voice {
+        synth.voices[synth.mono_voice.expect("mono voice")]
+    }
+
+    #[test]
+    fn test_mono_note_stack() {
+        let mut synth = mono_synth(NotePriority::Last, false);
+        play_notes(&mut synth, &[60, 64, 67]);
+        assert_eq!(sounding_notes(&synth), vec![67]);
+
+        // Releasing the playing note returns to the previous held note.
+        synth.note_off(Note(67));
+        assert_eq!(sounding_notes(&synth), vec![64]);
+
+        // Releasing a note that isn't playing changes nothing.
+        synth.note_off(Note(60));
+        assert_eq!(sounding_notes(&synth), vec![64]);
+        assert!(mono_voice(&synth).is_active());
+
+        synth.note_off(Note(64));
+        assert!(!mono_voice(&synth).is_active());
+    }
+
+    #[test]
+    fn test_mono_priority() {
+        let mut synth = mono_synth(NotePriority::Low, false);
+        play_notes(&mut synth, &[60, 55, 64]);
+        assert_eq!(mono_voice(&synth).note.0, 55);
+        synth.note_off(Note(55));
+        assert_eq!(mono_voice(&synth).note.0, 60);
+
+        let mut synth = mono_synth(NotePriority::High, false);
+        play_notes(&mut synth, &[60, 55, 64]);
+        assert_eq!(mono_voice(&synth).note.0, 64);
+        synth.note_off(Note(64));
+        assert_eq!(mono_voice(&synth).note.0, 60);
+    }
+
+    #[test]
+    fn test_legato() {
+        let mut synth = mono_synth(NotePriority::Last, false);
+        play_notes(&mut synth, &[60, 64]);
+        // Retriggered one millisecond ago.
+        assert!(mono_voice(&synth).current_frame_offset.expect("offset").0 < 100);
+
+        let mut synth = mono_synth(NotePriority::Last, true);
+        play_notes(&mut synth, &[60, 64]);
+        assert!(mono_voice(&synth).current_frame_offset.expect("offset").0 >= 96);
+        assert_eq!(mono_voice(&synth).note.0, 64);
+    }
+
+    #[test]
+    fn test_mono_retrigger_is_continuous() {
+
+        let mut synth = mono_synth(NotePriority::Last, false);
+        synth.config.osc.kind = sc::OscillatorKind::Sine;
+        synth.note_on(Note(60), velocity());
+        render(&mut synth, 300.0);
+        let mut before = vec![0.0; 1000];
+        synth.sample(&mut before, SAMPLE_RATE);
+
+        synth.note_on(Note(61), velocity());
+        assert_eq!(sounding_notes(&synth), vec![61]);
+        assert_eq!(fading_notes(&synth), vec![60]);
+        let mut after = vec![0.0; 1000];
+        synth.sample(&mut after, SAMPLE_RATE);
+
+        let steady_step = max_step(&before);
+        let step = max_step(&[&before[before.len() - 1..], &after[..]].concat());
+        assert!(step <= steady_step * 1.2, "{} > {}", step, steady_step);
+    }
+
+    #[test]
+    fn test_glide() {
+        let mut synth = mono_synth(NotePriority::Last, true);
+        synth.set_glide(Glide {
+            time: Ms(100.0),
+            kind: GlideKind::ConstantTime,
+        });
+        play_notes(&mut synth, &[60]);
+        synth.note_on(Note(72), velocity());
+        render(&mut synth, 50.0);
+        let pitch = mono_voice(&synth).pitch;
+        assert!(pitch > 65.0 && pitch < 67.0, "{}", pitch);
+        render(&mut synth, 60.0);
+        assert_eq!(mono_voice(&synth).pitch, 72.0);
+
+        // Constant rate glides take longer over larger intervals.
+        synth.set_glide(Glide {
+            time: Ms(100.0),
+            kind: GlideKind::ConstantRate,
+        });
+        synth.note_on(Note(48), velocity());
+        render(&mut synth, 150.0);
+        let pitch = mono_voice(&synth).pitch;
+        assert!(pitch > 52.0 && pitch < 56.0, "{}", pitch);
+        render(&mut synth, 60.0);
+        assert_eq!(mono_voice(&synth).pitch, 48.0);
+    }
+
+    #[test]
+    fn test_unison_without_detune() {
+        // Identical in-phase copies only change the level.
+        let render_note = |unison: sc::Unison| {
+            let mut synth = Synth::new();
+            synth.config.unison = unison;
+            synth.note_on(Note(60), velocity());
+            let mut buf = vec![0.0; 1000];
+            synth.sample(&mut buf, SAMPLE_RATE);
+            buf
+        };
+
+        let single = render_note(sc::Unison::default());
+        let unison = render_note(sc::Unison {
+            voices: 4,
+            ..sc::Unison::default()
+        });
+
+        for (single, unison) in single.iter().zip(unison.iter()) {
+            assert!((single * 2.0 - unison).abs() < 1.0e-4, "{} {}", single, unison);
+        }
+
+        let detuned = render_note(sc::Unison {
+            voices: 4,
+            detune: Cents(20.0),
+            phase: sc::UnisonPhase::Random,
+            ..sc::Unison::default()
+        });
+        assert_ne!(unison, detuned);
+    }
+
+    #[test]
+    fn test_velocity_sensitivity() {
+        let render_note = |velocity: sc::Velocity, note_velocity: f32| {
+            let mut synth = Synth::new();
+            synth.config.velocity = velocity;
+            synth.note_on(Note(60), Velocity(Unipolar(note_velocity)));
+            let mut buf = vec![0.0; 4800];
+            synth.sample(&mut buf, SAMPLE_RATE);
+            buf.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()))
+        };
+
+        let default = sc::Velocity::default();
+        let loud = render_note(default, 1.0);
+        let soft = render_note(default, 0.25);
+        assert!((soft / loud - 1.0).abs() < 1.0e-3, "{} {}", soft, loud);
+
+        let linear = sc::Velocity { sensitivity: Unipolar(1.0), ..default };
+        let soft = render_note(linear, 0.25);
+        assert!((soft / loud - 0.25).abs() < 1.0e-3, "{} {}", soft, loud);
+
+        let hard = sc::Velocity { curve: sc::VelocityCurve::Hard, ..linear };
+        let soft = render_note(hard, 0.5);
+        assert!((soft / loud - 0.25).abs() < 1.0e-3, "{} {}", soft, loud);
+
+        let half = sc::Velocity { sensitivity: Unipolar(0.5), ..linear };
+        let soft = render_note(half, 0.25);
+        assert!((soft / loud - 0.625).abs() < 1.0e-3, "{} {}", soft, loud);
+    }
+
+    /// Zero crossings in the next 50ms, twice the frequency in 10hz.
+    fn zero_crossings(synth: &mut Synth) -> usize {
+        let mut buf = vec![0.0; Ms(50.0).as_samples(SAMPLE_RATE).0 as usize];
+        synth.sample(&mut buf, SAMPLE_RATE);
+        buf.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count()
+    }
+
+    /// A pure sine, for counting zero crossings.
+    fn sine_synth() -> Synth {
+        let mut synth = Synth::new();
+        synth.config.osc.kind = sc::OscillatorKind::Sine;
+        synth.config.lpf.freq = Hz(20000.0);
+        synth.config.modulations.mod_env_to_lpf_freq = Bipolar(0.0);
+        synth
+    }
+
+    #[test]
+    fn test_pitch_bend() {
+        let mut synth = sine_synth();
+        synth.config.modulations.pitch_bend_range = Unipolar(12.0);
+
+        // A4, 440hz.
+        synth.note_on(Note(69), velocity());
+        render(&mut synth, 100.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((43..=45).contains(&crossings), "{}", crossings);
+
+        synth.set_pitch_bend(Bipolar(1.0));
+        render(&mut synth, 50.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((87..=89).contains(&crossings), "{}", crossings);
+
+        synth.set_pitch_bend(Bipolar(-1.0));
+        render(&mut synth, 50.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((21..=23).contains(&crossings), "{}", crossings);
+    }
+
+    #[test]
+    fn test_poly_pressure() {
+        let mut synth = sine_synth();
+        synth.config.modulations.poly_pressure_to_osc_freq = Bipolar(1.0);
+
+        // A4, 440hz.
+        synth.note_on(Note(69), velocity());
+        // Pressure on a note that isn't playing does nothing.
+        synth.set_poly_pressure(Note(70), Unipolar(1.0));
+        render(&mut synth, 100.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((43..=45).contains(&crossings), "{}", crossings);
+
+        synth.set_poly_pressure(Note(69), Unipolar(1.0));
+        render(&mut synth, 50.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((87..=89).contains(&crossings), "{}", crossings);
+    }
+
+    #[test]
+    fn test_sample_events() {
+        let mut synth = Synth::new();
+        let mut left = [0.0; 256];
+        let mut right = [0.0; 256];
+        let events = [
+            TimedEvent { frame: 100, event: Note(60) },
+            TimedEvent { frame: 1000, event: Note(64) },
+        ];
+        synth.sample_events(&mut left, &mut right, SAMPLE_RATE, events, |synth, note| {
+            synth.note_on(note, velocity());
+        });
+
+        assert!(left[..100].iter().all(|s| *s == 0.0));
+        assert!(left[100..].iter().any(|s| *s != 0.0));
+        assert_eq!(left, right);
+        // Applied after the buffer.
+        assert_eq!(active_notes(&synth), vec![60, 64]);
+    }
+
+    #[test]
+    fn test_pan() {
+        let mut synth = Synth::new();
+        synth.config.pan = Bipolar(-1.0);
+        synth.note_on(Note(60), velocity());
+
+        let mut left = [0.0; 1000];
+        let mut right = [0.0; 1000];
+        synth.sample_stereo(&mut left, &mut right, SAMPLE_RATE);
+        assert!(left.iter().any(|s| *s != 0.0));
+        assert!(right.iter().all(|s| *s == 0.0));
+
+        // Modulated back to the center.
+        synth.config.modulations.mod_env_to_pan = Bipolar(1.0);
+        synth.config.mod_env.sustain = Unipolar(1.0);
+        synth.note_on(Note(64), velocity());
+        render(&mut synth, 10.0);
+        synth.sample_stereo(&mut left, &mut right, SAMPLE_RATE);
+        assert!(right.iter().any(|s| *s != 0.0));
+    }
+
+    fn active_notes(synth: &Synth) -> Vec<u8> {
+        let mut notes: Vec<u8> = synth.voices.iter()
+            .filter(|voice| voice.is_active())
+            .map(|voice| voice.note.0)
+            .collect();
+        notes.sort();
+        notes
+    }
+
+    #[test]
+    fn test_sustain_pedal() {
+        let mut synth = Synth::new();
+        play_notes(&mut synth, &[60, 64]);
+        synth.set_sustain(true);
+        synth.note_off(Note(60));
+        synth.note_off(Note(64));
+        // Played after the pedal, but still held by it.
+        play_notes(&mut synth, &[67]);
+        synth.note_off(Note(67));
+        render(&mut synth, 10.0);
+        assert_eq!(active_notes(&synth), vec![60, 64, 67]);
+
+        // Re-striking a held note replaces its voice.
+        play_notes(&mut synth, &[60]);
+        assert_eq!(active_notes(&synth), vec![60, 64, 67]);
+        assert_eq!(sounding_notes(&synth), vec![60, 60, 64, 67]);
+
+        synth.set_sustain(false);
+        assert_eq!(active_notes(&synth), vec![60]);
+
+        synth.note_off(Note(60));
+        assert!(active_notes(&synth).is_empty());
+    }
+
+    #[test]
+    fn test_sostenuto_pedal() {
+        let mut synth = Synth::new();
+        play_notes(&mut synth, &[60, 64]);
+        synth.note_off(Note(64));
+        synth.set_sostenuto(true);
+        play_notes(&mut synth, &[67]);
+
+        synth.note_off(Note(60));
+        synth.note_off(Note(67));
+        assert_eq!(active_notes(&synth), vec![60]);
+
+        // Sustain doesn't end with the sostenuto pedal.
+        synth.set_sustain(true);
+        play_notes(&mut synth, &[72]);
+        synth.note_off(Note(72));
+        synth.set_sostenuto(false);
+        assert_eq!(active_notes(&synth), vec![60, 72]);
+
+        synth.set_sustain(false);
+        assert!(active_notes(&synth).is_empty());
+    }
+
+    #[test]
+    fn test_mpe_member_expression() {
+        let mut synth = sine_synth();
+        synth.configure_mpe(super::super::mpe::LOWER_MANAGER, 15);
+
+        // A4, 440hz.
+        synth.note_on_member(Channel(1), Note(69), velocity());
+        render(&mut synth, 100.0);
+
+        // Other channels don't bend this note.
+        synth.set_member_pitch_bend(Channel(2), Bipolar(1.0));
+        render(&mut synth, 50.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((43..=45).contains(&crossings), "{}", crossings);
+
+        // A quarter of the default 48 semitone range.
+        synth.set_member_pitch_bend(Channel(1), Bipolar(0.25));
+        render(&mut synth, 50.0);
+        let crossings = zero_crossings(&mut synth);
+        assert!((87..=89).contains(&crossings), "{}", crossings);
+
+        // Bend sent before the note-on applies from the start.
+        synth.note_off_member(Channel(1), Note(69));
+        synth.set_member_pitch_bend(Channel(3), Bipolar(0.25));
+        synth.note_on_member(Channel(3), Note(57), velocity());
+        render(&mut synth, 300.0);
+        assert_eq!(active_notes(&synth), vec![57]);
+        let crossings = zero_crossings(&mut synth);
+        assert!((43..=45).contains(&crossings), "{}", crossings);
+
+        synth.note_off(Note(57));
+        assert_eq!(active_notes(&synth), vec![57]);
+        synth.note_off_member(Channel(3), Note(57));
+        assert!(active_notes(&synth).is_empty());
+    }
+
+    #[test]
+    fn test_set_config_crossfades() {
+        let peak = |buf: &[f32]| buf.iter().map(|s| s.abs()).fold(0.0, f32::max);
+
+        let mut synth = Synth::new();
+        synth.config.osc.kind = sc::OscillatorKind::Sine;
+        synth.note_on(Note(60), velocity());
+        render(&mut synth, 300.0);
+        let mut before = vec![0.0; 1000];
+        synth.sample(&mut before, SAMPLE_RATE);
+
+        // Dropping the sustain level of a held note would be a step without the crossfade.
+        let mut config = synth.config;
+        config.amp_env.sustain = Unipolar(0.1);
+        assert!(synth.set_config(config).is_none());
+        let mut after = vec![0.0; 1000];
+        synth.sample(&mut after, SAMPLE_RATE);
+
+        let steady_step = max_step(&before);
+        let step = max_step(&[&before[before.len() - 1..], &after[..]].concat());
+        assert!(step <= steady_step * 1.1, "{} > {}", step, steady_step);
+
+        render(&mut synth, 100.0);
+        let mut settled = vec![0.0; 1000];
+        synth.sample(&mut settled, SAMPLE_RATE);
+        let ratio = peak(&settled) / peak(&before);
+        assert!((ratio - 0.2).abs() < 0.02, "{}", ratio);
+    }
+
+    #[test]
+    fn test_set_config_during_crossfade() {
+        let mut synth = Synth::new();
+        synth.config.osc.kind = sc::OscillatorKind::Sine;
+        synth.note_on(Note(60), velocity());
+        render(&mut synth, 300.0);
+        let mut before = vec![0.0; 1000];
+        synth.sample(&mut before, SAMPLE_RATE);
+
+        // Back up to full level a quarter of the way into the fade down,
+        // which would drop the rest of the full level from the first fade
+        // if it were cut off.
+        let full = synth.config;
+        let mut config = full;
+        config.amp_env.sustain = Unipolar(0.1);
+        assert!(synth.set_config(config).is_none());
+        let mut during = vec![0.0; 240];
+        synth.sample(&mut during, SAMPLE_RATE);
+        assert!(synth.set_config(full).is_none());
+        let mut after = vec![0.0; 4800];
+        synth.sample(&mut after, SAMPLE_RATE);
+
+        let steady_step = max_step(&before);
+        let step = max_step(&[&before[before.len() - 1..], &during[..], &after[..]].concat());
+        assert!(step <= steady_step * 1.1, "{} > {}", step, steady_step);
+    }
+
+    fn test_wavetable() -> Arc<Wavetable> {
+        Arc::new(Wavetable::from_frames(&[&[0.0, 1.0, 0.0, -1.0]]).expect("wavetable"))
+    }
+
+    fn take_retired_wavetables(synth: &mut Synth) -> Vec<Arc<Wavetable>> {
+        let mut retired = vec![];
+        synth.send_retired_wavetables(|wavetable| {
+            retired.push(wavetable);
+            Ok(())
+        });
+        retired
+    }
+
+    #[test]
+    fn test_set_patch_retires_wavetables() {
+        let first = test_wavetable();
+        let second = test_wavetable();
+        let mut synth = Synth::new();
+        assert!(synth.set_patch(synth.config, first.clone()).is_none());
+        assert!(synth.set_patch(synth.config, second.clone()).is_none());
+        assert!(synth.set_patch(synth.config, Wavetable::sine()).is_none());
+        synth.note_on(Note(60), velocity());
+
+        // The second patch never played, so it goes at once.
+        let retired = take_retired_wavetables(&mut synth);
+        assert_eq!(retired.len(), 1);
+        assert!(Arc::ptr_eq(&retired[0], &second));
+
+        // The others go as their crossfades finish, one after the other.
+        render(&mut synth, 100.0);
+        let retired = take_retired_wavetables(&mut synth);
+        assert_eq!(retired.len(), 2);
+        assert!(Arc::ptr_eq(&retired[1], &first));
+        assert!(Arc::ptr_eq(&synth.wavetable, &Wavetable::sine()));
+    }
+
+    #[test]
+    fn test_set_patch_waits_for_retired_wavetables() {
+        let first = test_wavetable();
+        let mut synth = Synth::new();
+        assert!(synth.set_patch(synth.config, first.clone()).is_none());
+        assert!(synth.set_patch(synth.config, test_wavetable()).is_none());
+        for _ in 0..MAX_RETIRED_WAVETABLES {
+            assert!(synth.set_patch(synth.config, test_wavetable()).is_none());
+        }
+
+        // Replacing the waiting patch would need another slot.
+        let refused = test_wavetable();
+        let (_, handed_back) = synth.set_patch(synth.config, refused.clone()).expect("refused");
+        assert!(Arc::ptr_eq(&handed_back, &refused));
+
+        // Nor is there room for the wavetable the crossfade is done with,
+        // so the waiting patch doesn't start.
+        render(&mut synth, 100.0);
+        assert!(Arc::ptr_eq(&synth.wavetable, &first));
+
+        // A send that fails keeps everything for later.
+        synth.send_retired_wavetables(Err);
+        let retired = take_retired_wavetables(&mut synth);
+        assert_eq!(retired.len(), MAX_RETIRED_WAVETABLES);
+
+        // Then the waiting patch plays, retiring the starting sine and `first`.
+        render(&mut synth, 100.0);
+        let retired = take_retired_wavetables(&mut synth);
+        assert_eq!(retired.len(), 2);
+        assert!(Arc::ptr_eq(&retired[1], &first));
+        assert!(synth.set_patch(synth.config, refused).is_none());
+    }
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the tests of several modules.
+
+use super::units::*;
+
+/// Assert that rendering 16 frames at a time matches rendering one at a time.
+///
+/// `scalar` renders the frame it's given and `x16` the 16 frames from the one
+/// it's given, each carrying its own state from call to call.
+pub fn assert_x16_matches_scalar(
+    chunks: usize,
+    tolerance: f32,
+    mut scalar: impl FnMut(usize) -> f32,
+    mut x16: impl FnMut(usize) -> [f32; 16],
+) {
+    for chunk in 0..chunks {
+        let start = chunk * 16;
+        let expected: [f32; 16] = std::array::from_fn(|lane| scalar(start + lane));
+        let actual = x16(start);
+        for (lane, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+            assert!(
+                (expected - actual).abs() < tolerance,
+                "frame {}: {} != {}", start + lane, expected, actual,
+            );
+        }
+    }
+}
+
+/// A period around `period` that changes every frame, so that
+/// a lane played at another lane's period shows up.
+pub fn varying_period(period: f32, frame: usize) -> SampleOffset {
+    SampleOffset(period * (1.0 + 0.25 * (frame as f32 * 0.05).sin()))
+}
+
+/// A level that changes every frame, between 0.2 and 1.
+pub fn varying_level(frame: usize) -> Unipolar<1> {
+    Unipolar(0.6 + 0.4 * (frame as f32 * 0.07).cos())
+}
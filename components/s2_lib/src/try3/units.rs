@@ -1,13 +1,34 @@
+use serde::{Serialize, Deserialize};
+
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
 pub struct Hz(pub f32);
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
 pub struct Ms(pub f32);
 
+/// Hundredths of a semitone.
+#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
+pub struct Cents(pub f32);
+
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
 pub struct Bipolar<const N: u16>(pub f32);
 
 #[derive(Copy, Clone)]
+#[derive(Debug, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "f32", into = "f32")]
 pub struct Unipolar<const N: u16>(pub f32);
 
 #[derive(Copy, Clone)]
@@ -37,7 +58,7 @@ impl HzX16 for [Hz; 16] {
         let hz = f32x16::from_array(self.map(|hz| hz.0));
         let samples = sample_rate / hz;
         let samples = samples.to_array();
-        samples.map(|s| SampleOffset(s))
+        samples.map(SampleOffset)
     }
 }
 
@@ -52,6 +73,13 @@ impl Ms {
     }
 }
 
+impl Cents {
+    /// The frequency ratio of the interval.
+    pub fn as_ratio(&self) -> f32 {
+        2_f32.powf(self.0 / 1200.0)
+    }
+}
+
 impl<const N: u16> TryFrom<f32> for Unipolar<N> {
     type Error = anyhow::Error;
 
@@ -63,3 +91,81 @@ impl<const N: u16> TryFrom<f32> for Unipolar<N> {
         }
     }
 }
+
+impl<const N: u16> TryFrom<f32> for Bipolar<N> {
+    type Error = anyhow::Error;
+
+    fn try_from(other: f32) -> anyhow::Result<Bipolar<N>> {
+        if other >= -(N as f32) && other <= N as f32 {
+            Ok(Bipolar(other))
+        } else {
+            Err(anyhow::anyhow!("float out of [-{}, {}] range", N, N))
+        }
+    }
+}
+
+impl TryFrom<f32> for Hz {
+    type Error = anyhow::Error;
+
+    fn try_from(other: f32) -> anyhow::Result<Hz> {
+        if other > 0.0 && other.is_finite() {
+            Ok(Hz(other))
+        } else {
+            Err(anyhow::anyhow!("frequency must be positive"))
+        }
+    }
+}
+
+impl TryFrom<f32> for Ms {
+    type Error = anyhow::Error;
+
+    fn try_from(other: f32) -> anyhow::Result<Ms> {
+        if other >= 0.0 && other.is_finite() {
+            Ok(Ms(other))
+        } else {
+            Err(anyhow::anyhow!("time must not be negative"))
+        }
+    }
+}
+
+impl TryFrom<f32> for Cents {
+    type Error = anyhow::Error;
+
+    fn try_from(other: f32) -> anyhow::Result<Cents> {
+        if other.is_finite() {
+            Ok(Cents(other))
+        } else {
+            Err(anyhow::anyhow!("cents must be finite"))
+        }
+    }
+}
+
+impl From<Cents> for f32 {
+    fn from(other: Cents) -> f32 {
+        other.0
+    }
+}
+
+impl From<Hz> for f32 {
+    fn from(other: Hz) -> f32 {
+        other.0
+    }
+}
+
+impl From<Ms> for f32 {
+    fn from(other: Ms) -> f32 {
+        other.0
+    }
+}
+
+impl<const N: u16> From<Unipolar<N>> for f32 {
+    fn from(other: Unipolar<N>) -> f32 {
+        other.0
+    }
+}
+
+impl<const N: u16> From<Bipolar<N>> for f32 {
+    fn from(other: Bipolar<N>) -> f32 {
+        other.0
+    }
+}
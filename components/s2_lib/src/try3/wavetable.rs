@@ -0,0 +1,388 @@
+//! Multi-frame wavetables for the wavetable oscillator.
+//!
+//! A wavetable is a sequence of single-cycle frames. The oscillator plays
+//! a position between the first and last frame, crossfading the two
+//! nearest. Each frame is stored at several mipmap levels, each with half
+//! the harmonics of the one before, and playback uses the richest level
+//! whose harmonics are all below nyquist at the played period.
+//!
+//! Tables load from WAV files. A file with a Serum-style `clm ` chunk,
+//! whose text starts with `<!>` and the frame length, is split into frames
+//! of that length. Any other file is a single cycle.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::io::Cursor;
+use std::path::Path;
+use std::simd::prelude::*;
+use std::sync::{Arc, OnceLock};
+use super::lookup;
+use super::math;
+use super::tables;
+use super::units::*;
+
+/// The length of a frame at the richest level, as in Serum's tables.
+///
+/// It holds every harmonic a frame can have. Poorer levels are shorter,
+/// down to `MIN_LEVEL_LEN`.
+pub const FRAME_LEN: usize = 2048;
+
+/// From every harmonic a frame holds, halving down to the fundamental alone.
+const LEVELS: usize = 11;
+
+/// Samples per cycle of a level's highest harmonic, for smooth interpolation.
+const LEVEL_OVERSAMPLING: usize = 8;
+
+const MIN_LEVEL_LEN: usize = 64;
+
+pub struct Wavetable {
+    frame_count: usize,
+    levels: [Level; LEVELS],
+    /// Every frame of every level, with each level's frames consecutive.
+    samples: Vec<f32>,
+}
+
+#[derive(Default)]
+#[derive(Copy, Clone)]
+struct Level {
+    /// The index of the first sample of the level's first frame.
+    start: usize,
+    /// Samples per frame.
+    len: usize,
+}
+
+impl Wavetable {
+    /// A table of single-cycle frames, each of any length.
+    ///
+    /// Frames are band-limited to the harmonics their length can hold,
+    /// and DC is removed.
+    pub fn from_frames(frames: &[&[f32]]) -> Result<Wavetable> {
+        if frames.is_empty() {
+            bail!("wavetable has no frames");
+        }
+        if frames.iter().any(|frame| frame.len() < 2) {
+            bail!("wavetable frames need at least 2 samples");
+        }
+
+        let spectra: Vec<_> = frames.iter().map(|frame| spectrum(frame)).collect();
+
+        let mut levels = [Level::default(); LEVELS];
+        let mut samples = vec![];
+        for (index, level) in levels.iter_mut().enumerate() {
+            let harmonics = (FRAME_LEN / 2) >> index;
+            let len = (harmonics * LEVEL_OVERSAMPLING).clamp(MIN_LEVEL_LEN, FRAME_LEN);
+            *level = Level {
+                start: samples.len(),
+                len,
+            };
+            // Nyquist itself would be ambiguous.
+            let harmonics = harmonics.min(len / 2 - 1);
+            for spectrum in &spectra {
+                samples.extend(synthesize(spectrum, harmonics, len));
+            }
+        }
+
+        Ok(Wavetable {
+            frame_count: frames.len(),
+            levels,
+            samples,
+        })
+    }
+
+    /// A single sine frame, for patches without a wavetable.
+    pub fn sine() -> Arc<Wavetable> {
+        static SINE: OnceLock<Arc<Wavetable>> = OnceLock::new();
+        SINE.get_or_init(|| {
+            Arc::new(Wavetable::from_frames(&[&tables::SIN_TABLE[..]]).expect("sine wavetable"))
+        }).clone()
+    }
+
+    /// Read a table from a WAV file, mixing its channels.
+    pub fn load(path: &Path) -> Result<Wavetable> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("reading wavetable {}", path.display()))?;
+        Wavetable::from_wav(&bytes)
+            .with_context(|| format!("loading wavetable {}", path.display()))
+    }
+
+    pub fn from_wav(bytes: &[u8]) -> Result<Wavetable> {
+        let mut reader = hound::WavReader::new(Cursor::new(bytes))?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let samples: Vec<f32> = samples.chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+
+        let frame_len = match clm_frame_len(bytes)? {
+            Some(frame_len) => frame_len,
+            None => samples.len(),
+        };
+        if samples.is_empty() || samples.len() % frame_len != 0 {
+            bail!("{} samples is not a whole number of {} sample frames", samples.len(), frame_len);
+        }
+
+        let frames: Vec<&[f32]> = samples.chunks(frame_len).collect();
+        Wavetable::from_frames(&frames)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// The level whose highest harmonic is below nyquist at `period`.
+    fn level(&self, period: SampleOffset) -> Level {
+        // Each level holds harmonics up to half its frame length in the
+        // full table, so the full table's length needs to fit in a period.
+        let octaves_up = (FRAME_LEN as f32 / period.0).log2().ceil().max(0.0);
+        self.levels[(octaves_up as usize).min(LEVELS - 1)]
+    }
+
+    /// The sample `phase` through the cycle, crossfading the frames around `position`.
+    pub fn sample(&self, phase: Unipolar<1>, period: SampleOffset, position: Unipolar<1>) -> f32 {
+        let level = self.level(period);
+        let frame = position.0 * (self.frame_count - 1) as f32;
+        let frame_low = frame as usize;
+        let frame_high = (frame_low + 1).min(self.frame_count - 1);
+        let frame_fraction = frame - frame_low as f32;
+
+        let sample_frame = |frame: usize| {
+            let start = level.start + frame * level.len;
+            let table = &self.samples[start..start + level.len];
+            lookup::table_lookup_periodic(table, phase.0, 1.0)
+        };
+        let low = sample_frame(frame_low);
+        let high = sample_frame(frame_high);
+
+        low + (high - low) * frame_fraction
+    }
+
+    pub fn sample_x16(
+        &self,
+        phase: [Unipolar<1>; 16],
+        period: [SampleOffset; 16],
+        position: [Unipolar<1>; 16],
+    ) -> [f32; 16] {
+        // Lanes can be at different levels, so the table is gathered
+        // from directly rather than through a slice per level.
+        let levels = period.map(|period| self.level(period));
+        let start = usizex16::from_array(levels.map(|level| level.start));
+        let len = usizex16::from_array(levels.map(|level| level.len));
+
+        let last_frame = self.frame_count - 1;
+        let position = f32x16::from_array(position.map(|p| p.0));
+        let frame = position * f32x16::splat(last_frame as f32);
+        let frame_low = frame.cast::<usize>().simd_min(usizex16::splat(last_frame));
+        let frame_high = (frame_low + usizex16::splat(1)).simd_min(usizex16::splat(last_frame));
+        let frame_fraction = frame - frame_low.cast::<f32>();
+
+        let phase = f32x16::from_array(phase.map(|p| p.0)) % f32x16::splat(1.0);
+        let index = phase * len.cast::<f32>();
+        let index_low = index.cast::<usize>().simd_min(len - usizex16::splat(1));
+        let index_high = (index_low + usizex16::splat(1)) % len;
+        let index_fraction = index - index_low.cast::<f32>();
+
+        let sample_frame = |frame: usizex16| {
+            let frame_start = start + frame * len;
+            let low = f32x16::gather_or_default(&self.samples, frame_start + index_low);
+            let high = f32x16::gather_or_default(&self.samples, frame_start + index_high);
+            low + (high - low) * index_fraction
+        };
+        let low = sample_frame(frame_low);
+        let high = sample_frame(frame_high);
+
+        (low + (high - low) * frame_fraction).to_array()
+    }
+}
+
+/// The frame length from a `clm ` chunk, if the file has one.
+fn clm_frame_len(bytes: &[u8]) -> Result<Option<usize>> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        bail!("not a WAV file");
+    }
+
+    let mut chunks = &bytes[12..];
+    while chunks.len() >= 8 {
+        let id = &chunks[0..4];
+        let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        let data = &chunks[8..(8 + len).min(chunks.len())];
+
+        if id == b"clm " {
+            let text = String::from_utf8_lossy(data);
+            let digits: String = text.strip_prefix("<!>")
+                .unwrap_or("")
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            let frame_len = digits.parse::<usize>()
+                .map_err(|_| anyhow!("invalid clm chunk `{}`", text.trim_end_matches('\0')))?;
+            if frame_len == 0 {
+                bail!("clm chunk has a frame length of 0");
+            }
+            return Ok(Some(frame_len));
+        }
+
+        // Chunks are padded to an even length.
+        let next = 8 + len + len % 2;
+        chunks = chunks.get(next..).unwrap_or(&[]);
+    }
+
+    Ok(None)
+}
+
+/// The harmonics of a single cycle, scaled to their amplitude in the cycle,
+/// without DC and up to the most that a full table's frame can hold.
+fn spectrum(frame: &[f32]) -> Vec<(f32, f32)> {
+    let len = frame.len();
+    let harmonics = (len.div_ceil(2) - 1).min(FRAME_LEN / 2 - 1);
+    let scale = 1.0 / len as f32;
+
+    if len.is_power_of_two() {
+        let mut re = frame.to_vec();
+        let mut im = vec![0.0; len];
+        math::fft(&mut re, &mut im);
+        (1..=harmonics).map(|k| (re[k] * scale, im[k] * scale)).collect()
+    } else {
+        // Odd lengths are rare enough, and only loaded once, to not need
+        // anything faster than a direct DFT.
+        (1..=harmonics).map(|k| {
+            let (mut re, mut im) = (0.0_f64, 0.0_f64);
+            for (n, sample) in frame.iter().enumerate() {
+                let angle = -std::f64::consts::TAU * ((n * k) % len) as f64 / len as f64;
+                let (sin, cos) = angle.sin_cos();
+                re += *sample as f64 * cos;
+                im += *sample as f64 * sin;
+            }
+            (re as f32 * scale, im as f32 * scale)
+        }).collect()
+    }
+}
+
+/// `len` samples of one cycle with the first `harmonics` of `spectrum`.
+fn synthesize(spectrum: &[(f32, f32)], harmonics: usize, len: usize) -> Vec<f32> {
+    // An inverse FFT, as a forward FFT of the conjugate.
+    let mut re = vec![0.0; len];
+    let mut im = vec![0.0; len];
+    for (k, (harmonic_re, harmonic_im)) in spectrum.iter().enumerate().take(harmonics) {
+        let k = k + 1;
+        re[k] = *harmonic_re;
+        im[k] = -*harmonic_im;
+        re[len - k] = *harmonic_re;
+        im[len - k] = *harmonic_im;
+    }
+    math::fft(&mut re, &mut im);
+    re
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saw_frame(len: usize) -> Vec<f32> {
+        (0..len).map(|i| 1.0 - 2.0 * i as f32 / len as f32).collect()
+    }
+
+    #[test]
+    fn test_levels_band_limited() {
+        let saw = saw_frame(FRAME_LEN);
+        let wavetable = Wavetable::from_frames(&[&saw[..]]).expect("wavetable");
+
+        for period in [4000.0, 500.0, 37.0, 3.0] {
+            let level = wavetable.level(SampleOffset(period));
+            let mut re = wavetable.samples[level.start..level.start + level.len].to_vec();
+            let mut im = vec![0.0; level.len];
+            math::fft(&mut re, &mut im);
+
+            // Nothing at or above nyquist for this period.
+            let nyquist_harmonic = (period / 2.0).ceil() as usize;
+            for k in nyquist_harmonic.max(1)..level.len / 2 {
+                let amplitude = (re[k] * re[k] + im[k] * im[k]).sqrt() / level.len as f32;
+                assert!(amplitude < 1e-4, "period {}, harmonic {}: {}", period, k, amplitude);
+            }
+        }
+
+        // Low notes get the full table, which is close to the naive saw.
+        let sample = wavetable.sample(Unipolar(0.25), SampleOffset(4000.0), Unipolar(0.0));
+        assert!((sample - 0.5).abs() < 0.01, "{}", sample);
+    }
+
+    #[test]
+    fn test_position_crossfades_frames() {
+        let up = [1.0, 1.0, -1.0, -1.0];
+        let down = [-1.0, -1.0, 1.0, 1.0];
+        let wavetable = Wavetable::from_frames(&[&up[..], &down[..]]).expect("wavetable");
+        assert_eq!(wavetable.frame_count(), 2);
+
+        let phase = Unipolar(0.25);
+        let period = SampleOffset(1000.0);
+        let first = wavetable.sample(phase, period, Unipolar(0.0));
+        let last = wavetable.sample(phase, period, Unipolar(1.0));
+        let middle = wavetable.sample(phase, period, Unipolar(0.5));
+        assert!(first > 0.5, "{}", first);
+        assert!((last + first).abs() < 1e-4, "{} {}", first, last);
+        assert!(middle.abs() < 1e-4, "{}", middle);
+
+        let phases: [_; 16] = std::array::from_fn(|i| Unipolar(i as f32 / 16.0));
+        let positions: [_; 16] = std::array::from_fn(|i| Unipolar(i as f32 / 15.0));
+        let periods: [_; 16] = std::array::from_fn(|i| SampleOffset(3.0 + i as f32 * 300.0));
+        let x16 = wavetable.sample_x16(phases, periods, positions);
+        for i in 0..16 {
+            let scalar = wavetable.sample(phases[i], periods[i], positions[i]);
+            assert!((scalar - x16[i]).abs() < 1e-5, "lane {}: {} != {}", i, scalar, x16[i]);
+        }
+    }
+
+    #[test]
+    fn test_load_wav() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let write_wav = |frames: usize, frame_len: usize| {
+            let mut cursor = Cursor::new(vec![]);
+            let mut writer = hound::WavWriter::new(&mut cursor, spec).expect("writer");
+            for _ in 0..frames {
+                for sample in saw_frame(frame_len) {
+                    let sample = (sample * i16::MAX as f32) as i16;
+                    writer.write_sample(sample).expect("write");
+                    writer.write_sample(sample).expect("write");
+                }
+            }
+            writer.finalize().expect("finalize");
+            cursor.into_inner()
+        };
+
+        // A single cycle of an odd length.
+        let wav = write_wav(1, 600);
+        let wavetable = Wavetable::from_wav(&wav).expect("single cycle");
+        assert_eq!(wavetable.frame_count(), 1);
+        let sample = wavetable.sample(Unipolar(0.25), SampleOffset(4000.0), Unipolar(0.0));
+        assert!((sample - 0.5).abs() < 0.01, "{}", sample);
+
+        // Three frames, split by a clm chunk after the data.
+        let mut wav = write_wav(3, 256);
+        let clm = b"<!>256 10000000 wavetable (www.xferrecords.com)\0";
+        wav.extend(b"clm ");
+        wav.extend((clm.len() as u32).to_le_bytes());
+        wav.extend(clm);
+        let riff_len = (wav.len() - 8) as u32;
+        wav[4..8].copy_from_slice(&riff_len.to_le_bytes());
+        let wavetable = Wavetable::from_wav(&wav).expect("clm");
+        assert_eq!(wavetable.frame_count(), 3);
+
+        let mut wav = write_wav(1, 100);
+        wav.extend(b"clm ");
+        wav.extend(8_u32.to_le_bytes());
+        wav.extend(b"<!>64   ");
+        assert!(Wavetable::from_wav(&wav).is_err());
+    }
+}